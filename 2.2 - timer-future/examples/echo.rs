@@ -0,0 +1,44 @@
+// An echo server running on this crate's own pieces: sockets waited on by the reactor, and
+// connections served by tasks on a `Runtime`, with no async-std or tokio underneath.
+//
+//     cargo run --features polling --example echo
+//     nc localhost 7879
+//
+// The runtime's workers serve the connections; the reactor thread only wakes them.
+
+use std::net::{TcpListener, TcpStream};
+
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use timer_future::{executor::Handle, reactor::Async, runtime::Runtime};
+
+/// Writes back whatever the client sends, until it hangs up.
+async fn echo(mut stream: Async<TcpStream>) {
+    let mut buffer = [0; 1024];
+    loop {
+        let len = match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(len) => len,
+        };
+        if stream.write_all(&buffer[..len]).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn main() {
+    let listener = Async::<TcpListener>::bind("127.0.0.1:7879").expect("failed to bind");
+    println!("echoing on {}", listener.get_ref().local_addr().unwrap());
+
+    let runtime = Runtime::builder().thread_name("echo").build();
+    runtime.block_on(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    println!("{} connected", peer);
+                    Handle::current().spawn(echo(stream));
+                }
+                Err(e) => eprintln!("accept failed: {}", e),
+            }
+        }
+    });
+}
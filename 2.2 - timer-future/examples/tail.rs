@@ -0,0 +1,75 @@
+// A `tail -f` lookalike: print the lines of a file as they are appended to it.
+//
+//     cargo run --example tail -- some.log
+//
+// Reading a file is blocking, and a file which is still being written just returns EOF until
+// more data arrives. So the actual following happens in a plain iterator which, at EOF, waits
+// for `fs::watch` to see the file change before reading again, and `from_blocking_iter` moves
+// it off the executor and turns it into a stream of lines.
+
+use std::{
+    env,
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use futures::{executor::block_on, stream::StreamExt};
+use timer_future::{
+    fs::{self, Watch},
+    runtime::Runtime,
+    stream::from_blocking_iter,
+    timer::ThreadTimer,
+};
+
+/// Yields the lines of a file forever, waiting for more to be written at EOF.
+struct FollowLines {
+    reader: BufReader<File>,
+    partial: String,
+    changes: Watch<ThreadTimer>,
+}
+
+impl Iterator for FollowLines {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.reader.read_line(&mut self.partial) {
+                // A line is only complete once its newline has been written.
+                Ok(_) if self.partial.ends_with('\n') => {
+                    let line = self.partial.trim_end_matches(['\r', '\n']).to_string();
+                    self.partial.clear();
+                    return Some(Ok(line));
+                }
+                // Only ever on the iterator's own thread, so blocking is fine.
+                Ok(_) => {
+                    block_on(self.changes.next());
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn main() {
+    let path = env::args().nth(1).expect("usage: tail <file>");
+    let file = File::open(&path).expect("failed to open file");
+    let follow = FollowLines {
+        reader: BufReader::new(file),
+        partial: String::new(),
+        changes: fs::watch(&path),
+    };
+
+    let runtime = Runtime::builder().worker_threads(1).build();
+    runtime.block_on(async move {
+        let mut lines = from_blocking_iter(follow, 64);
+        while let Some(line) = lines.next().await {
+            match line {
+                Ok(line) => println!("{}", line),
+                Err(e) => {
+                    eprintln!("error reading {}: {}", path, e);
+                    break;
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,86 @@
+// How much does checking `will_wake` before storing a waker save? An executor hands a task an
+// equivalent waker on every poll, so a future which stores its waker, as most leaf futures
+// do, only needs to clone it the first time.
+//
+//     cargo run --release --example waker_reuse
+//
+// Both stores below are polled the way `executor::Executor` polls a task: with a fresh
+// `waker_ref` to the same task each time. Cloning an `Arc`-backed waker doesn't allocate, it
+// only bumps the reference count, so the saving is in atomic operations rather than memory;
+// the allocation counts are printed to show that.
+
+use std::{
+    hint::black_box,
+    sync::Arc,
+    task::Waker,
+    time::{Duration, Instant},
+};
+
+use futures::task::{waker_ref, ArcWake};
+use timer_future::memory::{self, Subsystem, TrackingAllocator};
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+const POLLS: usize = 10_000_000;
+
+struct Task;
+
+impl ArcWake for Task {
+    fn wake_by_ref(_: &Arc<Self>) {}
+}
+
+/// Stores the latest waker, counting how many times it had to clone one.
+#[derive(Default)]
+struct WakerSlot {
+    waker: Option<Waker>,
+    clones: usize,
+}
+
+impl WakerSlot {
+    fn store_always(&mut self, waker: &Waker) {
+        self.waker = Some(waker.clone());
+        self.clones += 1;
+    }
+
+    fn store_if_changed(&mut self, waker: &Waker) {
+        match &self.waker {
+            Some(stored) if stored.will_wake(waker) => {}
+            _ => self.store_always(waker),
+        }
+    }
+}
+
+/// Polls `store` `POLLS` times, returning the clones, allocations and time taken.
+fn measure(store: fn(&mut WakerSlot, &Waker)) -> (usize, usize, Duration) {
+    let task = Arc::new(Task);
+    let mut slot = WakerSlot::default();
+    let allocations = || memory::report().get(Subsystem::Other).allocations;
+
+    let allocations_before = allocations();
+    let started = Instant::now();
+    for _ in 0..POLLS {
+        // `black_box` stops the compiler noticing that every iteration is the same.
+        let waker = waker_ref(black_box(&task));
+        store(black_box(&mut slot), &waker);
+    }
+    let elapsed = started.elapsed();
+    (slot.clones, allocations() - allocations_before, elapsed)
+}
+
+fn main() {
+    for (name, store) in [
+        ("clone every poll", WakerSlot::store_always as fn(&mut WakerSlot, &Waker)),
+        ("clone if !will_wake", WakerSlot::store_if_changed),
+    ] {
+        let (clones, allocations, elapsed) = measure(store);
+        println!(
+            "{:<20} {:>9} clones {:>3} allocations {:>8.2?} ({:.1} ns/poll)",
+            name,
+            clones,
+            allocations,
+            elapsed,
+            elapsed.as_nanos() as f64 / POLLS as f64
+        );
+    }
+}
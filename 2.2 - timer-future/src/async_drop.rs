@@ -0,0 +1,105 @@
+// Rust has no async `Drop`: `drop` is a plain function, so it can't `.await` a flush or a
+// close handshake. A common workaround is to move the cleanup into a new task when the value
+// goes out of scope. The cleanup then runs "soon" rather than before `drop` returns, which is
+// usually good enough for things like flushing a buffer or saying goodbye to a peer.
+
+use std::{
+    future::Future,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+use futures::task::{Spawn, SpawnExt};
+
+/// Owns a value and, when dropped, spawns `cleanup(value)` onto `spawner`.
+///
+/// The value is reachable through `Deref`/`DerefMut` while the guard is alive.
+pub struct AsyncDropGuard<T, S, C, Fut>
+where
+    S: Spawn,
+    C: FnOnce(T) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    // `None` only after `into_inner` or `drop` has taken it.
+    inner: Option<(T, C)>,
+    spawner: S,
+    _cleanup: PhantomData<fn() -> Fut>,
+}
+
+impl<T, S, C, Fut> AsyncDropGuard<T, S, C, Fut>
+where
+    S: Spawn,
+    C: FnOnce(T) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    pub fn new(value: T, spawner: S, cleanup: C) -> Self {
+        AsyncDropGuard {
+            inner: Some((value, cleanup)),
+            spawner,
+            _cleanup: PhantomData,
+        }
+    }
+
+    /// Take the value back out without running the cleanup.
+    pub fn into_inner(mut self) -> T {
+        let (value, _) = self.inner.take().expect("value already taken");
+        value
+    }
+}
+
+impl<T, S, C, Fut> Deref for AsyncDropGuard<T, S, C, Fut>
+where
+    S: Spawn,
+    C: FnOnce(T) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner.as_ref().expect("value already taken").0
+    }
+}
+
+impl<T, S, C, Fut> DerefMut for AsyncDropGuard<T, S, C, Fut>
+where
+    S: Spawn,
+    C: FnOnce(T) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner.as_mut().expect("value already taken").0
+    }
+}
+
+impl<T, S, C, Fut> Drop for AsyncDropGuard<T, S, C, Fut>
+where
+    S: Spawn,
+    C: FnOnce(T) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn drop(&mut self) {
+        if let Some((value, cleanup)) = self.inner.take() {
+            // If the executor has shut down there is nowhere left to run the cleanup,
+            // and `drop` has no way to report that, so the future is simply dropped.
+            let _ = self.spawner.spawn(cleanup(value));
+        }
+    }
+}
+
+/// Spawn `cleanup` onto `spawner` when the enclosing scope ends.
+///
+/// `cleanup` must be a `'static` future (usually an `async move` block), since it
+/// outlives the scope that created it.
+///
+/// ```ignore
+/// defer_async!(spawner.clone(), async move {
+///     connection.close().await;
+/// });
+/// ```
+#[macro_export]
+macro_rules! defer_async {
+    ($spawner:expr, $cleanup:expr) => {
+        let _async_drop_guard =
+            $crate::async_drop::AsyncDropGuard::new((), $spawner, move |()| $cleanup);
+    };
+}
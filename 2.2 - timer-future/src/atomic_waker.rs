@@ -0,0 +1,178 @@
+// A slot for one waker that a future fills in from `poll` and another thread empties to wake
+// it, without a `Mutex`. It's the same protocol as `futures::task::AtomicWaker`.
+//
+// A small state word guards the slot. `register` takes it with REGISTERING while it swaps the
+// waker in, and `take` (or `wake`) sets WAKING. If a wake-up arrives while a register is in
+// progress, the waker can't be taken yet, so the waking side only leaves the WAKING bit behind
+// and `register` notices it on the way out and wakes the new waker itself. Either way the
+// wake-up is never lost, as long as the future checks its condition again after registering.
+
+use std::{
+    cell::UnsafeCell,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::Waker,
+};
+
+/// Nobody is using the slot.
+const WAITING: usize = 0;
+/// `register` is replacing the waker.
+const REGISTERING: usize = 0b01;
+/// `take` is removing the waker, or wants to once `register` is done.
+const WAKING: usize = 0b10;
+
+/// A waker slot shared between one registering future and any number of wakers.
+#[derive(Default)]
+pub struct AtomicWaker {
+    state: AtomicUsize,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// Safety: the slot is only touched by whoever moved `state` out of WAITING, so at most one
+// thread accesses it at a time, and `Waker` itself is `Send + Sync`.
+unsafe impl Send for AtomicWaker {}
+unsafe impl Sync for AtomicWaker {}
+
+impl AtomicWaker {
+    pub fn new() -> Self {
+        AtomicWaker::default()
+    }
+
+    /// Store `waker` to be woken by the next `wake`. Call this before checking whether the
+    /// event has already happened, so a wake-up in between isn't missed.
+    ///
+    /// Only one thread should register at a time (the one polling the future); concurrent
+    /// calls don't cause unsafety, but all but one are ignored.
+    pub fn register(&self, waker: &Waker) {
+        match self
+            .state
+            .compare_exchange(WAITING, REGISTERING, Ordering::Acquire, Ordering::Acquire)
+            .unwrap_or_else(|state| state)
+        {
+            WAITING => {
+                // Safety: we moved the state out of WAITING, so the slot is ours.
+                let slot = unsafe { &mut *self.waker.get() };
+                match slot {
+                    Some(old) if old.will_wake(waker) => {}
+                    _ => *slot = Some(waker.clone()),
+                }
+
+                if self
+                    .state
+                    .compare_exchange(REGISTERING, WAITING, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // Someone tried to wake us while we held the slot and left WAKING set,
+                    // so do their wake-up for them.
+                    let waker = slot.take();
+                    self.state.swap(WAITING, Ordering::AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            WAKING => {
+                // A wake-up is in progress and may have taken the old waker already. Wake the
+                // new one directly so the task polls again.
+                waker.wake_by_ref();
+            }
+            _ => {
+                // Another register is in progress.
+            }
+        }
+    }
+
+    /// Remove the registered waker, if there is one and no one else is using the slot.
+    pub fn take(&self) -> Option<Waker> {
+        match self.state.fetch_or(WAKING, Ordering::AcqRel) {
+            WAITING => {
+                // Safety: we moved the state out of WAITING, so the slot is ours.
+                let waker = unsafe { (*self.waker.get()).take() };
+                self.state.fetch_and(!WAKING, Ordering::Release);
+                waker
+            }
+            // Either `register` holds the slot and will see our WAKING bit, or another
+            // `take` is already handling it.
+            _ => None,
+        }
+    }
+
+    /// Wake the registered waker, if any.
+    pub fn wake(&self) {
+        if let Some(waker) = self.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl fmt::Debug for AtomicWaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AtomicWaker")
+            .field("state", &self.state.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::waker::CountingWaker;
+    use std::{
+        sync::{atomic::AtomicBool, Arc},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn wakes_the_last_registered_waker_once() {
+        let atomic_waker = AtomicWaker::new();
+        let first = CountingWaker::new();
+        let second = CountingWaker::new();
+
+        // Nothing registered yet, so this wake-up goes nowhere.
+        atomic_waker.wake();
+        atomic_waker.register(&first.waker());
+        atomic_waker.register(&second.waker());
+        atomic_waker.wake();
+        atomic_waker.wake();
+
+        assert_eq!(first.wake_count(), 0);
+        assert_eq!(second.wake_count(), 1);
+        assert!(atomic_waker.take().is_none());
+    }
+
+    // The race the state word exists for: the other thread sets the flag and wakes while
+    // we are registering. Whichever way it interleaves, after registering we must either see
+    // the flag or get woken.
+    #[test]
+    fn register_and_wake_race_never_loses_a_wake_up() {
+        for _ in 0..1000 {
+            let atomic_waker = Arc::new(AtomicWaker::new());
+            let flag = Arc::new(AtomicBool::new(false));
+            let counter = CountingWaker::new();
+
+            let waking = {
+                let atomic_waker = atomic_waker.clone();
+                let flag = flag.clone();
+                thread::spawn(move || {
+                    flag.store(true, Ordering::SeqCst);
+                    atomic_waker.wake();
+                })
+            };
+
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let seen = counter.wake_count();
+                atomic_waker.register(&counter.waker());
+                if flag.load(Ordering::SeqCst) {
+                    break;
+                }
+                while counter.wake_count() == seen {
+                    assert!(Instant::now() < deadline, "lost a wake-up");
+                    thread::yield_now();
+                }
+            }
+            waking.join().unwrap();
+        }
+    }
+}
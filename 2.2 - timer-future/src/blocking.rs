@@ -0,0 +1,60 @@
+// Calling `std::thread::sleep` inside a task doesn't just pause that task: it blocks the thread
+// the executor polls every task on, so nothing else makes progress. That's why the http-server
+// example's `/sleep` route uses `task::sleep(..).await` rather than `thread::sleep`.
+//
+// The compiler can't catch this mistake, so these wrappers catch it at runtime instead: in debug
+// builds they panic if they're called while the current thread is polling tasks. Release builds
+// skip the check.
+
+use std::{
+    io,
+    net::{TcpStream, ToSocketAddrs},
+    thread,
+    time::Duration,
+};
+
+use crate::context;
+
+/// Panic (in debug builds) if the current thread is polling tasks, naming the blocking
+/// `operation` that was attempted.
+#[track_caller]
+pub fn assert_can_block(operation: &str) {
+    debug_assert!(
+        !context::in_executor(),
+        "blocking call `{}` on an executor thread stalls every other task; \
+         use an async alternative such as `TimerFuture` instead",
+        operation
+    );
+}
+
+/// `std::thread::sleep`, but refuses to run on an executor thread in debug builds.
+#[track_caller]
+pub fn sleep(duration: Duration) {
+    assert_can_block("thread::sleep");
+    thread::sleep(duration);
+}
+
+/// `std::net::TcpStream::connect`, but refuses to run on an executor thread in debug builds.
+#[track_caller]
+pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+    assert_can_block("TcpStream::connect");
+    TcpStream::connect(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sleeping_outside_an_executor_is_allowed() {
+        sleep(Duration::from_millis(1));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "blocking call `thread::sleep` on an executor thread")]
+    fn sleeping_on_an_executor_thread_panics() {
+        let _enter = context::enter();
+        sleep(Duration::from_millis(1));
+    }
+}
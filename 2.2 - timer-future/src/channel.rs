@@ -0,0 +1,518 @@
+// A bounded multi-producer, multi-consumer channel. Unlike `std::sync::mpsc`, the receiving
+// half can be cloned too, which turns the channel into a shared job queue: several worker
+// tasks call `recv` on their own clone, and each message goes to exactly one of them.
+//
+// Senders can also `reserve` a slot before building a message, so an expensive message is never
+// constructed only to find the channel full.
+//
+// Everything lives behind one `Mutex`. Tasks which can't make progress (a receiver finding
+// the queue empty, a sender finding it full) leave their waker in a `WakerSet` in the shared
+// state, and whoever changes the state in a way they care about wakes them. Each waiting future
+// keeps its key into the set and gives it back once it is done waiting or dropped.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::stream::{FusedStream, Stream};
+
+use crate::{coop, memory, waker_set::WakerSet};
+
+/// Create a channel which holds at most `capacity` messages at once.
+///
+/// # Panics
+///
+/// Panics if `capacity` is 0.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be greater than 0");
+    let _memory_scope = memory::scope(memory::Subsystem::Channels);
+    let shared = Arc::new(Mutex::new(State {
+        queue: VecDeque::with_capacity(capacity),
+        capacity,
+        reserved: 0,
+        senders: 1,
+        receivers: 1,
+        recv_wakers: WakerSet::new(),
+        send_wakers: WakerSet::new(),
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver {
+            shared,
+            waker_key: None,
+        },
+    )
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    /// Slots promised to outstanding `Permit`s.
+    reserved: usize,
+    senders: usize,
+    receivers: usize,
+    /// Receivers waiting for a message.
+    recv_wakers: WakerSet,
+    /// Senders waiting for free capacity.
+    send_wakers: WakerSet,
+}
+
+impl<T> State<T> {
+    fn has_capacity(&self) -> bool {
+        self.queue.len() + self.reserved < self.capacity
+    }
+}
+
+// Wake every waiting task. Waking only one would be cheaper, but if that task's future is
+// dropped before it runs, the wake-up would be lost and the others would wait forever.
+fn wake_all(wakers: &mut WakerSet) {
+    wakers.notify_all();
+}
+
+// Give back the key of a future which is no longer waiting.
+fn stop_waiting(wakers: &mut WakerSet, key: &mut Option<usize>) {
+    if let Some(key) = key.take() {
+        wakers.remove(key);
+    }
+}
+
+/// Returned by `send` when every receiver has been dropped, handing back the message.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sending on a channel with no receivers")
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for SendError<T> {}
+
+/// Returned by `try_send`, handing back the message.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity.
+    Full(T),
+    /// Every receiver has been dropped.
+    Disconnected(T),
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "sending on a full channel"),
+            TrySendError::Disconnected(_) => write!(f, "sending on a channel with no receivers"),
+        }
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for TrySendError<T> {}
+
+/// Returned by `try_recv`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// The channel is empty, but senders remain.
+    Empty,
+    /// The channel is empty and every sender has been dropped.
+    Disconnected,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "receiving on an empty channel"),
+            TryRecvError::Disconnected => write!(f, "receiving on an empty, closed channel"),
+        }
+    }
+}
+
+impl std::error::Error for TryRecvError {}
+
+/// The sending half of a channel. Clone it to get more producers.
+pub struct Sender<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+/// The receiving half of a channel. Clone it to get more consumers; each message is
+/// received by exactly one of them.
+pub struct Receiver<T> {
+    shared: Arc<Mutex<State<T>>>,
+    /// Key into `recv_wakers` while polled as a `Stream`.
+    waker_key: Option<usize>,
+}
+
+impl<T> Sender<T> {
+    /// Send `value`, waiting for capacity if the channel is full.
+    pub fn send(&self, value: T) -> Send<'_, T> {
+        Send {
+            sender: self,
+            value: Some(value),
+            waker_key: None,
+        }
+    }
+
+    /// Send `value` if there is capacity right now, without waiting.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.shared.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(TrySendError::Disconnected(value));
+        }
+        if !state.has_capacity() {
+            return Err(TrySendError::Full(value));
+        }
+        state.queue.push_back(value);
+        wake_all(&mut state.recv_wakers);
+        Ok(())
+    }
+
+    /// Wait for capacity and reserve it, returning a `Permit` which sends without waiting.
+    /// Fails if every receiver has been dropped.
+    pub fn reserve(&self) -> Reserve<'_, T> {
+        Reserve {
+            sender: self,
+            waker_key: None,
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            // Waiting receivers need to find out that nothing more is coming.
+            wake_all(&mut state.recv_wakers);
+        }
+    }
+}
+
+/// Future returned by `Sender::send`.
+pub struct Send<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    waker_key: Option<usize>,
+}
+
+// The message is only moved around, never pinned.
+impl<T> Unpin for Send<'_, T> {}
+
+impl<T> Future for Send<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let value = self.value.take().expect("Send polled after completion");
+        let mut state = self.sender.shared.lock().unwrap();
+
+        if state.receivers == 0 {
+            stop_waiting(&mut state.send_wakers, &mut self.waker_key);
+            return Poll::Ready(Err(SendError(value)));
+        }
+        if state.has_capacity() {
+            state.queue.push_back(value);
+            wake_all(&mut state.recv_wakers);
+            stop_waiting(&mut state.send_wakers, &mut self.waker_key);
+            return Poll::Ready(Ok(()));
+        }
+
+        state.send_wakers.register(&mut self.waker_key, cx.waker());
+        drop(state);
+        self.value = Some(value);
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Send<'_, T> {
+    fn drop(&mut self) {
+        if self.waker_key.is_some() {
+            let mut state = self.sender.shared.lock().unwrap();
+            stop_waiting(&mut state.send_wakers, &mut self.waker_key);
+        }
+    }
+}
+
+/// Future returned by `Sender::reserve`.
+pub struct Reserve<'a, T> {
+    sender: &'a Sender<T>,
+    waker_key: Option<usize>,
+}
+
+impl<'a, T> Future for Reserve<'a, T> {
+    type Output = Result<Permit<'a, T>, SendError<()>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let this = self.get_mut();
+        let mut state = this.sender.shared.lock().unwrap();
+        if state.receivers == 0 {
+            stop_waiting(&mut state.send_wakers, &mut this.waker_key);
+            return Poll::Ready(Err(SendError(())));
+        }
+        if state.has_capacity() {
+            state.reserved += 1;
+            stop_waiting(&mut state.send_wakers, &mut this.waker_key);
+            return Poll::Ready(Ok(Permit {
+                sender: this.sender,
+            }));
+        }
+        state.send_wakers.register(&mut this.waker_key, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Reserve<'_, T> {
+    fn drop(&mut self) {
+        if self.waker_key.is_some() {
+            let mut state = self.sender.shared.lock().unwrap();
+            stop_waiting(&mut state.send_wakers, &mut self.waker_key);
+        }
+    }
+}
+
+/// One slot of channel capacity, reserved by `Sender::reserve`. Dropping the permit
+/// without sending gives the slot back.
+pub struct Permit<'a, T> {
+    sender: &'a Sender<T>,
+}
+
+impl<T> Permit<'_, T> {
+    /// Send `value` using the reserved slot. If every receiver has been dropped in the
+    /// meantime, the value is dropped.
+    pub fn send(self, value: T) {
+        let mut state = self.sender.shared.lock().unwrap();
+        state.reserved -= 1;
+        if state.receivers > 0 {
+            state.queue.push_back(value);
+            wake_all(&mut state.recv_wakers);
+        }
+        drop(state);
+        // The slot has been used, so skip the release in `drop`.
+        std::mem::forget(self);
+    }
+}
+
+impl<T> Drop for Permit<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.sender.shared.lock().unwrap();
+        state.reserved -= 1;
+        wake_all(&mut state.send_wakers);
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receive the next message, waiting for one if the channel is empty. Returns `None`
+    /// once the channel is empty and every sender has been dropped.
+    pub fn recv(&self) -> Recv<'_, T> {
+        Recv {
+            receiver: self,
+            waker_key: None,
+        }
+    }
+
+    /// Receive a message if one is queued right now, without waiting.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.lock().unwrap();
+        if let Some(value) = state.queue.pop_front() {
+            wake_all(&mut state.send_wakers);
+            return Ok(value);
+        }
+        if state.senders == 0 {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+}
+
+// Shared by `Recv` and the `Stream` impl, which each keep their own `waker_key`.
+fn poll_recv<T>(
+    shared: &Mutex<State<T>>,
+    waker_key: &mut Option<usize>,
+    cx: &mut Context<'_>,
+) -> Poll<Option<T>> {
+    if coop::poll_proceed(cx).is_pending() {
+        return Poll::Pending;
+    }
+    let mut state = shared.lock().unwrap();
+    if let Some(value) = state.queue.pop_front() {
+        wake_all(&mut state.send_wakers);
+        stop_waiting(&mut state.recv_wakers, waker_key);
+        return Poll::Ready(Some(value));
+    }
+    if state.senders == 0 {
+        stop_waiting(&mut state.recv_wakers, waker_key);
+        return Poll::Ready(None);
+    }
+    state.recv_wakers.register(waker_key, cx.waker());
+    Poll::Pending
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().receivers += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            waker_key: None,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        stop_waiting(&mut state.recv_wakers, &mut self.waker_key);
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            // Senders waiting for capacity will never get it now.
+            wake_all(&mut state.send_wakers);
+        }
+    }
+}
+
+/// Future returned by `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a Receiver<T>,
+    waker_key: Option<usize>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        poll_recv(&this.receiver.shared, &mut this.waker_key, cx)
+    }
+}
+
+impl<T> Drop for Recv<'_, T> {
+    fn drop(&mut self) {
+        if self.waker_key.is_some() {
+            let mut state = self.receiver.shared.lock().unwrap();
+            stop_waiting(&mut state.recv_wakers, &mut self.waker_key);
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        poll_recv(&this.shared, &mut this.waker_key, cx)
+    }
+}
+
+impl<T> FusedStream for Receiver<T> {
+    fn is_terminated(&self) -> bool {
+        let state = self.shared.lock().unwrap();
+        state.senders == 0 && state.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::waker::noop_waker;
+    use futures::executor::block_on;
+    use std::thread;
+
+    #[test]
+    fn each_message_goes_to_exactly_one_worker() {
+        let (sender, receiver) = channel(4);
+
+        let workers: Vec<_> = (0..3)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || {
+                    let mut received = Vec::new();
+                    while let Some(job) = block_on(receiver.recv()) {
+                        received.push(job);
+                    }
+                    received
+                })
+            })
+            .collect();
+        drop(receiver);
+
+        for job in 0..100 {
+            block_on(sender.send(job)).unwrap();
+        }
+        drop(sender);
+
+        let mut received: Vec<i32> = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect();
+        received.sort();
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn send_waits_for_capacity_and_fails_without_receivers() {
+        let (sender, receiver) = channel(1);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        block_on(sender.send(1)).unwrap();
+        let mut second = sender.send(2);
+        assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+
+        assert_eq!(block_on(receiver.recv()), Some(1));
+        assert_eq!(Pin::new(&mut second).poll(&mut cx), Poll::Ready(Ok(())));
+        drop(receiver);
+
+        assert_eq!(block_on(sender.send(3)), Err(SendError(3)));
+    }
+
+    #[test]
+    fn try_send_and_try_recv_never_wait() {
+        let (sender, receiver) = channel(1);
+
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(sender.try_send(1), Ok(()));
+        assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+        assert_eq!(receiver.try_recv(), Ok(1));
+
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn permits_hold_capacity_until_used_or_dropped() {
+        let (sender, receiver) = channel(1);
+
+        let permit = block_on(sender.reserve()).unwrap();
+        // The only slot is reserved, even though nothing has been sent yet.
+        assert_eq!(sender.try_send(1), Err(TrySendError::Full(1)));
+        drop(permit);
+        assert_eq!(sender.try_send(1), Ok(()));
+        assert_eq!(receiver.try_recv(), Ok(1));
+
+        let permit = block_on(sender.reserve()).unwrap();
+        permit.send(2);
+        assert_eq!(receiver.try_recv(), Ok(2));
+
+        drop(receiver);
+        assert!(block_on(sender.reserve()).is_err());
+    }
+}
@@ -0,0 +1,31 @@
+// Executors mark the threads they poll tasks on, so other code can tell whether it is
+// running inside a task. Blocking the thread from inside a task stalls every other task
+// on that executor, and waiting on the executor from inside it can never finish.
+
+use std::cell::Cell;
+
+thread_local! {
+    static IN_EXECUTOR: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Returned by `enter`; marks the current thread as an executor thread until dropped.
+pub struct EnterGuard {
+    was_in_executor: bool,
+}
+
+/// Mark the current thread as one which polls tasks.
+pub fn enter() -> EnterGuard {
+    let was_in_executor = IN_EXECUTOR.with(|flag| flag.replace(true));
+    EnterGuard { was_in_executor }
+}
+
+/// Whether the current thread is currently polling tasks for an executor.
+pub fn in_executor() -> bool {
+    IN_EXECUTOR.with(|flag| flag.get())
+}
+
+impl Drop for EnterGuard {
+    fn drop(&mut self) {
+        IN_EXECUTOR.with(|flag| flag.set(self.was_in_executor));
+    }
+}
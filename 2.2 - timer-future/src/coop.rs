@@ -0,0 +1,161 @@
+// Our executors never take a future away from a task: once polled, a task runs until it
+// returns `Pending`. A task doing a long stretch of work without awaiting anything keeps
+// every other task on its thread waiting, so it has to give them a turn by choice.
+//
+// Choice isn't always enough. A task receiving from a channel that never runs dry doesn't
+// look busy to its author: every `recv().await` is just immediately ready, so the loop never
+// returns `Pending`. To catch that, executors give each poll a budget of operations. Resources
+// like channels spend one unit per operation, and once it is spent they return `Pending` (and
+// wake the task straight away) even though they could go on, which sends the task to the back
+// of the ready queue.
+
+use std::{
+    cell::Cell,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Operations a task may perform in one poll before resources make it yield.
+pub const BUDGET: u32 = 128;
+
+thread_local! {
+    /// What's left of the running task's budget, `None` outside of `budget`.
+    static REMAINING: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+/// Run `poll` with a fresh budget. Executors wrap each poll of a task's future in this.
+pub fn budget<R>(poll: impl FnOnce() -> R) -> R {
+    // Put the previous budget back even if the poll panics.
+    struct Restore(Option<u32>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            REMAINING.with(|remaining| remaining.set(self.0));
+        }
+    }
+
+    let _restore = Restore(REMAINING.with(|remaining| remaining.replace(Some(BUDGET))));
+    poll()
+}
+
+/// Spend one unit of the running task's budget. Once it is used up, this wakes the task and
+/// returns `Pending`, and the resource should return `Pending` too. Outside of `budget`, for
+/// example under an executor which doesn't set one, there is no limit.
+pub fn poll_proceed(cx: &mut Context<'_>) -> Poll<()> {
+    REMAINING.with(|remaining| match remaining.get() {
+        Some(0) => {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        Some(left) => {
+            remaining.set(Some(left - 1));
+            Poll::Ready(())
+        }
+        None => Poll::Ready(()),
+    })
+}
+
+/// Future returned by `yield_now`.
+#[derive(Debug, Default)]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+/// Give other queued tasks a turn: returns `Pending` once, waking itself straight away so
+/// the task goes to the back of the ready queue.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{channel, waker::CountingWaker};
+    use futures::{executor::LocalPool, task::LocalSpawnExt};
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn yield_now_is_pending_once_and_wakes_itself() {
+        let counter = CountingWaker::new();
+        let waker = counter.waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = yield_now();
+
+        assert!(Pin::new(&mut future).poll(&mut cx).is_pending());
+        assert_eq!(counter.wake_count(), 1);
+        assert!(Pin::new(&mut future).poll(&mut cx).is_ready());
+        assert_eq!(counter.wake_count(), 1);
+    }
+
+    #[test]
+    fn yielding_tasks_take_turns() {
+        let mut pool = LocalPool::new();
+        let order = Rc::new(RefCell::new(Vec::new()));
+        for name in ["a", "b"] {
+            let order = order.clone();
+            pool.spawner()
+                .spawn_local(async move {
+                    for _ in 0..3 {
+                        order.borrow_mut().push(name);
+                        yield_now().await;
+                    }
+                })
+                .unwrap();
+        }
+
+        pool.run();
+        assert_eq!(*order.borrow(), ["a", "b", "a", "b", "a", "b"]);
+    }
+
+    #[test]
+    fn a_busy_receiver_yields_once_its_budget_is_spent() {
+        let (sender, receiver) = channel::channel(BUDGET as usize + 10);
+        for message in 0..BUDGET + 10 {
+            sender.try_send(message).unwrap();
+        }
+        drop(sender);
+
+        let received = Rc::new(Cell::new(0));
+        let mut task = Box::pin({
+            let received = received.clone();
+            async move {
+                while receiver.recv().await.is_some() {
+                    received.set(received.get() + 1);
+                }
+            }
+        });
+        let counter = CountingWaker::new();
+        let waker = counter.waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(budget(|| task.as_mut().poll(&mut cx)).is_pending());
+        assert_eq!(received.get(), BUDGET);
+        assert_eq!(counter.wake_count(), 1);
+
+        assert!(budget(|| task.as_mut().poll(&mut cx)).is_ready());
+        assert_eq!(received.get(), BUDGET + 10);
+    }
+
+    #[test]
+    fn no_budget_outside_an_executor() {
+        let waker = crate::waker::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for _ in 0..BUDGET * 2 {
+            assert!(poll_proceed(&mut cx).is_ready());
+        }
+    }
+}
@@ -0,0 +1,228 @@
+// `executor::Executor` leans on std: an mpsc channel for the ready queue and a `Mutex` around
+// each future. Neither exists on a microcontroller, but the ideas carry over. This module is a
+// second, much smaller executor along the same lines, written from `core` and `alloc` only, with
+// the two platform-specific pieces behind traits:
+//
+// - `Queue` holds woken tasks until they are polled. On std that can be a `Mutex<VecDeque>`;
+//   on an embedded target, a lock-free ring buffer or a critical-section-protected queue.
+// - `Park` puts the executor to sleep when there is nothing to do and wakes it up again.
+//   On std that's `thread::park`/`Thread::unpark`; on a Cortex-M, `WFI` and an interrupt.
+//
+// Implementations of both traits backed by std live at the bottom of this file, so the
+// executor can be tried out on a desktop.
+//
+// `executor::Executor` doesn't run on these pieces, and shares no code with this module. Its
+// queue capacity, timed waits for shutdown and, on a `WorkerPool`, work stealing all go
+// beyond what `Queue` and `Park` offer.
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, task::Wake};
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Waker},
+};
+
+/// Where woken tasks wait until the executor polls them.
+pub trait Queue: Send + Sync {
+    fn push(&self, task: Arc<Task>);
+    fn pop(&self) -> Option<Arc<Task>>;
+}
+
+/// Lets the executor sleep while the queue is empty.
+///
+/// An `unpark` which happens before the matching `park` must make that `park` return
+/// immediately, otherwise a task woken just before the executor goes to sleep is lost.
+pub trait Park: Send + Sync {
+    fn park(&self);
+    fn unpark(&self);
+}
+
+// What a task needs to put itself back on the queue, without knowing the queue's type.
+trait Schedule: Send + Sync {
+    fn schedule(&self, task: Arc<Task>);
+}
+
+struct Shared<Q, P> {
+    queue: Q,
+    park: P,
+    /// Spawned tasks which haven't completed yet.
+    live_tasks: AtomicUsize,
+}
+
+impl<Q: Queue, P: Park> Schedule for Shared<Q, P> {
+    fn schedule(&self, task: Arc<Task>) {
+        self.queue.push(task);
+        self.park.unpark();
+    }
+}
+
+/// A spawned future and the bookkeeping needed to reschedule it.
+pub struct Task {
+    /// In-progress future, `None` once it has completed.
+    ///
+    /// Without std there is no `Mutex`, so the future sits in an `UnsafeCell` instead.
+    /// See the `Sync` impl below for why that's sound.
+    future: UnsafeCell<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+
+    /// Whether the task is currently in the queue, so waking it twice doesn't queue it twice.
+    queued: AtomicBool,
+
+    scheduler: Arc<dyn Schedule>,
+}
+
+// Safety: the future is only accessed by `Executor::run`, which takes `&mut self`, so only one
+// thread polls tasks of a given executor at a time. Wakers only touch `queued` and `scheduler`.
+unsafe impl Sync for Task {}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.scheduler.schedule(self.clone());
+        }
+    }
+}
+
+/// Polls tasks from the queue until every spawned task has completed.
+pub struct Executor<Q, P> {
+    shared: Arc<Shared<Q, P>>,
+}
+
+/// Spawns new futures onto the executor's queue.
+pub struct Spawner<Q, P> {
+    shared: Arc<Shared<Q, P>>,
+}
+
+// Derived `Clone` would require `Q: Clone` and `P: Clone`.
+impl<Q, P> Clone for Spawner<Q, P> {
+    fn clone(&self) -> Self {
+        Spawner {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+pub fn new_executor_and_spawner<Q, P>(queue: Q, park: P) -> (Executor<Q, P>, Spawner<Q, P>)
+where
+    Q: Queue + 'static,
+    P: Park + 'static,
+{
+    let shared = Arc::new(Shared {
+        queue,
+        park,
+        live_tasks: AtomicUsize::new(0),
+    });
+    (
+        Executor {
+            shared: shared.clone(),
+        },
+        Spawner { shared },
+    )
+}
+
+impl<Q: Queue + 'static, P: Park + 'static> Spawner<Q, P> {
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        self.shared.live_tasks.fetch_add(1, Ordering::AcqRel);
+        let task = Arc::new(Task {
+            future: UnsafeCell::new(Some(Box::pin(future))),
+            queued: AtomicBool::new(true),
+            scheduler: self.shared.clone(),
+        });
+        self.shared.schedule(task);
+    }
+}
+
+impl<Q: Queue, P: Park> Executor<Q, P> {
+    /// Run until every spawned task has completed, parking whenever the queue is empty.
+    pub fn run(&mut self) {
+        loop {
+            while let Some(task) = self.shared.queue.pop() {
+                // Clear the flag before polling, so a wake during the poll queues the task again.
+                task.queued.store(false, Ordering::Release);
+
+                // Safety: see the `Sync` impl for `Task`.
+                let future_slot = unsafe { &mut *task.future.get() };
+                if let Some(mut future) = future_slot.take() {
+                    let waker = Waker::from(task.clone());
+                    let context = &mut Context::from_waker(&waker);
+                    if future.as_mut().poll(context).is_pending() {
+                        *future_slot = Some(future);
+                    } else {
+                        self.shared.live_tasks.fetch_sub(1, Ordering::AcqRel);
+                    }
+                }
+            }
+
+            if self.shared.live_tasks.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            self.shared.park.park();
+        }
+    }
+}
+
+// std implementations, for running the executor on a desktop.
+
+impl Queue for std::sync::Mutex<VecDeque<Arc<Task>>> {
+    fn push(&self, task: Arc<Task>) {
+        self.lock().unwrap().push_back(task);
+    }
+
+    fn pop(&self) -> Option<Arc<Task>> {
+        self.lock().unwrap().pop_front()
+    }
+}
+
+/// Parks the thread which created it. Create it on the thread that will call `Executor::run`.
+pub struct ThreadPark {
+    thread: std::thread::Thread,
+}
+
+impl ThreadPark {
+    pub fn current() -> Self {
+        ThreadPark {
+            thread: std::thread::current(),
+        }
+    }
+}
+
+impl Park for ThreadPark {
+    fn park(&self) {
+        // `thread::park` keeps a token from an earlier `unpark`, as `Park` requires.
+        std::thread::park();
+    }
+
+    fn unpark(&self) {
+        self.thread.unpark();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TimerFuture;
+    use std::{sync::Mutex, time::Duration};
+
+    #[test]
+    fn runs_tasks_woken_from_other_threads() {
+        let (mut executor, spawner) =
+            new_executor_and_spawner(Mutex::new(VecDeque::new()), ThreadPark::current());
+        let finished = Arc::new(AtomicUsize::new(0));
+
+        for millis in [30, 10, 20] {
+            let finished = finished.clone();
+            spawner.spawn(async move {
+                TimerFuture::new(Duration::from_millis(millis)).await;
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        executor.run();
+        assert_eq!(finished.load(Ordering::SeqCst), 3);
+    }
+}
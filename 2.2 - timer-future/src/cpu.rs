@@ -0,0 +1,97 @@
+// Async is about waiting on many things at once, not about doing many computations at once.
+// A future which crunches numbers for a second holds its executor thread for that whole second,
+// so every other task on that thread waits too. CPU-heavy work belongs on a thread pool built
+// for parallelism, like rayon's, and the async side only waits for the result.
+//
+// The handoff works exactly like `TimerFuture`: the pool thread stores the result in shared
+// state and wakes the task which is waiting for it.
+
+use std::{
+    any::Any,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// Future returned by `spawn_cpu`, resolving to the output of the closure.
+pub struct CpuFuture<T> {
+    shared_state: Arc<Mutex<SharedState<T>>>,
+}
+
+struct SharedState<T> {
+    /// The closure's output, or the payload it panicked with.
+    result: Option<Result<T, Box<dyn Any + Send>>>,
+
+    /// Waker of the task awaiting the result.
+    waker: Option<Waker>,
+}
+
+/// Run `work` on the rayon global thread pool and return a future for its result.
+///
+/// If `work` panics, the panic is resumed in the task that awaits the future.
+pub fn spawn_cpu<F, T>(work: F) -> CpuFuture<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let shared_state = Arc::new(Mutex::new(SharedState {
+        result: None,
+        waker: None,
+    }));
+
+    let pool_shared_state = shared_state.clone();
+    rayon::spawn(move || {
+        // A panic inside `rayon::spawn` aborts the process by default, so catch it and
+        // hand it over to the awaiting task instead.
+        let result = panic::catch_unwind(AssertUnwindSafe(work));
+
+        let mut shared_state = pool_shared_state.lock().unwrap();
+        shared_state.result = Some(result);
+        if let Some(waker) = shared_state.waker.take() {
+            waker.wake()
+        }
+    });
+
+    CpuFuture { shared_state }
+}
+
+impl<T> Future for CpuFuture<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut shared_state = self.shared_state.lock().unwrap();
+        match shared_state.result.take() {
+            Some(Ok(output)) => Poll::Ready(output),
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => {
+                // Executors hand a task the same waker on every poll, so this usually
+                // skips the clone.
+                match &shared_state.waker {
+                    Some(waker) if waker.will_wake(cx.waker()) => {}
+                    _ => shared_state.waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn resolves_to_the_closure_output() {
+        let sum = block_on(spawn_cpu(|| (1..=100u64).sum::<u64>()));
+        assert_eq!(sum, 5050);
+    }
+
+    #[test]
+    #[should_panic(expected = "boom")]
+    fn panics_are_resumed_in_the_awaiting_task() {
+        block_on(spawn_cpu(|| -> u32 { panic!("boom") }));
+    }
+}
@@ -0,0 +1,2363 @@
+// Our own executor, capable of running a large number of top-level futures to completion
+// concurrently.
+//
+// Future executors take a set of top-level Futures and run them to completion by calling poll
+// whenever the Future can make progress. Typically, an executor will poll a future once to start off.
+// When Futures indicate that they are ready to make progress by calling wake(),
+// they are placed back onto a queue and poll is called again, repeating until the Future has completed.
+
+use futures::{
+    future::{
+        self, AbortHandle, Abortable, BoxFuture, Either, FutureExt, FutureObj, LocalBoxFuture,
+    },
+    task::{waker, ArcWake, Spawn, SpawnError},
+};
+use std::{
+    cell::{Cell, RefCell, UnsafeCell},
+    collections::HashMap,
+    fmt,
+    future::Future,
+    mem::ManuallyDrop,
+    panic::{self, AssertUnwindSafe, Location},
+    pin::{pin, Pin},
+    rc::Rc,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    sync::mpsc::{
+        Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError,
+    },
+    sync::{mpsc, Arc, Mutex, Weak},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+use crate::worker_pool::{LocalQueue, WorkerCounters, WorkerPool, LOCAL_QUEUE};
+use crate::{
+    channel, context, coop, memory, oneshot,
+    replay::{Event, Recorder},
+    timer,
+    waker_set::WakerSet,
+    TimerFuture,
+};
+
+/// Emits a `tracing` event about a task, when built with `--features tracing`.
+macro_rules! trace_task {
+    ($task:expr, $message:literal) => {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(task.id = $task.id, $message);
+    };
+}
+
+/// Task executor that receives tasks off of a channel and runs them.
+///
+/// Tasks are polled in the order they were woken. The channel is first in, first out, and
+/// only the wake which finds a task idle queues it, so waking a task that is already queued
+/// doesn't move it, and a task woken while it is being polled joins the back of the queue
+/// once the poll is over. No task is polled twice while another, woken before it, waits to
+/// be polled once. `WorkerPool` trades this for locality with its LIFO slot and per-worker
+/// queues.
+pub struct Executor {
+    pub(crate) ready_queue: TaskReceiver,
+    /// Lets `block_on` queue its root task without keeping the channel open, which would
+    /// stop `run` from noticing that every `Spawner` and task is gone.
+    pub(crate) task_sender: Weak<Sender<Arc<Task>>>,
+    pub(crate) state: Arc<ExecutorState>,
+    /// Registered with `on_shutdown`, run in order by `shutdown`.
+    pub(crate) shutdown_hooks: Mutex<Vec<ShutdownHook>>,
+}
+
+type ShutdownHook = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+
+/// The receiving end of the task channel. Each new task taken off it frees a slot, which is
+/// passed on to a `Spawner::spawn_async` waiting for one.
+pub(crate) struct TaskReceiver {
+    receiver: Receiver<Arc<Task>>,
+    state: Arc<ExecutorState>,
+}
+
+impl TaskReceiver {
+    pub(crate) fn recv(&self) -> Result<Arc<Task>, RecvError> {
+        self.took(self.receiver.recv())
+    }
+
+    pub(crate) fn try_recv(&self) -> Result<Arc<Task>, TryRecvError> {
+        self.took(self.receiver.try_recv())
+    }
+
+    pub(crate) fn recv_timeout(&self, timeout: Duration) -> Result<Arc<Task>, RecvTimeoutError> {
+        self.took(self.receiver.recv_timeout(timeout))
+    }
+
+    fn took<E>(&self, result: Result<Arc<Task>, E>) -> Result<Arc<Task>, E> {
+        if let Ok(task) = &result {
+            if task.holds_slot.swap(false, Ordering::Relaxed) {
+                self.state.slot_freed();
+            }
+        }
+        result
+    }
+}
+
+/// `Spawner` spawns new futures onto the task channel.
+#[derive(Clone)]
+pub struct Spawner {
+    pub(crate) task_sender: Arc<Sender<Arc<Task>>>,
+    pub(crate) state: Arc<ExecutorState>,
+}
+
+/// State shared by an executor, its spawners and its tasks.
+pub(crate) struct ExecutorState {
+    /// Identifies the task channel, so a `WorkerPool` worker only keeps its own
+    /// pool's tasks in its local queue.
+    pub(crate) id: usize,
+    /// Set by `Executor::shutdown`, after which spawning fails.
+    pub(crate) shut_down: AtomicBool,
+    /// Spawned tasks whose futures haven't completed yet.
+    pub(crate) live_tasks: AtomicUsize,
+    /// Counters behind `Executor::metrics`.
+    pub(crate) tasks_spawned: AtomicUsize,
+    pub(crate) slow_polls: AtomicUsize,
+    pub(crate) tasks_completed: AtomicUsize,
+    pub(crate) lost_tasks: AtomicUsize,
+    pub(crate) expired_tasks: AtomicUsize,
+    pub(crate) stale_wakers: AtomicUsize,
+    pub(crate) queued_tasks: AtomicUsize,
+    pub(crate) polls: AtomicUsize,
+    /// New tasks in the task channel, not yet taken off it for their first poll. Tasks
+    /// which are woken don't count, so a wake never has to wait for room.
+    pub(crate) queued_spawns: AtomicUsize,
+    /// How high `queued_spawns` may go before spawning waits for room.
+    pub(crate) queue_capacity: usize,
+    /// `Spawner::spawn_async` calls waiting for room in the task channel.
+    pub(crate) capacity_waiters: Mutex<WakerSet>,
+    /// How many of those there are, so taking a task off the channel only locks
+    /// `capacity_waiters` when someone is waiting.
+    pub(crate) waiting_spawners: AtomicUsize,
+    pub(crate) panic_policy: PanicPolicy,
+    /// Polls taking at least this long are reported.
+    pub(crate) slow_poll_threshold: Duration,
+    /// Tasks still running this long after being spawned are cancelled, unless spawned with
+    /// `Spawner::spawn_long_lived`.
+    pub(crate) max_task_lifetime: Option<Duration>,
+    /// Whether tasks completing with wakers still held are reported, not just counted.
+    pub(crate) warn_on_stale_wakers: bool,
+    /// Hands out `Task::index`es.
+    pub(crate) next_task_index: AtomicUsize,
+    /// Told about every task spawned, woken and polled, if recording was asked for.
+    pub(crate) recorder: Option<Arc<Recorder>>,
+    /// Every live task, by task id, to report the ones left over at shutdown and to answer
+    /// `Executor::tasks`. Weak, so a task nothing can wake is still dropped, and reported
+    /// as lost.
+    pub(crate) live_task_list: Mutex<HashMap<usize, Weak<Task>>>,
+}
+
+/// What a live task is doing, from `Executor::tasks`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    /// Waiting to be woken.
+    Idle,
+    /// Woken, and queued to be polled.
+    Scheduled,
+    /// Being polled right now, on some thread.
+    Running,
+}
+
+/// A live task, from `Executor::tasks`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: usize,
+    /// The `spawn` call which created the task, which is the closest thing a task has to a
+    /// name.
+    pub spawned_at: &'static Location<'static>,
+    pub state: TaskState,
+    /// Times the task has been polled.
+    pub polls: usize,
+    /// When the task was last polled, or `None` if it hasn't been yet.
+    pub last_polled: Option<Instant>,
+    /// Time spent polling the task, in all.
+    pub busy: Duration,
+    /// The longest single poll of the task.
+    pub longest_poll: Duration,
+}
+
+/// How a task has been polled so far, kept on the task for `Executor::tasks`.
+#[derive(Clone, Copy, Debug, Default)]
+struct PollStats {
+    polls: usize,
+    last_polled: Option<Instant>,
+    busy: Duration,
+    longest_poll: Duration,
+}
+
+/// A task which hadn't completed when its executor shut down, from `Executor::leaked_tasks`.
+///
+/// Usually a detached task waiting on something that never happens, such as a channel
+/// whose sender was forgotten.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LeakedTask {
+    pub id: usize,
+    /// The `spawn` call which created the task.
+    pub spawned_at: &'static Location<'static>,
+}
+
+impl fmt::Display for LeakedTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "task {} spawned at {}", self.id, self.spawned_at)
+    }
+}
+
+impl ExecutorState {
+    /// Tasks spawned but not yet completed, in spawn order.
+    fn tasks(&self) -> Vec<TaskInfo> {
+        // Collected before looking at them, because the last `Arc` of a task going away here
+        // drops the task, which takes the lock again.
+        let live: Vec<Arc<Task>> = self
+            .live_task_list
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let mut tasks: Vec<TaskInfo> = live
+            .iter()
+            .filter_map(|task| {
+                let state = match task.lifecycle.load(Ordering::Acquire) {
+                    IDLE => TaskState::Idle,
+                    SCHEDULED => TaskState::Scheduled,
+                    RUNNING | NOTIFIED => TaskState::Running,
+                    // Completed since we took the lock.
+                    _ => return None,
+                };
+                let stats = *task.poll_stats.lock().unwrap();
+                Some(TaskInfo {
+                    id: task.id,
+                    spawned_at: task.spawned_at,
+                    state,
+                    polls: stats.polls,
+                    last_polled: stats.last_polled,
+                    busy: stats.busy,
+                    longest_poll: stats.longest_poll,
+                })
+            })
+            .collect();
+        tasks.sort_by_key(|task| task.id);
+        tasks
+    }
+
+    /// Lists every pending task, one per line, to look at when the program seems stuck.
+    pub(crate) fn dump(&self) -> String {
+        let tasks = self.tasks();
+        let now = Instant::now();
+        let mut dump = format!("{} pending tasks\n", tasks.len());
+        for task in tasks {
+            let last_polled = match task.last_polled {
+                Some(at) => format!("last polled {:?} ago", now.saturating_duration_since(at)),
+                None => "never polled".to_owned(),
+            };
+            dump += &format!(
+                "  task {} spawned at {}: {:?}, {} polls taking {:?} (longest {:?}), {}\n",
+                task.id,
+                task.spawned_at,
+                task.state,
+                task.polls,
+                task.busy,
+                task.longest_poll,
+                last_polled
+            );
+        }
+        dump
+    }
+
+    fn leaked_tasks(&self) -> Vec<LeakedTask> {
+        self.tasks()
+            .into_iter()
+            .map(|task| LeakedTask {
+                id: task.id,
+                spawned_at: task.spawned_at,
+            })
+            .collect()
+    }
+
+    pub(crate) fn metrics(&self) -> ExecutorMetrics {
+        ExecutorMetrics {
+            tasks_spawned: self.tasks_spawned.load(Ordering::Relaxed),
+            tasks_completed: self.tasks_completed.load(Ordering::Relaxed),
+            lost_tasks: self.lost_tasks.load(Ordering::Relaxed),
+            expired_tasks: self.expired_tasks.load(Ordering::Relaxed),
+            stale_wakers: self.stale_wakers.load(Ordering::Relaxed),
+            queue_depth: self.queued_tasks.load(Ordering::Relaxed),
+            polls: self.polls.load(Ordering::Relaxed),
+            slow_polls: self.slow_polls.load(Ordering::Relaxed),
+            timers: timer::accuracy(),
+        }
+    }
+
+    /// Prints the tasks left over at shutdown, if there are any.
+    pub(crate) fn report_leaks(&self) {
+        for task in self.leaked_tasks() {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(task.id = task.id, spawned_at = %task.spawned_at, "leaked");
+            eprintln!("executor shut down with a leaked {}", task);
+        }
+    }
+
+    /// Counts a new task going into the task channel. Fails if that would take it past
+    /// `queue_capacity`, unless `over_capacity` says to let it in anyway.
+    fn take_slot(&self, over_capacity: bool) -> bool {
+        if over_capacity {
+            self.queued_spawns.fetch_add(1, Ordering::SeqCst);
+            return true;
+        }
+        self.queued_spawns
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < self.queue_capacity).then_some(queued + 1)
+            })
+            .is_ok()
+    }
+
+    /// A new task has left the task channel; wakes one waiting `spawn_async` to take its
+    /// slot.
+    fn slot_freed(&self) {
+        self.queued_spawns.fetch_sub(1, Ordering::SeqCst);
+        // A `spawn_async` counts itself before registering, and tries again after, so one
+        // that isn't counted yet will see the free slot for itself.
+        if self.waiting_spawners.load(Ordering::SeqCst) > 0 {
+            self.capacity_waiters.lock().unwrap().notify_one();
+        }
+    }
+
+    /// Takes a `spawn_async` out of `capacity_waiters`. Returns whether it had been woken
+    /// and not registered again since.
+    fn stop_waiting(&self, key: usize) -> bool {
+        let woken = self.capacity_waiters.lock().unwrap().remove(key);
+        self.waiting_spawners.fetch_sub(1, Ordering::SeqCst);
+        woken
+    }
+}
+
+/// A snapshot of an executor's counters, from `Executor::metrics`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExecutorMetrics {
+    /// Tasks spawned since the executor was created.
+    pub tasks_spawned: usize,
+    /// Spawned tasks whose futures have completed.
+    pub tasks_completed: usize,
+    /// Tasks dropped while pending because nothing was left to wake them.
+    pub lost_tasks: usize,
+    /// Tasks cancelled for outliving the executor's maximum task lifetime.
+    pub expired_tasks: usize,
+    /// Tasks which completed while wakers for them were still held somewhere.
+    pub stale_wakers: usize,
+    /// Tasks currently waiting to be polled.
+    pub queue_depth: usize,
+    /// Times any task's future has been polled.
+    pub polls: usize,
+    /// Polls which took longer than the slow-poll threshold.
+    pub slow_polls: usize,
+    /// How late timers have fired. Timers aren't tied to an executor, so this covers every
+    /// `TimerFuture` in the process.
+    pub timers: timer::TimerAccuracy,
+}
+
+/// Why `Spawner::try_spawn` didn't spawn a task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrySpawnError {
+    /// The task channel is full; try again once the executor has caught up.
+    Full,
+    /// The executor has shut down, or been dropped.
+    ShutDown,
+}
+
+// The spawning methods are `#[track_caller]` all the way down to `new_task`, so a task knows
+// where in the caller's code it was spawned.
+impl Spawner {
+    /// Spawns `future`, blocking the thread while the task channel is full. On a thread
+    /// running tasks, which may be the one that has to make room, the task goes in over the
+    /// limit instead.
+    #[track_caller]
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> JoinHandle<T> {
+        self.spawn_with_lifetime(future, self.state.max_task_lifetime)
+    }
+
+    /// Spawns `future` like `spawn`, but exempt from the executor's maximum task lifetime,
+    /// for tasks meant to run as long as the program does, such as an accept loop.
+    #[track_caller]
+    pub fn spawn_long_lived<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> JoinHandle<T> {
+        self.spawn_with_lifetime(future, None)
+    }
+
+    #[track_caller]
+    pub fn spawn_with_lifetime<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+        lifetime: Option<Duration>,
+    ) -> JoinHandle<T> {
+        let (task, handle) = self.new_task(future, lifetime);
+        // If the executor has shut down, the task is dropped here, and with it its output
+        // sender, which the `JoinHandle` reports.
+        if context::in_executor() {
+            let _ = self.try_start(&task, true);
+            return handle;
+        }
+
+        let mut spawn = SpawnAsync {
+            spawner: self,
+            task: Some((task, handle)),
+            waker_key: None,
+        };
+        let waker = waker(Arc::new(ThreadWaker(thread::current())));
+        loop {
+            match Pin::new(&mut spawn).poll(&mut Context::from_waker(&waker)) {
+                Poll::Ready(Ok(handle)) => return handle,
+                Poll::Ready(Err(_)) => return spawn.task.take().unwrap().1,
+                // Parking can wake up spuriously, which just means an extra poll.
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    /// Spawns `future` if there is room in the task channel right now, so an overloaded
+    /// caller can shed work instead of blocking.
+    #[track_caller]
+    pub fn try_spawn<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> Result<JoinHandle<T>, TrySpawnError> {
+        let (task, handle) = self.new_task(future, self.state.max_task_lifetime);
+        self.try_start(&task, false)?;
+        Ok(handle)
+    }
+
+    /// Spawns `future`, waiting without blocking the thread while the task channel is full.
+    /// Fails if the executor shuts down first.
+    #[track_caller]
+    pub fn spawn_async<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> SpawnAsync<'_, T> {
+        let (task, handle) = self.new_task(future, self.state.max_task_lifetime);
+        SpawnAsync {
+            spawner: self,
+            task: Some((task, handle)),
+            waker_key: None,
+        }
+    }
+
+    /// Wraps `future` in a task, which is cancelled if it is still running after `lifetime`.
+    #[track_caller]
+    fn new_task<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+        lifetime: Option<Duration>,
+    ) -> (Arc<Task>, JoinHandle<T>) {
+        let _memory_scope = memory::scope(memory::Subsystem::Tasks);
+        let id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed);
+        let spawned_at = Location::caller();
+        let (output_sender, output) = oneshot::channel();
+        let future = async move {
+            // If the `JoinHandle` was dropped, nobody wants the output anyway.
+            let _ = output_sender.send(future.await);
+        };
+        let future = match lifetime {
+            None => future.boxed(),
+            Some(lifetime) => {
+                let state = self.state.clone();
+                async move {
+                    let expired = TimerFuture::new(lifetime);
+                    if let Either::Right(_) = future::select(pin!(future), expired).await {
+                        // Dropping the future drops its output sender, which the
+                        // `JoinHandle` reports.
+                        state.expired_tasks.fetch_add(1, Ordering::Relaxed);
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(task.id = id, spawned_at = %spawned_at, "expired");
+                        eprintln!(
+                            "task {} spawned at {} was still running after {:?}, cancelling it",
+                            id, spawned_at, lifetime
+                        );
+                    }
+                }
+                .boxed()
+            }
+        };
+
+        let task = Arc::new(Task {
+            id,
+            index: self.state.next_task_index.fetch_add(1, Ordering::Relaxed),
+            // Spawning queues the task straight away.
+            lifecycle: AtomicU8::new(SCHEDULED),
+            future: UnsafeCell::new(Some(future)),
+            spawned_at,
+            poll_stats: Mutex::new(PollStats::default()),
+            wakers: AtomicUsize::new(0),
+            task_sender: self.task_sender.clone(),
+            holds_slot: AtomicBool::new(false),
+            state: self.state.clone(),
+        });
+        (task, JoinHandle { output })
+    }
+
+    /// Queues a new task without blocking, counting it as spawned if that worked. With
+    /// `over_capacity`, it goes in even if the task channel is full.
+    fn try_start(&self, task: &Arc<Task>, over_capacity: bool) -> Result<(), TrySpawnError> {
+        // Count the task before checking for shutdown, so that either `shutdown` sees the
+        // task and waits for it, or we see the flag.
+        self.state.live_tasks.fetch_add(1, Ordering::SeqCst);
+        if self.state.shut_down.load(Ordering::SeqCst) {
+            self.state.live_tasks.fetch_sub(1, Ordering::SeqCst);
+            return Err(TrySpawnError::ShutDown);
+        }
+        if let Err(error) = task.try_schedule(over_capacity) {
+            self.state.live_tasks.fetch_sub(1, Ordering::SeqCst);
+            return Err(error);
+        }
+        self.state.tasks_spawned.fetch_add(1, Ordering::Relaxed);
+        task.started();
+        Ok(())
+    }
+}
+
+/// Future returned by `Spawner::spawn_async`.
+pub struct SpawnAsync<'a, T> {
+    spawner: &'a Spawner,
+    /// The task waiting to be queued, and its handle; `None` once done.
+    task: Option<(Arc<Task>, JoinHandle<T>)>,
+    /// Key into `ExecutorState::capacity_waiters` while waiting.
+    waker_key: Option<usize>,
+}
+
+impl<T> Future for SpawnAsync<'_, T> {
+    type Output = Result<JoinHandle<T>, SpawnError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let (task, _) = this.task.as_ref().expect("SpawnAsync polled after completion");
+        let mut result = this.spawner.try_start(task, false);
+        if result == Err(TrySpawnError::Full) {
+            let mut waiters = this.spawner.state.capacity_waiters.lock().unwrap();
+            if this.waker_key.is_none() {
+                this.spawner.state.waiting_spawners.fetch_add(1, Ordering::SeqCst);
+            }
+            waiters.register(&mut this.waker_key, cx.waker());
+            drop(waiters);
+            // A task may have left the channel before we registered; look again.
+            result = this.spawner.try_start(task, false);
+        }
+
+        let result = match result {
+            Ok(()) => Ok(this.task.take().unwrap().1),
+            Err(TrySpawnError::ShutDown) => Err(SpawnError::shutdown()),
+            Err(TrySpawnError::Full) => return Poll::Pending,
+        };
+        if let Some(key) = this.waker_key.take() {
+            this.spawner.state.stop_waiting(key);
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl<T> Drop for SpawnAsync<'_, T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.waker_key.take() {
+            // Woken for a slot it won't use now, so let the next one in line have it.
+            if self.spawner.state.stop_waiting(key) {
+                self.spawner.state.capacity_waiters.lock().unwrap().notify_one();
+            }
+        }
+    }
+}
+
+/// Resolves to the output of a task started with `Spawner::spawn`.
+///
+/// Dropping the handle detaches the task, which still runs to completion.
+pub struct JoinHandle<T> {
+    output: oneshot::Receiver<T>,
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        match self.output.poll_unpin(cx) {
+            Poll::Ready(Ok(output)) => Poll::Ready(output),
+            // The sender lives in the task's future, so it is only dropped without sending
+            // if the future was, e.g. because the executor was dropped or had shut down, or
+            // the task outlived its maximum lifetime.
+            Poll::Ready(Err(_)) => panic!("task was dropped before it completed"),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Implementing the `futures` `Spawn` trait lets code that is generic over executors,
+// like `AsyncDropGuard`, spawn onto ours.
+impl Spawn for Spawner {
+    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        self.status()?;
+        self.spawn(future);
+        Ok(())
+    }
+
+    fn status(&self) -> Result<(), SpawnError> {
+        if self.state.shut_down.load(Ordering::SeqCst) {
+            return Err(SpawnError::shutdown());
+        }
+        Ok(())
+    }
+}
+
+/// Lets synchronous code, such as a callback invoked on some other thread, run a future
+/// on the executor and block until its output is ready.
+#[derive(Clone)]
+pub struct SyncHandle {
+    spawner: Spawner,
+}
+
+impl SyncHandle {
+    pub fn new(spawner: Spawner) -> Self {
+        SyncHandle { spawner }
+    }
+
+    pub fn block_on<T: Send + 'static>(&self, future: impl Future<Output = T> + Send + 'static) -> T {
+        // The executor thread would be stuck here waiting for a task that only it can run.
+        assert!(
+            !context::in_executor(),
+            "SyncHandle::block_on called from an executor thread, which would deadlock; \
+             `.await` the future instead"
+        );
+
+        let (result_sender, result_receiver) = mpsc::channel();
+        // Like `Executor::block_on`'s future, this runs as long as the caller waits for it.
+        self.spawner.spawn_long_lived(async move {
+            // The receiver only goes away if the caller has stopped waiting.
+            let _ = result_sender.send(future.await);
+        });
+        result_receiver
+            .recv()
+            .expect("executor dropped the task before it completed")
+    }
+}
+
+/// A `Spawner` for the executor running the current task, from `Handle::current`, so code
+/// deep inside a task can spawn more without a `Spawner` being passed down to it.
+#[derive(Clone)]
+pub struct Handle {
+    spawner: Spawner,
+}
+
+thread_local! {
+    /// The `!Send` future `Executor::block_on_local` is running on this thread.
+    static LOCAL_ROOT: RefCell<Option<LocalBoxFuture<'static, ()>>> = const { RefCell::new(None) };
+
+    /// The task being polled on this thread, for `Handle::current`.
+    static CURRENT_TASK: RefCell<Option<Arc<Task>>> = const { RefCell::new(None) };
+}
+
+/// Makes a task the current one for as long as it is polled.
+struct CurrentTaskGuard {
+    previous: Option<Arc<Task>>,
+}
+
+impl CurrentTaskGuard {
+    fn enter(task: &Arc<Task>) -> Self {
+        let previous = CURRENT_TASK.with(|current| current.replace(Some(task.clone())));
+        CurrentTaskGuard { previous }
+    }
+}
+
+impl Drop for CurrentTaskGuard {
+    fn drop(&mut self) {
+        CURRENT_TASK.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+impl Handle {
+    /// The handle of the executor polling the calling task.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside a task; see `try_current`.
+    #[track_caller]
+    pub fn current() -> Handle {
+        Handle::try_current().expect("Handle::current called outside a task")
+    }
+
+    /// The handle of the executor polling the calling task, or `None` outside a task.
+    pub fn try_current() -> Option<Handle> {
+        CURRENT_TASK.with(|current| {
+            let current = current.borrow();
+            let task = current.as_ref()?;
+            Some(Handle {
+                spawner: Spawner {
+                    task_sender: task.task_sender.clone(),
+                    state: task.state.clone(),
+                },
+            })
+        })
+    }
+
+    /// Spawns `future` onto the current task's executor, as `Spawner::spawn` does.
+    #[track_caller]
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> JoinHandle<T> {
+        self.spawner.spawn(future)
+    }
+
+    pub fn spawner(&self) -> &Spawner {
+        &self.spawner
+    }
+}
+
+/// Spawns child tasks which can't outlive it, for structured concurrency: `join` waits for
+/// every child, and dropping the group without joining cancels the ones still running.
+///
+/// A child that panics (with `PanicPolicy::DropTask`) makes `join` panic too, so the failure
+/// reaches the parent instead of disappearing with a detached task.
+pub struct TaskGroup {
+    spawner: Spawner,
+    children: Vec<(JoinHandle<Result<(), futures::future::Aborted>>, AbortHandle)>,
+}
+
+impl TaskGroup {
+    pub fn new(spawner: &Spawner) -> Self {
+        TaskGroup {
+            spawner: spawner.clone(),
+            children: Vec::new(),
+        }
+    }
+
+    #[track_caller]
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + Send + 'static) {
+        let (abort_handle, registration) = AbortHandle::new_pair();
+        let handle = self.spawner.spawn(Abortable::new(future, registration));
+        self.children.push((handle, abort_handle));
+    }
+
+    /// Waits until every child has completed.
+    pub async fn join(mut self) {
+        for (handle, _) in std::mem::take(&mut self.children) {
+            // A child can only have been aborted by `cancel`, which consumes the group.
+            let _ = handle.await;
+        }
+    }
+
+    /// Cancels every child still running. Each is dropped the next time it would be polled.
+    pub fn cancel(self) {
+        // Dropping the group aborts them.
+    }
+}
+
+impl Drop for TaskGroup {
+    fn drop(&mut self) {
+        for (_, abort_handle) in &self.children {
+            abort_handle.abort();
+        }
+    }
+}
+
+/// A future that can reschedule itself to be polled by an `Executor`.
+pub(crate) struct Task {
+    /// Unique across every executor, to tell tasks apart when tracing or debugging.
+    id: usize,
+
+    /// Spawn order within the executor, which is how a recorded `Schedule` names the task.
+    index: usize,
+
+    /// Where the task is in its life: one of `IDLE`, `SCHEDULED`, `RUNNING`, `NOTIFIED` or
+    /// `COMPLETE`.
+    ///
+    /// A task is only queued when a wake moves it from `IDLE` to `SCHEDULED`, so it is in
+    /// at most one queue at a time however often it is woken, and only the thread which
+    /// takes it from `SCHEDULED` to `RUNNING` polls it.
+    lifecycle: AtomicU8,
+
+    /// In-progress future that should be pushed to completion, or `None` once it has.
+    ///
+    /// Only the thread which moved `lifecycle` to `RUNNING` touches it, so it needs no
+    /// `Mutex`; Rust can't see that, hence the `UnsafeCell`.
+    future: UnsafeCell<Option<BoxFuture<'static, ()>>>,
+
+    /// The `spawn` call which created the task.
+    spawned_at: &'static Location<'static>,
+
+    /// Only written by the thread polling the task, but read by `Executor::tasks` from
+    /// anywhere.
+    poll_stats: Mutex<PollStats>,
+
+    /// Clones of the task's waker which haven't been dropped yet, wherever they are held.
+    wakers: AtomicUsize,
+
+    /// Handle to place the task itself back onto the task queue.
+    task_sender: Arc<Sender<Arc<Task>>>,
+
+    /// Whether the task is new, and counted in `ExecutorState::queued_spawns` until it's
+    /// taken off the task channel.
+    holds_slot: AtomicBool,
+
+    state: Arc<ExecutorState>,
+}
+
+/// Not queued; waiting to be woken.
+const IDLE: u8 = 0;
+/// Queued to be polled.
+const SCHEDULED: u8 = 1;
+/// Being polled.
+const RUNNING: u8 = 2;
+/// Woken while being polled; whoever is polling it queues it again afterwards.
+const NOTIFIED: u8 = 3;
+/// The future has completed or panicked, and won't be polled again.
+const COMPLETE: u8 = 4;
+
+// Safety: `future` is only accessed by the one thread which holds the task in `RUNNING`
+// (see `Task::poll`), and the `lifecycle` transitions in and out of it order those accesses.
+unsafe impl Sync for Task {}
+
+// The future itself can't be printed, so tasks are told apart by their id.
+impl fmt::Debug for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Task").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+/// Hands out `Task::id`s.
+static NEXT_TASK_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Hands out a distinct `ExecutorState::id` to each task channel.
+static NEXT_EXECUTOR_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Shorthand for `Executor::builder().build()`.
+pub fn new_executor_and_spawner() -> (Executor, Spawner) {
+    Executor::builder().build()
+}
+
+/// What an executor does when a task's future panics while being polled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Let the panic unwind out of the executor, just like a panic in a function call:
+    /// `run` or `block_on` panics, or the worker thread dies.
+    Propagate,
+    /// Catch the panic and drop the task, and carry on running the others. Awaiting the
+    /// task's `JoinHandle` panics instead.
+    DropTask,
+}
+
+/// Configures an `Executor` or `WorkerPool`, see `Executor::builder`.
+pub struct ExecutorBuilder {
+    queue_capacity: usize,
+    worker_threads: usize,
+    thread_name: String,
+    panic_policy: PanicPolicy,
+    slow_poll_threshold: Duration,
+    recorder: Option<Arc<Recorder>>,
+    lifo_slot: bool,
+    max_task_lifetime: Option<Duration>,
+    warn_on_stale_wakers: bool,
+}
+
+impl ExecutorBuilder {
+    /// How many new tasks the task channel holds before `spawn` blocks, `try_spawn` fails
+    /// and `spawn_async` waits. Tasks which have been polled and are woken again don't
+    /// count, and are always queued straight away. Defaults to 10,000.
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity;
+        self
+    }
+
+    /// How many threads a `WorkerPool` polls tasks on. Defaults to the number of CPUs.
+    pub fn worker_threads(mut self, worker_threads: usize) -> Self {
+        self.worker_threads = worker_threads;
+        self
+    }
+
+    /// `WorkerPool` threads are named `{name}-{index}`. Defaults to `worker`.
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// Defaults to `PanicPolicy::Propagate`.
+    pub fn panic_policy(mut self, panic_policy: PanicPolicy) -> Self {
+        self.panic_policy = panic_policy;
+        self
+    }
+
+    /// Polls taking at least `threshold` are reported on stderr, with where the task was
+    /// spawned, and counted in `ExecutorMetrics::slow_polls`. Defaults to 100ms.
+    pub fn slow_poll_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_poll_threshold = threshold;
+        self
+    }
+
+    /// Report every task spawned, woken and polled to `recorder`, so the run can be replayed
+    /// with `ReplayExecutor`. Off by default.
+    pub fn record_schedule(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Cancel tasks still running `lifetime` after they were spawned, reporting where they
+    /// were spawned, so a connection task stuck forever can't hold its resources forever.
+    /// Tasks spawned with `Spawner::spawn_long_lived` are exempt. Each task then has a timer
+    /// running alongside it. Off by default.
+    pub fn max_task_lifetime(mut self, lifetime: Duration) -> Self {
+        self.max_task_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Print a warning, as well as counting it in `ExecutorMetrics::stale_wakers`, for every
+    /// task which completes while a clone of its waker is still held somewhere. That's often
+    /// harmless (`futures`' `Abortable` keeps one in its `AbortHandle`, for instance), but
+    /// it also catches futures which register a waker and never take it back. Off by default.
+    pub fn warn_on_stale_wakers(mut self, enabled: bool) -> Self {
+        self.warn_on_stale_wakers = enabled;
+        self
+    }
+
+    /// Whether each `WorkerPool` worker polls the task most recently woken by the task it is
+    /// running next, ahead of its queue; see `WorkerQueues::lifo_slot`. Defaults to `true`.
+    pub fn lifo_slot(mut self, enabled: bool) -> Self {
+        self.lifo_slot = enabled;
+        self
+    }
+
+    /// Builds an executor which runs tasks on the thread calling `run` or `block_on`.
+    pub fn build(self) -> (Executor, Spawner) {
+        let (task_sender, ready_queue) = mpsc::channel();
+        let task_sender = Arc::new(task_sender);
+        let state = Arc::new(ExecutorState {
+            id: NEXT_EXECUTOR_ID.fetch_add(1, Ordering::Relaxed),
+            shut_down: AtomicBool::new(false),
+            live_tasks: AtomicUsize::new(0),
+            tasks_spawned: AtomicUsize::new(0),
+            tasks_completed: AtomicUsize::new(0),
+            lost_tasks: AtomicUsize::new(0),
+            expired_tasks: AtomicUsize::new(0),
+            stale_wakers: AtomicUsize::new(0),
+            queued_tasks: AtomicUsize::new(0),
+            polls: AtomicUsize::new(0),
+            queued_spawns: AtomicUsize::new(0),
+            queue_capacity: self.queue_capacity,
+            capacity_waiters: Mutex::new(WakerSet::new()),
+            waiting_spawners: AtomicUsize::new(0),
+            panic_policy: self.panic_policy,
+            slow_poll_threshold: self.slow_poll_threshold,
+            max_task_lifetime: self.max_task_lifetime,
+            warn_on_stale_wakers: self.warn_on_stale_wakers,
+            slow_polls: AtomicUsize::new(0),
+            next_task_index: AtomicUsize::new(0),
+            recorder: self.recorder,
+            live_task_list: Mutex::new(HashMap::new()),
+        });
+        let executor = Executor {
+            ready_queue: TaskReceiver { receiver: ready_queue, state: state.clone() },
+            task_sender: Arc::downgrade(&task_sender),
+            state: state.clone(),
+            shutdown_hooks: Mutex::new(Vec::new()),
+        };
+        (executor, Spawner { task_sender, state })
+    }
+
+    /// Builds a pool of `worker_threads` threads sharing the tasks.
+    pub fn build_worker_pool(self) -> (WorkerPool, Spawner) {
+        assert!(self.worker_threads > 0, "a worker pool needs at least one worker");
+        let workers = self.worker_threads;
+        let thread_name = self.thread_name.clone();
+        let lifo_slot = self.lifo_slot;
+        let (executor, spawner) = self.build();
+        let pool = WorkerPool {
+            ready_queue: Arc::new(Mutex::new(executor.ready_queue)),
+            workers,
+            thread_name,
+            executor_id: executor.state.id,
+            lifo_slot,
+            stop: Arc::new(AtomicBool::new(false)),
+            local_queues: Arc::new((0..workers).map(|_| LocalQueue::default()).collect()),
+            counters: Arc::new((0..workers).map(|_| WorkerCounters::default()).collect()),
+        };
+        (pool, spawner)
+    }
+}
+
+// To poll futures, we'll need to create a Waker.
+// Wakers are responsible for scheduling a task to be polled again once wake is called.
+// Remember that Wakers tell the executor exactly which task has become ready,
+// allowing them to poll just the futures that are ready to make progress.
+// Task wakers are built by hand, like `waker::CountingWaker`, rather than with `waker_ref`, so
+// each task can count the clones of its waker held by timers, channels and the like. The data
+// pointer is the task's `Arc`, and each clone owns one strong reference, as with `ArcWake`.
+// A `static` rather than a `const`, so every waker points at the same table and `will_wake`
+// can tell wakers for the same task apart from the rest.
+static TASK_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    task_waker_clone,
+    task_waker_wake,
+    task_waker_wake_by_ref,
+    task_waker_drop,
+);
+
+unsafe fn task_waker_clone(data: *const ()) -> RawWaker {
+    let task = &*(data as *const Task);
+    task.wakers.fetch_add(1, Ordering::AcqRel);
+    Arc::increment_strong_count(data as *const Task);
+    RawWaker::new(data, &TASK_WAKER_VTABLE)
+}
+
+unsafe fn task_waker_wake(data: *const ()) {
+    let task = Arc::from_raw(data as *const Task);
+    // Stop counting the waker before the wake-up, which may let the task complete.
+    task.wakers.fetch_sub(1, Ordering::AcqRel);
+    ArcWake::wake_by_ref(&task);
+}
+
+unsafe fn task_waker_wake_by_ref(data: *const ()) {
+    // Borrowed, so the reference count stays untouched.
+    let task = ManuallyDrop::new(Arc::from_raw(data as *const Task));
+    ArcWake::wake_by_ref(&task);
+}
+
+unsafe fn task_waker_drop(data: *const ()) {
+    let task = Arc::from_raw(data as *const Task);
+    task.wakers.fetch_sub(1, Ordering::AcqRel);
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        // Implement `wake` by sending this task back onto the task channel
+        // so that it will be polled again by the executor.
+        trace_task!(arc_self, "woken");
+        arc_self.record(Event::Woken);
+        let mut lifecycle = arc_self.lifecycle.load(Ordering::Acquire);
+        loop {
+            let next = match lifecycle {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                // Already going to be polled, or never will be again.
+                _ => return,
+            };
+            match arc_self.lifecycle.compare_exchange_weak(
+                lifecycle,
+                next,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(actual) => lifecycle = actual,
+            }
+        }
+        // A `RUNNING` task is queued again by the thread polling it, once it's done.
+        if lifecycle == IDLE {
+            arc_self.schedule();
+        }
+    }
+}
+
+// The executor only holds a task while it is queued or being polled; an `IDLE` task is kept
+// alive by its wakers alone. So a task dropped while `IDLE` is one whose every waker was
+// dropped while it was pending, e.g. by a future that returned `Pending` without registering
+// its waker anywhere, and which could never have been polled again. Without this it would
+// count as live forever, and `shutdown` would wait for it until the deadline.
+//
+// A task whose future holds its own waker (say, in a channel only it can send on) keeps
+// itself alive, and isn't caught here; `shutdown` still reports it as leaked.
+impl Drop for Task {
+    fn drop(&mut self) {
+        if *self.lifecycle.get_mut() != IDLE {
+            return;
+        }
+        self.state.lost_tasks.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "tracing")]
+        tracing::warn!(task.id = self.id, spawned_at = %self.spawned_at, "lost");
+        eprintln!(
+            "task {} spawned at {} can never be woken: every waker for it was dropped \
+             while it was pending",
+            self.id, self.spawned_at
+        );
+        self.finished();
+    }
+}
+
+// When a Waker is created from an Arc<Task>, calling wake() on it will cause a copy
+// of the Arc to be sent onto the task channel.
+// Our executor then needs to pick up the task and poll it.
+impl Task {
+    /// Notes that the task has been spawned, now that it is counted as live.
+    fn started(self: &Arc<Self>) {
+        trace_task!(self, "spawned");
+        self.record(Event::Spawned);
+        self.state
+            .live_task_list
+            .lock()
+            .unwrap()
+            .insert(self.id, Arc::downgrade(self));
+    }
+
+    /// Notes that the task is no longer live, having completed or panicked.
+    fn finished(&self) {
+        self.state.live_tasks.fetch_sub(1, Ordering::SeqCst);
+        self.state.live_task_list.lock().unwrap().remove(&self.id);
+    }
+
+    /// Counts a poll which started at `started` and took `elapsed`.
+    fn polled(&self, started: Instant, elapsed: Duration) {
+        let mut stats = self.poll_stats.lock().unwrap();
+        stats.polls += 1;
+        stats.last_polled = Some(started);
+        stats.busy += elapsed;
+        stats.longest_poll = stats.longest_poll.max(elapsed);
+    }
+
+    /// Warns about a poll which kept the thread for longer than the executor's slow-poll
+    /// threshold, which usually means the future is blocking or doing heavy work inline.
+    fn check_poll_time(&self, elapsed: Duration) {
+        if elapsed < self.state.slow_poll_threshold {
+            return;
+        }
+        self.state.slow_polls.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            task.id = self.id,
+            spawned_at = %self.spawned_at,
+            elapsed = ?elapsed,
+            "slow poll"
+        );
+        eprintln!(
+            "task {} spawned at {} took {:?} to poll, holding up the other tasks",
+            self.id, self.spawned_at, elapsed
+        );
+    }
+
+    /// Tells the executor's `Recorder` about the task, if it has one.
+    fn record(&self, event: fn(usize) -> Event) {
+        if let Some(recorder) = &self.state.recorder {
+            recorder.record(event(self.index));
+        }
+    }
+
+    /// Queues the task to be polled.
+    ///
+    /// On one of its own `WorkerPool`'s threads the task goes onto that worker's local
+    /// queue, which it is likely to get to soonest; everywhere else onto the task channel.
+    fn schedule(self: &Arc<Self>) {
+        self.enqueue(true);
+    }
+
+    /// Queues a task woken while it was being polled, once the poll is over. It has just had
+    /// its turn, so it goes to the back of the queue rather than into the LIFO slot, or a
+    /// task yielding would be polled again straight away.
+    fn requeue(self: &Arc<Self>) {
+        self.enqueue(false);
+    }
+
+    fn enqueue(self: &Arc<Self>, lifo: bool) {
+        self.state.queued_tasks.fetch_add(1, Ordering::Relaxed);
+        if !self.schedule_locally(lifo) {
+            // Only fails once the executor is gone, and with it any chance to run the task.
+            let _ = self.task_sender.send(self.clone());
+        }
+    }
+
+    /// Queues a new task like `schedule`, but if it goes onto the task channel it takes one
+    /// of the `queue_capacity` slots there, and fails if there are none left, unless
+    /// `over_capacity`.
+    fn try_schedule(self: &Arc<Self>, over_capacity: bool) -> Result<(), TrySpawnError> {
+        if self.schedule_locally(true) {
+            self.state.queued_tasks.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        if !self.state.take_slot(over_capacity) {
+            return Err(TrySpawnError::Full);
+        }
+        self.holds_slot.store(true, Ordering::Relaxed);
+        self.state.queued_tasks.fetch_add(1, Ordering::Relaxed);
+        self.task_sender.send(self.clone()).map_err(|_| {
+            self.state.queued_tasks.fetch_sub(1, Ordering::Relaxed);
+            self.holds_slot.store(false, Ordering::Relaxed);
+            self.state.slot_freed();
+            TrySpawnError::ShutDown
+        })
+    }
+
+    /// Puts the task in the current worker's LIFO slot (if `lifo` and it has one) or local
+    /// queue, if this is a worker thread of the task's own pool. The local queue has no
+    /// limit.
+    fn schedule_locally(self: &Arc<Self>, lifo: bool) -> bool {
+        LOCAL_QUEUE.with(|local| match &*local.borrow() {
+            Some(queues) if queues.executor_id == self.state.id => {
+                let queued = match (&queues.lifo_slot, lifo) {
+                    (Some(slot), true) => slot.replace(Some(self.clone())),
+                    _ => Some(self.clone()),
+                };
+                if let Some(task) = queued {
+                    queues.local.lock().unwrap().push_back(task);
+                }
+                true
+            }
+            _ => false,
+        })
+    }
+
+    /// Polls the future once, if it has not yet completed.
+    pub(crate) fn poll(self: &Arc<Self>) {
+        self.state.queued_tasks.fetch_sub(1, Ordering::Relaxed);
+        // Claim the task. Only a `SCHEDULED` task is ever queued, and only once, so this
+        // can't fail while the task is still live.
+        if self
+            .lifecycle
+            .compare_exchange(SCHEDULED, RUNNING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        // Safety: we moved the task to `RUNNING`, so the future is ours until we move it
+        // out again.
+        let future_slot = unsafe { &mut *self.future.get() };
+        // If it has not yet completed (is still Some), poll it in an attempt to complete it.
+        if let Some(future) = future_slot {
+            self.state.polls.fetch_add(1, Ordering::Relaxed);
+            self.record(Event::Polled);
+            // Subscribers see the poll start and end as the span being entered and exited.
+            #[cfg(feature = "tracing")]
+            let _span = tracing::trace_span!("poll", task.id = self.id).entered();
+            // Create a waker from the task itself. It borrows the task rather than cloning the
+            // `Arc`, so building one per poll costs nothing, and it points at the same task
+            // every time, so a future's stored waker `will_wake` the new one and needn't be
+            // cloned again.
+            let waker = self.borrowed_waker();
+            let context = &mut Context::from_waker(&waker);
+            let _current = CurrentTaskGuard::enter(self);
+
+            // `BoxFuture<T>` is a type alias for
+            // `Pin<Box<dyn Future<Output = T> + Send + 'static>>`.
+            // We can get a `Pin<&mut dyn Future + Send + 'static>`
+            // from it by calling the `Pin::as_mut` method.
+            //
+            // The poll gets a fresh cooperative budget, so the future can't keep this thread
+            // to itself by looping over resources that are always ready.
+            //
+            // Panics are caught either way, so the report can say where the task came from.
+            let started = Instant::now();
+            let poll = panic::catch_unwind(AssertUnwindSafe(|| {
+                coop::budget(|| future.as_mut().poll(context))
+            }));
+            let elapsed = started.elapsed();
+            self.polled(started, elapsed);
+            self.check_poll_time(elapsed);
+            let poll = match poll {
+                Ok(poll) => Some(poll),
+                Err(payload) => {
+                    eprintln!("task {} spawned at {} panicked", self.id, self.spawned_at);
+                    match self.state.panic_policy {
+                        PanicPolicy::Propagate => panic::resume_unwind(payload),
+                        PanicPolicy::DropTask => None,
+                    }
+                }
+            };
+            match poll {
+                Some(Poll::Pending) => {
+                    // We're not done processing the future, so leave it in its task to be
+                    // run again in the future. If it was woken while we polled it, that
+                    // wake-up left the queueing to us.
+                    if self
+                        .lifecycle
+                        .compare_exchange(RUNNING, IDLE, Ordering::AcqRel, Ordering::Acquire)
+                        .is_err()
+                    {
+                        self.lifecycle.store(SCHEDULED, Ordering::Release);
+                        self.requeue();
+                    }
+                    return;
+                }
+                Some(Poll::Ready(())) => {
+                    self.finished();
+                    self.state.tasks_completed.fetch_add(1, Ordering::Relaxed);
+                    trace_task!(self, "completed");
+                }
+                // The future panicked, and is dropped along with its `JoinHandle`'s sender.
+                None => {
+                    self.finished();
+                    trace_task!(self, "panicked");
+                }
+            }
+        }
+        *future_slot = None;
+        self.lifecycle.store(COMPLETE, Ordering::Release);
+        self.check_stale_wakers();
+    }
+
+    /// A waker for the task which borrows it, for the duration of a poll. Only clones of it
+    /// own a reference to the task, and count as held.
+    fn borrowed_waker(self: &Arc<Self>) -> ManuallyDrop<Waker> {
+        let data = Arc::as_ptr(self) as *const ();
+        // Safety: the vtable treats `data` as an `Arc<Task>` pointer. This waker owns no
+        // reference, and `ManuallyDrop` makes sure it never gives one back; `self` keeps the
+        // task alive for as long as the waker is borrowed.
+        ManuallyDrop::new(unsafe { Waker::from_raw(RawWaker::new(data, &TASK_WAKER_VTABLE)) })
+    }
+
+    /// Counts, and if asked warns about, wakers for the task which are still held now that it
+    /// has completed, and its future with everything it owned is gone. Whatever holds them has no reason to:
+    /// usually a registration with some resource which isn't removed when the future
+    /// stops waiting on it, e.g. after losing a `select`.
+    fn check_stale_wakers(&self) {
+        let stale = self.wakers.load(Ordering::Acquire);
+        if stale == 0 {
+            return;
+        }
+        self.state.stale_wakers.fetch_add(1, Ordering::Relaxed);
+        if !self.state.warn_on_stale_wakers {
+            return;
+        }
+        #[cfg(feature = "tracing")]
+        tracing::warn!(task.id = self.id, spawned_at = %self.spawned_at, wakers = stale, "stale wakers");
+        eprintln!(
+            "task {} spawned at {} completed, but {} of its wakers are still held elsewhere",
+            self.id, self.spawned_at, stale
+        );
+    }
+}
+
+impl Executor {
+    pub fn builder() -> ExecutorBuilder {
+        ExecutorBuilder {
+            queue_capacity: 10_000,
+            worker_threads: thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+            thread_name: "worker".to_owned(),
+            panic_policy: PanicPolicy::Propagate,
+            slow_poll_threshold: Duration::from_millis(100),
+            recorder: None,
+            lifo_slot: true,
+            max_task_lifetime: None,
+            warn_on_stale_wakers: false,
+        }
+    }
+
+    pub fn run(&self) {
+        // Let code running inside tasks know it is on the executor thread.
+        let _enter = context::enter();
+        while let Ok(task) = self.ready_queue.recv() {
+            task.poll();
+        }
+    }
+
+    /// Polls the task at the front of the queue, if any, without waiting for one to be
+    /// woken. Returns whether a task was polled.
+    pub fn try_run_one(&self) -> bool {
+        let _enter = context::enter();
+        match self.ready_queue.try_recv() {
+            Ok(task) => {
+                task.poll();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Polls tasks until none is ready, and returns how many polls that took. Tasks waiting
+    /// on a timer or anything else outside the executor are left waiting, and a task that
+    /// keeps waking itself keeps this running.
+    pub fn run_until_idle(&self) -> usize {
+        let mut polls = 0;
+        while self.try_run_one() {
+            polls += 1;
+        }
+        polls
+    }
+
+    /// Runs spawned tasks until `future` completes, and returns its output.
+    ///
+    /// Unlike `run`, this doesn't wait for every `Spawner` to be dropped; tasks that are
+    /// still pending stay queued for a later `run` or `block_on`.
+    pub fn block_on<T: Send + 'static>(&self, future: impl Future<Output = T> + Send + 'static) -> T {
+        assert!(
+            !self.state.shut_down.load(Ordering::SeqCst),
+            "Executor::block_on called after shutdown"
+        );
+        let task_sender = match self.task_sender.upgrade() {
+            Some(task_sender) => task_sender,
+            // Every `Spawner` and task is gone, so there is nothing else to run.
+            None => {
+                let _enter = context::enter();
+                return futures::executor::block_on(future);
+            }
+        };
+        let spawner = Spawner { task_sender, state: self.state.clone() };
+        // The root's panic is caught in the task, so it reaches us whatever the `PanicPolicy`;
+        // with `DropTask` the task would just be dropped, leaving us waiting for it.
+        let future = AssertUnwindSafe(future).catch_unwind();
+        // The root runs as long as the caller wants it to, like a `spawn_long_lived` task.
+        let (root, JoinHandle { output }) = spawner.new_task(future, None);
+        // Only this thread can make room in the task channel, so the root doesn't wait for it.
+        let _ = spawner.try_start(&root, true);
+        drop(root);
+
+        let _enter = context::enter();
+        loop {
+            // `spawner` keeps the channel open, so this can't fail.
+            let task = self.ready_queue.recv().expect("task channel closed");
+            task.poll();
+            match output.try_recv() {
+                Ok(Ok(output)) => return output,
+                Ok(Err(payload)) => panic::resume_unwind(payload),
+                // Cancelled for outliving the maximum task lifetime.
+                Err(channel::TryRecvError::Disconnected) => {
+                    panic!("block_on's future was dropped before it completed")
+                }
+                Err(channel::TryRecvError::Empty) => {}
+            }
+        }
+    }
+
+    /// Like `block_on`, but `future` needn't be `Send`: say a `LocalSet::run_until`, whose
+    /// `Rc`-holding tasks then run alongside this executor's own.
+    ///
+    /// `future` never leaves this thread. The task spawned in its place polls it from a
+    /// thread-local, which works because this executor polls every task on the thread
+    /// calling `block_on_local`.
+    pub fn block_on_local<T: 'static>(&self, future: impl Future<Output = T> + 'static) -> T {
+        let output = Rc::new(Cell::new(None));
+        let root_output = output.clone();
+        let root = async move { root_output.set(Some(future.await)) }.boxed_local();
+        LOCAL_ROOT.with(|slot| {
+            let mut slot = slot.borrow_mut();
+            assert!(slot.is_none(), "Executor::block_on_local called from inside itself");
+            *slot = Some(root);
+        });
+
+        self.block_on(futures::future::poll_fn(|cx| {
+            LOCAL_ROOT.with(|slot| {
+                let mut slot = slot.borrow_mut();
+                let root = slot.as_mut().expect("block_on_local's root polled on another thread");
+                root.as_mut().poll(cx)
+            })
+        }));
+        LOCAL_ROOT.with(|slot| slot.borrow_mut().take());
+        output.take().expect("block_on_local's root completed without an output")
+    }
+
+    /// Stops accepting new tasks, then runs the ones already spawned until they have all
+    /// completed, or until `deadline` if one is given. Then runs the `on_shutdown` hooks, in
+    /// the order they were registered, within the same deadline. Returns whether the tasks
+    /// and hooks all completed.
+    ///
+    /// Unlike `run`, this doesn't wait for every `Spawner` to be dropped. Without a deadline
+    /// it does wait for every task, even one that never completes. Hooks are polled at least
+    /// once even if the deadline has already passed, so cleanup that doesn't need to wait
+    /// for anything still happens.
+    ///
+    /// Tasks still not completed at the deadline are printed to stderr, along with where
+    /// they were spawned, before the hooks run; see also `leaked_tasks`.
+    pub fn shutdown(&self, deadline: Option<Instant>) -> bool {
+        self.state.shut_down.store(true, Ordering::SeqCst);
+        // Waiting `spawn_async` calls can give up now.
+        self.state.capacity_waiters.lock().unwrap().notify_all();
+
+        let _enter = context::enter();
+        let mut finished = true;
+        while self.state.live_tasks.load(Ordering::SeqCst) > 0 {
+            let task = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match self.ready_queue.recv_timeout(timeout) {
+                        Ok(task) => task,
+                        Err(RecvTimeoutError::Timeout) => {
+                            finished = false;
+                            break;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match self.ready_queue.recv() {
+                    Ok(task) => task,
+                    Err(_) => break,
+                },
+            };
+            task.poll();
+        }
+
+        self.state.report_leaks();
+
+        let hooks = std::mem::take(&mut *self.shutdown_hooks.lock().unwrap());
+        for hook in hooks {
+            finished &= poll_until(hook(), deadline);
+        }
+        finished
+    }
+
+    /// Shuts down like `shutdown`, polling for at most `timeout`, and returns the tasks
+    /// which were still pending when it stopped; none if everything finished in time.
+    pub fn shutdown_timeout(&self, timeout: Duration) -> Vec<LeakedTask> {
+        self.shutdown(Some(Instant::now() + timeout));
+        self.leaked_tasks()
+    }
+
+    /// Takes a snapshot of the executor's counters.
+    pub fn metrics(&self) -> ExecutorMetrics {
+        self.state.metrics()
+    }
+
+    /// Tasks which haven't completed yet; after `shutdown`, the ones it gave up on.
+    pub fn leaked_tasks(&self) -> Vec<LeakedTask> {
+        self.state.leaked_tasks()
+    }
+
+    /// How many tasks have been spawned and not yet completed.
+    pub fn task_count(&self) -> usize {
+        self.state.live_tasks.load(Ordering::SeqCst)
+    }
+
+    /// How many tasks are queued or being polled, rather than waiting to be woken.
+    pub fn active_tasks(&self) -> usize {
+        self.tasks()
+            .filter(|task| task.state != TaskState::Idle)
+            .count()
+    }
+
+    /// A snapshot of the live tasks, in spawn order, for tests or a console to see what the
+    /// executor is doing. Tasks can change state, or complete, as soon as it is taken.
+    pub fn tasks(&self) -> impl Iterator<Item = TaskInfo> {
+        self.state.tasks().into_iter()
+    }
+
+    /// A readable list of the pending tasks, with where each was spawned, how often it has
+    /// been polled and how long ago it last was, for working out why a program hangs.
+    pub fn dump(&self) -> String {
+        self.state.dump()
+    }
+
+    /// Registers cleanup for `shutdown` to run once the spawned tasks are done, such as
+    /// flushing a log writer or stopping a timer thread. Spawning is closed by then, so
+    /// a hook has to do its work itself.
+    pub fn on_shutdown<F, Fut>(&self, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.shutdown_hooks
+            .lock()
+            .unwrap()
+            .push(Box::new(move || hook().boxed()));
+    }
+}
+
+/// Wakes the thread waiting in `poll_until`.
+struct ThreadWaker(thread::Thread);
+
+impl ArcWake for ThreadWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.unpark();
+    }
+}
+
+/// Polls `future` on the current thread until it completes, or until `deadline` if one is
+/// given. Returns whether it completed.
+fn poll_until(mut future: BoxFuture<'static, ()>, deadline: Option<Instant>) -> bool {
+    let waker = waker(Arc::new(ThreadWaker(thread::current())));
+    let context = &mut Context::from_waker(&waker);
+    loop {
+        if future.as_mut().poll(context).is_ready() {
+            return true;
+        }
+        // Parking can wake up spuriously, which just means an extra poll.
+        match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return false;
+                }
+                thread::park_timeout(deadline - now);
+            }
+            None => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{local_executor::LocalSet, runtime::Runtime, waker::CountingWaker};
+    use std::collections::VecDeque;
+
+    #[test]
+    fn sync_handle_waits_for_the_task() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let sync_handle = SyncHandle { spawner };
+        let caller = thread::spawn(move || sync_handle.block_on(async { 1 + 1 }));
+
+        executor.run();
+        assert_eq!(caller.join().unwrap(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "would deadlock")]
+    fn sync_handle_panics_on_the_executor_thread() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let sync_handle = SyncHandle {
+            spawner: spawner.clone(),
+        };
+        spawner.spawn(async move {
+            sync_handle.block_on(async {});
+        });
+        drop(spawner);
+
+        executor.run();
+    }
+
+    #[test]
+    fn join_handle_resolves_to_the_task_output() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (result_sender, result_receiver) = mpsc::channel();
+        let inner_spawner = spawner.clone();
+        spawner.spawn(async move {
+            let handle = inner_spawner.spawn(async {
+                TimerFuture::new(Duration::from_millis(10)).await;
+                "finished"
+            });
+            result_sender.send(handle.await).unwrap();
+        });
+        drop(spawner);
+
+        executor.run();
+        assert_eq!(result_receiver.recv().unwrap(), "finished");
+    }
+
+    #[test]
+    #[should_panic(expected = "dropped before it completed")]
+    fn join_handle_panics_if_the_task_is_dropped() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let handle = spawner.spawn(futures::future::pending::<()>());
+        // Dropping the executor with the task still queued drops its future.
+        drop(executor);
+        drop(spawner);
+
+        futures::executor::block_on(handle);
+    }
+
+    #[test]
+    fn block_on_returns_once_the_root_future_completes() {
+        let (executor, spawner) = new_executor_and_spawner();
+        // Never finishes, so `run` would never return.
+        spawner.spawn(futures::future::pending::<()>());
+        let inner_spawner = spawner.clone();
+
+        let answer = executor.block_on(async move {
+            inner_spawner.spawn(async { 6 * 7 }).await
+        });
+        assert_eq!(answer, 42);
+    }
+
+    #[test]
+    fn block_on_without_spawners_runs_the_future() {
+        let (executor, spawner) = new_executor_and_spawner();
+        drop(spawner);
+
+        assert_eq!(executor.block_on(async { "done" }), "done");
+    }
+
+    #[test]
+    fn shutdown_finishes_spawned_tasks_and_rejects_new_ones() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (done_sender, done_receiver) = mpsc::channel();
+        spawner.spawn(async move {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            done_sender.send(()).unwrap();
+        });
+
+        // `spawner` is still alive, which would keep `run` going forever.
+        assert!(executor.shutdown(None));
+        assert!(done_receiver.try_recv().is_ok());
+        assert!(spawner.spawn_obj(FutureObj::new(Box::new(async {}))).is_err());
+    }
+
+    #[test]
+    fn shutdown_gives_up_at_the_deadline() {
+        let (executor, spawner) = new_executor_and_spawner();
+        spawner.spawn(async {});
+        let spawned_on = line!() + 1;
+        spawner.spawn(TimerFuture::new(Duration::from_secs(3600)));
+
+        assert!(!executor.shutdown(Some(Instant::now() + Duration::from_millis(10))));
+        // The task which completed isn't reported, and the one which didn't is traced back
+        // to this test.
+        let leaked = executor.leaked_tasks();
+        assert_eq!(leaked.len(), 1, "{:?}", leaked);
+        assert_eq!(leaked[0].spawned_at.file(), file!());
+        assert_eq!(leaked[0].spawned_at.line(), spawned_on);
+    }
+
+    #[test]
+    fn shutdown_timeout_returns_the_tasks_still_pending() {
+        let (executor, spawner) = new_executor_and_spawner();
+        spawner.spawn(TimerFuture::new(Duration::from_millis(10)));
+        spawner.spawn(TimerFuture::new(Duration::from_secs(3600)));
+        let slow_id = executor.leaked_tasks()[1].id;
+
+        let started = Instant::now();
+        let pending = executor.shutdown_timeout(Duration::from_millis(100));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(pending.iter().map(|task| task.id).collect::<Vec<_>>(), [slow_id]);
+        assert_eq!(executor.metrics().tasks_completed, 1);
+
+        // Nothing is left pending once everything has finished.
+        let (executor, spawner) = new_executor_and_spawner();
+        spawner.spawn(async {});
+        assert!(executor.shutdown_timeout(Duration::from_millis(100)).is_empty());
+    }
+
+    #[test]
+    fn shutdown_runs_hooks_in_order_after_the_tasks() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (event_sender, event_receiver) = mpsc::channel();
+        let task_events = event_sender.clone();
+        spawner.spawn(async move {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            task_events.send("task").unwrap();
+        });
+        for hook in ["first hook", "second hook"] {
+            let hook_events = event_sender.clone();
+            executor.on_shutdown(move || async move {
+                TimerFuture::new(Duration::from_millis(10)).await;
+                hook_events.send(hook).unwrap();
+            });
+        }
+        drop(event_sender);
+
+        assert!(executor.shutdown(None));
+        let events: Vec<_> = event_receiver.iter().collect();
+        assert_eq!(events, ["task", "first hook", "second hook"]);
+    }
+
+    #[test]
+    fn shutdown_hooks_share_the_deadline() {
+        let (executor, _spawner) = new_executor_and_spawner();
+        let (event_sender, event_receiver) = mpsc::channel();
+        executor.on_shutdown(futures::future::pending);
+        executor.on_shutdown(move || async move {
+            // Still polled once after the deadline.
+            event_sender.send("late hook").unwrap();
+        });
+
+        assert!(!executor.shutdown(Some(Instant::now() + Duration::from_millis(10))));
+        assert_eq!(event_receiver.try_recv(), Ok("late hook"));
+    }
+
+    #[test]
+    fn try_spawn_fails_while_the_queue_is_full() {
+        let (executor, spawner) = Executor::builder().queue_capacity(2).build();
+        assert!(spawner.try_spawn(async {}).is_ok());
+        assert!(spawner.try_spawn(async {}).is_ok());
+        assert_eq!(spawner.try_spawn(async {}).err(), Some(TrySpawnError::Full));
+
+        // Shutting down empties the queue, but closes spawning.
+        assert!(executor.shutdown(None));
+        assert_eq!(spawner.try_spawn(async {}).err(), Some(TrySpawnError::ShutDown));
+        let metrics = executor.metrics();
+        assert_eq!((metrics.tasks_spawned, metrics.tasks_completed), (2, 2));
+    }
+
+    #[test]
+    fn spawn_async_waits_for_room_in_the_queue() {
+        let (executor, spawner) = Executor::builder().queue_capacity(1).build();
+        let task_spawner = spawner.clone();
+        let total = executor.block_on(async move {
+            // The channel only has room for one task at a time, so all but the first
+            // spawn wait for the executor to take the previous task off the queue.
+            let mut handles = Vec::new();
+            for n in 1..=5 {
+                handles.push(task_spawner.spawn_async(async move { n }).await.unwrap());
+            }
+            let mut total = 0;
+            for handle in handles {
+                total += handle.await;
+            }
+            total
+        });
+        assert_eq!(total, 15);
+    }
+
+    #[test]
+    fn each_freed_slot_wakes_one_waiting_spawn_async() {
+        let (executor, spawner) = Executor::builder().queue_capacity(1).build();
+        spawner.spawn(async {});
+        let counters = [CountingWaker::new(), CountingWaker::new()];
+        let mut waiting = [spawner.spawn_async(async {}), spawner.spawn_async(async {})];
+        for (spawn, counter) in waiting.iter_mut().zip(&counters) {
+            let waker = counter.waker();
+            assert!(Pin::new(spawn).poll(&mut Context::from_waker(&waker)).is_pending());
+        }
+        let wakes = || counters.iter().map(CountingWaker::wake_count).collect::<Vec<_>>();
+
+        // One task leaves the channel, making room for one of them.
+        assert!(executor.try_run_one());
+        assert_eq!(wakes(), [1, 0]);
+
+        // The first gives up without taking the slot, so the second gets it instead.
+        let [first, mut second] = waiting;
+        drop(first);
+        assert_eq!(wakes(), [1, 1]);
+        let waker = counters[1].waker();
+        let spawned = Pin::new(&mut second).poll(&mut Context::from_waker(&waker));
+        assert!(matches!(spawned, Poll::Ready(Ok(_))));
+    }
+
+    #[test]
+    fn wakes_and_spawns_on_the_executor_thread_never_wait_for_room() {
+        let (executor, spawner) = Executor::builder().queue_capacity(1).build();
+        let (wake_sender, wake_receiver) = oneshot::channel();
+        let waiting = spawner.spawn(wake_receiver);
+        assert_eq!(executor.run_until_idle(), 1);
+
+        let task_spawner = spawner.clone();
+        let total = executor.block_on(async move {
+            let filler = task_spawner.try_spawn(async { 1 }).unwrap();
+            assert_eq!(task_spawner.try_spawn(async {}).err(), Some(TrySpawnError::Full));
+            // Only this thread takes tasks off the full channel, so neither of these can wait
+            // for room there.
+            wake_sender.send(2).unwrap();
+            let spawned = task_spawner.spawn(async { 3 });
+            filler.await + waiting.await.unwrap() + spawned.await
+        });
+        assert_eq!(total, 6);
+    }
+
+    #[test]
+    fn block_on_resumes_the_root_panic_with_the_drop_task_policy() {
+        let (executor, _spawner) =
+            Executor::builder().panic_policy(PanicPolicy::DropTask).build();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            executor.block_on(async { panic!("root panicked on purpose") })
+        }));
+        let payload = result.expect_err("block_on should panic with its root");
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"root panicked on purpose"));
+    }
+
+    #[test]
+    fn drop_task_policy_keeps_running_after_a_panic() {
+        let (executor, spawner) =
+            Executor::builder().panic_policy(PanicPolicy::DropTask).build();
+        let panicked = spawner.spawn(async { panic!("task panicked on purpose") });
+        let survivor = spawner.spawn(async { 7 });
+
+        assert_eq!(executor.block_on(survivor), 7);
+        let awaited = thread::spawn(move || futures::executor::block_on(panicked)).join();
+        assert!(awaited.is_err(), "the panicked task's handle should panic");
+        assert!(executor.shutdown(None));
+    }
+
+    #[test]
+    fn task_group_join_waits_for_every_child() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let finished = Arc::new(AtomicUsize::new(0));
+        let group_spawner = spawner.clone();
+        let children_finished = finished.clone();
+        let seen = executor.block_on(async move {
+            let mut group = TaskGroup::new(&group_spawner);
+            for millis in [30, 10, 20] {
+                let finished = children_finished.clone();
+                group.spawn(async move {
+                    TimerFuture::new(Duration::from_millis(millis)).await;
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            group.join().await;
+            children_finished.load(Ordering::SeqCst)
+        });
+        assert_eq!(seen, 3);
+    }
+
+    #[test]
+    fn dropping_a_task_group_cancels_its_children() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let finished = Arc::new(AtomicBool::new(false));
+        let mut group = TaskGroup::new(&spawner);
+        let child_finished = finished.clone();
+        group.spawn(async move {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            child_finished.store(true, Ordering::SeqCst);
+        });
+        drop(group);
+        drop(spawner);
+
+        executor.run();
+        assert!(!finished.load(Ordering::SeqCst));
+        assert_eq!(executor.metrics().tasks_completed, 1);
+    }
+
+    #[test]
+    fn metrics_count_slow_polls() {
+        let (executor, spawner) = Executor::builder()
+            .slow_poll_threshold(Duration::from_millis(20))
+            .build();
+        spawner.spawn(async {});
+        // Blocking the executor thread is exactly what the warning is for.
+        spawner.spawn(async { thread::sleep(Duration::from_millis(30)) });
+        drop(spawner);
+
+        executor.run();
+        assert_eq!(executor.metrics().slow_polls, 1);
+    }
+
+    #[test]
+    fn metrics_count_spawns_completions_and_polls() {
+        let (executor, spawner) = new_executor_and_spawner();
+        for _ in 0..2 {
+            spawner.spawn(async {
+                TimerFuture::new(Duration::from_millis(10)).await;
+            });
+        }
+        let queued = executor.metrics();
+        assert_eq!(queued.tasks_spawned, 2);
+        assert_eq!(queued.queue_depth, 2);
+        assert_eq!(queued.polls, 0);
+        drop(spawner);
+
+        executor.run();
+        let finished = executor.metrics();
+        assert_eq!(finished.tasks_completed, 2);
+        assert_eq!(finished.queue_depth, 0);
+        // Each task is polled once to start its timer, and again once it fires.
+        assert!(finished.polls >= 4, "{:?}", finished);
+        assert!(finished.timers.fired >= 2);
+        assert!(finished.timers.max_lateness >= finished.timers.mean_lateness);
+    }
+
+    #[test]
+    fn stepping_runs_one_ready_task_at_a_time() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for name in ["a", "b"] {
+            let log = log.clone();
+            spawner.spawn(async move {
+                log.lock().unwrap().push(format!("{} started", name));
+                coop::yield_now().await;
+                log.lock().unwrap().push(format!("{} finished", name));
+            });
+        }
+        let log_in_timer_task = log.clone();
+        spawner.spawn(async move {
+            TimerFuture::new(Duration::from_secs(60)).await;
+            log_in_timer_task.lock().unwrap().push("timer fired".to_owned());
+        });
+
+        assert!(executor.try_run_one());
+        assert_eq!(*log.lock().unwrap(), ["a started"]);
+        // "b", the timer task's first poll, then "a" and "b" again after they yielded.
+        assert_eq!(executor.run_until_idle(), 4);
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["a started", "b started", "a finished", "b finished"]
+        );
+        // Only the task waiting on the timer is left, and it isn't ready.
+        assert!(!executor.try_run_one());
+        assert_eq!(executor.metrics().tasks_completed, 2);
+    }
+
+    #[test]
+    fn a_task_nothing_can_wake_is_reported_as_lost() {
+        let (executor, spawner) = new_executor_and_spawner();
+        // Pending forever, without keeping a waker.
+        let spawned_on = line!() + 1;
+        spawner.spawn(futures::future::pending::<()>());
+        // Pending until its timer fires, which keeps a waker.
+        spawner.spawn(TimerFuture::new(Duration::from_secs(3600)));
+        executor.run_until_idle();
+
+        let metrics = executor.metrics();
+        assert_eq!(metrics.lost_tasks, 1);
+        assert_eq!(metrics.tasks_completed, 0);
+        // Only the timer task is still live, so only it holds up `shutdown`.
+        let leaked = executor.leaked_tasks();
+        assert_eq!(leaked.len(), 1, "{:?}", leaked);
+        assert_ne!(leaked[0].spawned_at.line(), spawned_on);
+    }
+
+    #[test]
+    fn tasks_outliving_their_max_lifetime_are_cancelled() {
+        let (executor, spawner) = Executor::builder()
+            .max_task_lifetime(Duration::from_millis(50))
+            .build();
+        let stuck = spawner.spawn(TimerFuture::new(Duration::from_secs(3600)));
+        let quick = spawner.spawn(async { 7 });
+        let long_lived = spawner.spawn_long_lived(async {
+            TimerFuture::new(Duration::from_millis(100)).await;
+            "done"
+        });
+        drop(spawner);
+        executor.run();
+
+        assert_eq!(executor.metrics().expired_tasks, 1);
+        assert_eq!(futures::executor::block_on(quick), 7);
+        assert_eq!(futures::executor::block_on(long_lived), "done");
+        let stuck = thread::spawn(move || futures::executor::block_on(stuck)).join();
+        assert!(stuck.is_err());
+    }
+
+    #[test]
+    fn block_on_runs_its_future_past_the_max_lifetime() {
+        let (executor, spawner) = Executor::builder()
+            .max_task_lifetime(Duration::from_millis(20))
+            .build();
+        let output = executor.block_on(async {
+            TimerFuture::new(Duration::from_millis(60)).await;
+            "done"
+        });
+        assert_eq!(output, "done");
+
+        // The same goes for a future run through a `SyncHandle`.
+        let sync_handle = SyncHandle::new(spawner);
+        let caller = thread::spawn(move || {
+            sync_handle.block_on(async {
+                TimerFuture::new(Duration::from_millis(60)).await;
+                "done"
+            })
+        });
+        executor.run();
+        assert_eq!(caller.join().unwrap(), "done");
+        assert_eq!(executor.metrics().expired_tasks, 0);
+    }
+
+    #[test]
+    fn handle_current_spawns_onto_the_running_executor() {
+        fn spawn_deep_inside(value: u32) -> JoinHandle<u32> {
+            Handle::current().spawn(async move { value * 2 })
+        }
+
+        assert!(Handle::try_current().is_none());
+        let (executor, spawner) = new_executor_and_spawner();
+        let doubled = executor.block_on(async { spawn_deep_inside(21).await });
+        assert_eq!(doubled, 42);
+        assert_eq!(executor.metrics().tasks_spawned, 2);
+        // Only while a task is being polled.
+        assert!(Handle::try_current().is_none());
+
+        drop(spawner);
+
+        // Tasks on a worker pool get the pool's handle.
+        let runtime = Runtime::builder().worker_threads(2).build();
+        assert_eq!(runtime.block_on(async { spawn_deep_inside(4).await }), 8);
+        assert!(runtime.shutdown(None));
+    }
+
+    #[test]
+    fn tasks_lists_each_live_task_and_its_state() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (wake_sender, wake_receiver) = oneshot::channel::<()>();
+        spawner.spawn(async {});
+        let waiting_on = line!() + 1;
+        spawner.spawn(wake_receiver);
+        spawner.spawn(async {});
+        assert_eq!(executor.task_count(), 3);
+        assert_eq!(executor.active_tasks(), 3);
+
+        // Runs the first task to completion, and leaves the second waiting.
+        executor.try_run_one();
+        executor.try_run_one();
+        let states: Vec<_> = executor.tasks().map(|task| task.state).collect();
+        assert_eq!(states, [TaskState::Idle, TaskState::Scheduled]);
+        let waiting = executor.tasks().next().unwrap();
+        assert_eq!(waiting.spawned_at.line(), waiting_on);
+        assert_eq!(executor.task_count(), 2);
+        assert_eq!(executor.active_tasks(), 1);
+
+        wake_sender.send(()).unwrap();
+        executor.run_until_idle();
+        assert_eq!(executor.task_count(), 0);
+        assert_eq!(executor.tasks().count(), 0);
+    }
+
+    /// What the fairness harness saw, in order.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Seen {
+        Woken(usize),
+        Polled(usize),
+        /// A poll returned `Pending`.
+        Parked(usize),
+        /// A poll returned `Ready`.
+        Finished(usize),
+    }
+
+    /// Logs each wake before passing it on to the task's own waker.
+    struct LoggingWaker {
+        task: usize,
+        inner: std::task::Waker,
+        log: Arc<Mutex<Vec<Seen>>>,
+    }
+
+    impl ArcWake for LoggingWaker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.log.lock().unwrap().push(Seen::Woken(arc_self.task));
+            arc_self.inner.wake_by_ref();
+        }
+    }
+
+    /// Replays `log` against a first in, first out run queue, and panics at the first poll of
+    /// a task which wasn't at the front of it. As in `Executor`, waking a queued task leaves
+    /// it where it is, and a task woken while it is being polled is queued when the poll ends.
+    fn assert_polled_in_wake_order(log: &[Seen]) {
+        let mut queue = VecDeque::new();
+        let mut polling = None;
+        let mut woken_while_polled = false;
+        for (at, &seen) in log.iter().enumerate() {
+            match seen {
+                Seen::Woken(task) if polling == Some(task) => woken_while_polled = true,
+                Seen::Woken(task) => {
+                    if !queue.contains(&task) {
+                        queue.push_back(task);
+                    }
+                }
+                Seen::Polled(task) => {
+                    assert_eq!(
+                        queue.front(),
+                        Some(&task),
+                        "event {}: task {} was polled before tasks woken earlier: {:?}",
+                        at,
+                        task,
+                        queue
+                    );
+                    queue.pop_front();
+                    polling = Some(task);
+                    woken_while_polled = false;
+                }
+                Seen::Parked(task) => {
+                    polling = None;
+                    if woken_while_polled {
+                        queue.push_back(task);
+                    }
+                }
+                Seen::Finished(_) => polling = None,
+            }
+        }
+    }
+
+    #[test]
+    fn tasks_are_polled_in_the_order_they_were_woken() {
+        const TASKS: usize = 50;
+        const STEPS: usize = 20;
+
+        /// Parked tasks' wakers, and how many tasks are neither parked nor finished.
+        struct Board {
+            parked: Vec<Option<std::task::Waker>>,
+            active: usize,
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let board = Arc::new(Mutex::new(Board {
+            parked: vec![None; TASKS],
+            active: TASKS,
+        }));
+        let (executor, spawner) = new_executor_and_spawner();
+        for id in 0..TASKS {
+            let (log, board) = (log.clone(), board.clone());
+            let mut step = 0;
+            log.lock().unwrap().push(Seen::Woken(id));
+            spawner.spawn(futures::future::poll_fn(move |cx| {
+                log.lock().unwrap().push(Seen::Polled(id));
+                let waker = waker(Arc::new(LoggingWaker {
+                    task: id,
+                    inner: cx.waker().clone(),
+                    log: log.clone(),
+                }));
+                let mut board = board.lock().unwrap();
+                if step == STEPS {
+                    // Nobody else may be left to wake the parked tasks.
+                    board.active -= 1;
+                    let parked: Vec<_> = board.parked.iter_mut().filter_map(Option::take).collect();
+                    board.active += parked.len();
+                    parked.into_iter().for_each(std::task::Waker::wake);
+                    log.lock().unwrap().push(Seen::Finished(id));
+                    return Poll::Ready(());
+                }
+
+                // Interleave the tasks: each step wakes some other task if it is parked,
+                // then either parks or yields.
+                if let Some(other) = board.parked[(id + step + 1) % TASKS].take() {
+                    other.wake();
+                    board.active += 1;
+                }
+                step += 1;
+                if (id + step) % 3 == 0 && board.active > 1 {
+                    board.parked[id] = Some(waker);
+                    board.active -= 1;
+                } else {
+                    waker.wake_by_ref();
+                }
+                log.lock().unwrap().push(Seen::Parked(id));
+                Poll::Pending
+            }));
+        }
+        drop(spawner);
+        executor.run();
+
+        let log = log.lock().unwrap();
+        let finished = log.iter().filter(|seen| matches!(seen, Seen::Finished(_)));
+        assert_eq!(finished.count(), TASKS);
+        assert_polled_in_wake_order(&log);
+
+        // The check itself catches a task jumping the queue.
+        let unfair = [Seen::Woken(0), Seen::Woken(1), Seen::Polled(1)];
+        assert!(panic::catch_unwind(|| assert_polled_in_wake_order(&unfair)).is_err());
+    }
+
+    #[test]
+    fn local_set_tasks_run_alongside_send_tasks() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (send_sender, send_receiver) = oneshot::channel();
+        spawner.spawn(async move {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            send_sender.send("from a Send task").unwrap();
+        });
+
+        let local = LocalSet::new();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let task_log = log.clone();
+        local.spawner().spawn(async move {
+            // Holding the `Rc` across an `.await` makes this task `!Send`.
+            TimerFuture::new(Duration::from_millis(10)).await;
+            task_log.borrow_mut().push("from a local task");
+        });
+        let root_log = log.clone();
+        executor.block_on_local(local.run_until(async move {
+            let message = send_receiver.await.unwrap();
+            root_log.borrow_mut().push(message);
+            TimerFuture::new(Duration::from_millis(30)).await;
+        }));
+
+        let mut log = log.borrow().clone();
+        log.sort();
+        assert_eq!(log, ["from a Send task", "from a local task"]);
+        assert!(LOCAL_ROOT.with(|slot| slot.borrow().is_none()));
+        // The set outliving `run_until` doesn't keep the executor's channel open.
+        drop(spawner);
+        executor.run();
+        drop(local);
+    }
+
+    #[test]
+    fn tasks_count_their_polls_for_the_dump() {
+        let (executor, spawner) = new_executor_and_spawner();
+        spawner.spawn(async {
+            coop::yield_now().await;
+            coop::yield_now().await;
+            futures::future::pending::<()>().await;
+        });
+        let (_never_sent, never_received) = oneshot::channel::<()>();
+        let spawned_on = line!() + 1;
+        spawner.spawn(never_received);
+
+        assert_eq!(executor.tasks().next().unwrap().last_polled, None);
+        let before = Instant::now();
+        executor.run_until_idle();
+        let polls: Vec<_> = executor.tasks().map(|task| task.polls).collect();
+        assert_eq!(polls, [1]);
+        let waiting = executor.tasks().next().unwrap();
+        assert!(waiting.last_polled.unwrap() >= before);
+
+        let dump = executor.dump();
+        assert!(dump.starts_with("1 pending tasks\n"), "{}", dump);
+        let location = format!("{}:{}:", file!(), spawned_on);
+        assert!(dump.contains(&location), "{}", dump);
+        assert!(dump.contains("Idle, 1 polls taking "), "{}", dump);
+    }
+
+    #[test]
+    fn wakers_held_after_a_task_completes_are_counted() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let held: Arc<Mutex<Vec<std::task::Waker>>> = Arc::new(Mutex::new(Vec::new()));
+        for keep_waker in [true, false] {
+            let held = held.clone();
+            let mut woken = false;
+            spawner.spawn(futures::future::poll_fn(move |cx| {
+                if woken {
+                    return Poll::Ready(());
+                }
+                woken = true;
+                let waker = cx.waker().clone();
+                if keep_waker {
+                    held.lock().unwrap().push(waker.clone());
+                }
+                // Consumes the clone, which stops counting it.
+                waker.wake();
+                Poll::Pending
+            }));
+        }
+        executor.run_until_idle();
+
+        assert_eq!(executor.metrics().tasks_completed, 2);
+        assert_eq!(executor.metrics().stale_wakers, 1);
+        // Wakers for a completed task are harmless to use, just pointless.
+        held.lock().unwrap().drain(..).for_each(std::task::Waker::wake);
+        assert_eq!(executor.run_until_idle(), 0);
+    }
+
+    #[test]
+    fn tasks_record_their_busy_time_and_longest_poll() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (_never_sent, never_received) = oneshot::channel::<()>();
+        spawner.spawn(async {});
+        spawner.spawn(async move {
+            // Blocking the thread is what makes this task the one to find.
+            thread::sleep(Duration::from_millis(20));
+            coop::yield_now().await;
+            thread::sleep(Duration::from_millis(5));
+            never_received.await.unwrap();
+        });
+        executor.run_until_idle();
+
+        let busiest = executor.tasks().max_by_key(|task| task.busy).unwrap();
+        assert_eq!(busiest.polls, 2);
+        assert!(busiest.longest_poll >= Duration::from_millis(20));
+        assert!(busiest.busy >= busiest.longest_poll + Duration::from_millis(5));
+    }
+
+    #[test]
+    fn waking_a_task_many_times_queues_it_once() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let polls = Arc::new(AtomicUsize::new(0));
+        let task_polls = polls.clone();
+        spawner.spawn(futures::future::poll_fn(move |cx| {
+            // Woken three times during the first poll, and never again.
+            if task_polls.fetch_add(1, Ordering::SeqCst) == 0 {
+                for _ in 0..3 {
+                    cx.waker().wake_by_ref();
+                }
+            }
+            Poll::<()>::Pending
+        }));
+
+        executor.block_on(TimerFuture::new(Duration::from_millis(20)));
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+        assert_eq!(executor.metrics().queue_depth, 0);
+    }
+
+    #[test]
+    fn a_task_gets_an_equivalent_waker_on_every_poll() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let stored: Arc<Mutex<Option<std::task::Waker>>> = Arc::new(Mutex::new(None));
+        let task_stored = stored.clone();
+        let polls = Arc::new(AtomicUsize::new(0));
+        let task_polls = polls.clone();
+        spawner.spawn(futures::future::poll_fn(move |cx| {
+            let mut stored = task_stored.lock().unwrap();
+            if let Some(previous) = &*stored {
+                assert!(previous.will_wake(cx.waker()));
+            }
+            *stored = Some(cx.waker().clone());
+            if task_polls.fetch_add(1, Ordering::SeqCst) < 3 {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            } else {
+                // The stored waker keeps the task, and so the task channel, alive.
+                *stored = None;
+                Poll::Ready(())
+            }
+        }));
+        drop(spawner);
+
+        executor.run();
+        assert_eq!(polls.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,310 @@
+// `executor::Executor` polls tasks in the order they were woken. On a server that means a
+// burst of background jobs, each of which wakes up ready to do more work, can fill the queue
+// and make every request wait behind it.
+//
+// `FairExecutor` sorts tasks into groups (say "accept", "requests" and "background") and gives
+// each group a weight. It keeps a queue per group and measures how long each poll takes, and
+// always polls next from the group which has had the least CPU time for its weight. A group
+// with weight 3 gets three times the CPU time of a group with weight 1 while both have work,
+// and an idle group's share is split between the others.
+//
+// This is weighted fair queuing with each group's "virtual time" being its CPU time divided by
+// its weight. A group that was idle rejoins at the current virtual time rather than its own,
+// so it can't save up its share while idle and then starve everyone else.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Wake, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures::future::{BoxFuture, FutureExt};
+
+use crate::{context, coop};
+
+/// Runs tasks from several weighted groups on the current thread, sharing CPU time between
+/// the groups in proportion to their weights.
+pub struct FairExecutor {
+    shared: Arc<Shared>,
+}
+
+/// Spawns tasks into one group of a `FairExecutor`.
+#[derive(Clone)]
+pub struct FairSpawner {
+    shared: Arc<Shared>,
+    group: usize,
+}
+
+/// CPU time used by one group, from `FairExecutor::group_metrics`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupMetrics {
+    pub name: String,
+    pub weight: u32,
+    /// Total time spent polling the group's tasks.
+    pub cpu_time: Duration,
+    /// `cpu_time` as a fraction of the time spent polling every group's tasks.
+    pub share: f64,
+}
+
+struct Shared {
+    groups: Mutex<Groups>,
+    /// Spawned tasks which haven't completed yet.
+    live_tasks: AtomicUsize,
+    /// The executor's thread, unparked whenever a task is woken.
+    thread: thread::Thread,
+}
+
+struct Groups {
+    groups: Vec<Group>,
+    /// The virtual time of the group polled most recently.
+    virtual_time: f64,
+}
+
+struct Group {
+    name: String,
+    weight: u32,
+    ready: VecDeque<Arc<Task>>,
+    cpu_time: Duration,
+    /// `cpu_time` in seconds divided by `weight`.
+    virtual_time: f64,
+}
+
+struct Task {
+    group: usize,
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    /// Whether the task is in its group's ready queue, so waking twice doesn't queue it twice.
+    queued: AtomicBool,
+    shared: Arc<Shared>,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let mut groups = self.shared.groups.lock().unwrap();
+        let now = groups.virtual_time;
+        let group = &mut groups.groups[self.group];
+        if group.ready.is_empty() {
+            // Rejoining after being idle: no credit for the time spent away.
+            group.virtual_time = group.virtual_time.max(now);
+        }
+        group.ready.push_back(self.clone());
+        drop(groups);
+        self.shared.thread.unpark();
+    }
+}
+
+impl Default for FairExecutor {
+    fn default() -> Self {
+        FairExecutor::new()
+    }
+}
+
+impl FairExecutor {
+    /// Create an executor, with no groups yet, which runs its tasks on the current thread.
+    pub fn new() -> Self {
+        FairExecutor {
+            shared: Arc::new(Shared {
+                groups: Mutex::new(Groups {
+                    groups: Vec::new(),
+                    virtual_time: 0.0,
+                }),
+                live_tasks: AtomicUsize::new(0),
+                thread: thread::current(),
+            }),
+        }
+    }
+
+    /// Add a group whose tasks get `weight` shares of CPU time, and return a spawner for it.
+    pub fn add_group(&self, name: impl Into<String>, weight: u32) -> FairSpawner {
+        assert!(weight > 0, "a group needs a weight of at least 1");
+        let mut groups = self.shared.groups.lock().unwrap();
+        let virtual_time = groups.virtual_time;
+        groups.groups.push(Group {
+            name: name.into(),
+            weight,
+            ready: VecDeque::new(),
+            cpu_time: Duration::ZERO,
+            virtual_time,
+        });
+        FairSpawner {
+            shared: self.shared.clone(),
+            group: groups.groups.len() - 1,
+        }
+    }
+
+    /// Run until every spawned task has completed, parking whenever none is ready.
+    pub fn run(&self) {
+        // Let code running inside tasks know it is on the executor thread.
+        let _enter = context::enter();
+        while self.shared.live_tasks.load(Ordering::SeqCst) > 0 {
+            match self.next_task() {
+                Some(task) => self.poll_task(task),
+                None => thread::park(),
+            }
+        }
+    }
+
+    /// CPU time used by each group so far, in the order the groups were added.
+    pub fn group_metrics(&self) -> Vec<GroupMetrics> {
+        let groups = self.shared.groups.lock().unwrap();
+        let total: Duration = groups.groups.iter().map(|group| group.cpu_time).sum();
+        groups
+            .groups
+            .iter()
+            .map(|group| GroupMetrics {
+                name: group.name.clone(),
+                weight: group.weight,
+                cpu_time: group.cpu_time,
+                share: if total.is_zero() {
+                    0.0
+                } else {
+                    group.cpu_time.as_secs_f64() / total.as_secs_f64()
+                },
+            })
+            .collect()
+    }
+
+    /// Takes the next task from the group furthest behind its share.
+    fn next_task(&self) -> Option<Arc<Task>> {
+        let mut groups = self.shared.groups.lock().unwrap();
+        let group = groups
+            .groups
+            .iter_mut()
+            .filter(|group| !group.ready.is_empty())
+            .min_by(|a, b| a.virtual_time.total_cmp(&b.virtual_time))?;
+        let task = group.ready.pop_front();
+        let virtual_time = group.virtual_time;
+        groups.virtual_time = virtual_time;
+        task
+    }
+
+    fn poll_task(&self, task: Arc<Task>) {
+        // Clear the flag before polling, so a wake during the poll queues the task again.
+        task.queued.store(false, Ordering::Release);
+        let mut future_slot = task.future.lock().unwrap();
+        let Some(mut future) = future_slot.take() else {
+            return;
+        };
+        let waker = Waker::from(task.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let started = Instant::now();
+        let poll = coop::budget(|| future.as_mut().poll(&mut cx));
+        let elapsed = started.elapsed();
+
+        let mut groups = self.shared.groups.lock().unwrap();
+        let group = &mut groups.groups[task.group];
+        group.cpu_time += elapsed;
+        group.virtual_time += elapsed.as_secs_f64() / f64::from(group.weight);
+        drop(groups);
+
+        if poll.is_pending() {
+            *future_slot = Some(future);
+        } else {
+            self.shared.live_tasks.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl FairSpawner {
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        let task = Arc::new(Task {
+            group: self.group,
+            future: Mutex::new(Some(future.boxed())),
+            queued: AtomicBool::new(false),
+            shared: self.shared.clone(),
+        });
+        self.shared.live_tasks.fetch_add(1, Ordering::SeqCst);
+        task.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Spins for `cost` per poll, `polls` times, logging `name` each time.
+    async fn busy(name: &'static str, cost: Duration, polls: usize, log: Arc<Mutex<Vec<&str>>>) {
+        for _ in 0..polls {
+            let started = Instant::now();
+            while started.elapsed() < cost {}
+            log.lock().unwrap().push(name);
+            coop::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn groups_share_cpu_time_by_weight() {
+        let executor = FairExecutor::new();
+        let requests = executor.add_group("requests", 3);
+        let background = executor.add_group("background", 1);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let cost = Duration::from_micros(200);
+        // Background work is spawned first, and would go first in a plain FIFO executor.
+        background.spawn(busy("background", cost, 100, log.clone()));
+        requests.spawn(busy("requests", cost, 100, log.clone()));
+
+        executor.run();
+        let log = log.lock().unwrap();
+        // While both groups have work, requests get about three polls in every four.
+        let early_requests = log[..40].iter().filter(|name| **name == "requests").count();
+        assert!((25..=35).contains(&early_requests), "{:?}", &log[..40]);
+
+        let metrics = executor.group_metrics();
+        assert_eq!(metrics[0].name, "requests");
+        assert!(metrics.iter().all(|group| group.cpu_time >= cost * 100));
+        assert!((metrics[0].share + metrics[1].share - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn an_idle_group_does_not_bank_its_share() {
+        let executor = FairExecutor::new();
+        let first = executor.add_group("first", 1);
+        let late = executor.add_group("late", 1);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let cost = Duration::from_micros(200);
+        first.spawn(busy("first", cost, 60, log.clone()));
+
+        // "late" joins after "first" has been running alone for a while.
+        let late_log = log.clone();
+        first.spawn(async move {
+            for _ in 0..20 {
+                coop::yield_now().await;
+            }
+            late.spawn(busy("late", cost, 20, late_log));
+        });
+
+        executor.run();
+        let log = log.lock().unwrap();
+        let joined = log.iter().position(|name| *name == "late").unwrap();
+        // From then on the two alternate, rather than "late" running alone to catch up.
+        let after: Vec<_> = log[joined..joined + 10].to_vec();
+        let late_polls = after.iter().filter(|name| **name == "late").count();
+        assert!((3..=7).contains(&late_polls), "{:?}", after);
+    }
+
+    #[test]
+    fn tasks_run_on_an_executor_thread() {
+        let executor = FairExecutor::new();
+        let group = executor.add_group("only", 1);
+        let in_task = Arc::new(AtomicBool::new(false));
+        let flag = in_task.clone();
+        group.spawn(async move { flag.store(context::in_executor(), Ordering::SeqCst) });
+
+        executor.run();
+        assert!(in_task.load(Ordering::SeqCst));
+        assert!(!context::in_executor());
+    }
+}
@@ -0,0 +1,169 @@
+// Watching a file or directory for changes, as a stream of events, for things like reloading a
+// config file, picking up a rotated TLS certificate, or following a log.
+//
+// Operating systems have their own notification APIs for this (inotify, kqueue, FSEvents),
+// each with its own quirks, and each needing bindings this crate doesn't have. What every
+// platform does have is `metadata`, so `watch` polls: on each tick of a `Timer` it looks at
+// the watched path again and reports the differences since the last look. That costs a
+// `stat` per file per tick, and a change is only seen up to one period late, which is fine
+// for the uses above. A native backend could produce the same `FsEvent`s behind `watch`
+// without its callers changing.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use futures::stream::Stream;
+
+use crate::timer::{Interval, ThreadTimer, Timer};
+
+/// How often `watch` looks for changes.
+pub const DEFAULT_PERIOD: Duration = Duration::from_millis(250);
+
+/// A change seen by `watch`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FsEvent {
+    /// The file appeared since the last look.
+    Created(PathBuf),
+    /// The file's length or modification time changed.
+    Modified(PathBuf),
+    /// The file is gone.
+    Removed(PathBuf),
+}
+
+/// Watch `path` for changes, looking every `DEFAULT_PERIOD`. If `path` is a directory its
+/// entries are watched, though not the ones in its subdirectories; otherwise the file itself
+/// is, and it may not exist yet.
+///
+/// The stream never ends; drop it to stop watching.
+pub fn watch(path: impl Into<PathBuf>) -> Watch<ThreadTimer> {
+    Watch::with_timer(path, DEFAULT_PERIOD, ThreadTimer)
+}
+
+/// Stream returned by `watch`.
+pub struct Watch<T: Timer> {
+    path: PathBuf,
+    ticks: Interval<T>,
+    /// Length and modification time of each file, as of the last look.
+    seen: HashMap<PathBuf, (u64, Option<SystemTime>)>,
+    /// Changes found by the last look which haven't been yielded yet.
+    pending: VecDeque<FsEvent>,
+}
+
+impl<T: Timer> Watch<T> {
+    /// Like `watch`, but looking every `period` according to `timer`.
+    pub fn with_timer(path: impl Into<PathBuf>, period: Duration, timer: T) -> Self {
+        let path = path.into();
+        Watch {
+            seen: snapshot(&path),
+            path,
+            ticks: timer.interval(period),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn look(&mut self) {
+        let now = snapshot(&self.path);
+        let mut changes: Vec<FsEvent> = now
+            .iter()
+            .filter_map(|(path, file)| match self.seen.get(path) {
+                None => Some(FsEvent::Created(path.clone())),
+                Some(before) if before != file => Some(FsEvent::Modified(path.clone())),
+                Some(_) => None,
+            })
+            .collect();
+        changes.extend(
+            self.seen
+                .keys()
+                .filter(|path| !now.contains_key(*path))
+                .map(|path| FsEvent::Removed(path.clone())),
+        );
+        // `HashMap` order is random; sorting by path makes a burst of changes come out the
+        // same way every time.
+        changes.sort_by(|a, b| event_path(a).cmp(event_path(b)));
+        self.pending.extend(changes);
+        self.seen = now;
+    }
+}
+
+fn event_path(event: &FsEvent) -> &Path {
+    match event {
+        FsEvent::Created(path) | FsEvent::Modified(path) | FsEvent::Removed(path) => path,
+    }
+}
+
+/// The files at `path`: its entries if it is a directory, or the file itself. Files which
+/// can't be read, or vanish while we look, are left out, and show up as removed.
+fn snapshot(path: &Path) -> HashMap<PathBuf, (u64, Option<SystemTime>)> {
+    let stat = |path: &Path| {
+        let metadata = fs::metadata(path).ok()?;
+        Some((metadata.len(), metadata.modified().ok()))
+    };
+    match fs::read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let file = stat(&path)?;
+                Some((path, file))
+            })
+            .collect(),
+        Err(_) => stat(path)
+            .map(|file| (path.to_path_buf(), file))
+            .into_iter()
+            .collect(),
+    }
+}
+
+impl<T: Timer> Stream for Watch<T> {
+    type Item = FsEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<FsEvent>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            match Pin::new(&mut self.ticks).poll_next(cx) {
+                Poll::Ready(_) => self.look(),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, stream::StreamExt};
+
+    #[test]
+    fn reports_files_created_modified_and_removed() {
+        let root = std::env::temp_dir().join(format!("fs-watch-test-{}", std::process::id()));
+        let dir = root.join("watched");
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config.toml");
+        let mut events = Watch::with_timer(&dir, Duration::from_millis(10), ThreadTimer);
+        // Written beside the watched directory and renamed into it, so a look can't catch a
+        // file half written.
+        let replace_config = |contents: &str| {
+            let staged = root.join("config.toml");
+            fs::write(&staged, contents).unwrap();
+            fs::rename(&staged, &config).unwrap();
+        };
+
+        replace_config("port = 80\n");
+        assert_eq!(block_on(events.next()), Some(FsEvent::Created(config.clone())));
+        // A different length is a change even if the modification time has too coarse a
+        // resolution to tell.
+        replace_config("port = 8080\n");
+        assert_eq!(block_on(events.next()), Some(FsEvent::Modified(config.clone())));
+        fs::remove_file(&config).unwrap();
+        assert_eq!(block_on(events.next()), Some(FsEvent::Removed(config)));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+// `futures::future::join_all` polls every future at once. That's fine for a handful of futures,
+// but for, say, a thousand downloads we usually want to bound how many run at the same time
+// while still getting the results back in the order the futures were given.
+//
+// `StreamExt::buffered` does exactly that: it keeps at most `n` futures in flight and yields
+// their outputs in the order they were pulled from the underlying stream.
+
+use std::future::Future;
+
+use futures::stream::{self, StreamExt, TryStreamExt};
+
+/// Run `futures` with at most `limit` of them in flight at once, and return their outputs
+/// in input order. A `limit` of 0 is treated as 1.
+pub async fn join_all_ordered<I>(futures: I, limit: usize) -> Vec<<I::Item as Future>::Output>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    stream::iter(futures)
+        .buffered(limit.max(1))
+        .collect()
+        .await
+}
+
+/// Like `join_all_ordered`, but for fallible futures: returns all outputs in input order,
+/// or the first error encountered. Futures which haven't completed when an error is seen
+/// are dropped.
+pub async fn try_join_all_ordered<I, T, E>(futures: I, limit: usize) -> Result<Vec<T>, E>
+where
+    I: IntoIterator,
+    I::Item: Future<Output = Result<T, E>>,
+{
+    stream::iter(futures)
+        .buffered(limit.max(1))
+        .try_collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TimerFuture;
+    use futures::executor::block_on;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[test]
+    fn results_keep_input_order_and_respect_limit() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        // Later futures finish sooner, so completion order is the reverse of input order.
+        let futures = (0..6u64).map(|i| {
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(now, Ordering::SeqCst);
+                TimerFuture::new(Duration::from_millis(30 - i * 5)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                i
+            }
+        });
+
+        let results = block_on(join_all_ordered(futures, 2));
+        assert_eq!(results, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn try_variant_stops_at_first_error() {
+        let futures = (0..4).map(|i| async move {
+            if i == 2 {
+                Err(format!("failed on {}", i))
+            } else {
+                Ok(i)
+            }
+        });
+        assert_eq!(
+            block_on(try_join_all_ordered(futures, 3)),
+            Err(String::from("failed on 2"))
+        );
+
+        let futures = (0..4).map(|i| async move { Ok::<_, String>(i * 10) });
+        assert_eq!(block_on(try_join_all_ordered(futures, 0)), Ok(vec![0, 10, 20, 30]));
+    }
+}
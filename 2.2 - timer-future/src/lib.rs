@@ -1,33 +1,72 @@
 // For the sake of the example, we'll just spin up a new thread when the timer is created,
 // sleep for the required time, and then signal the timer future when the time window has elapsed.
 
+extern crate alloc;
+
+pub mod async_drop;
+pub mod atomic_waker;
+pub mod blocking;
+pub mod channel;
+pub mod context;
+pub mod coop;
+pub mod core_executor;
+#[cfg(feature = "rayon")]
+pub mod cpu;
+pub mod executor;
+pub mod fair_executor;
+pub mod fs;
+pub mod join;
+pub mod local_executor;
+pub mod memory;
+pub mod oneshot;
+pub mod raw_executor;
+#[cfg(all(feature = "polling", unix))]
+pub mod reactor;
+pub mod replay;
+pub mod runtime;
+pub mod scope;
+pub mod static_executor;
+pub mod stream;
+pub mod timer;
+pub mod waker;
+pub mod waker_set;
+pub mod worker_pool;
+pub mod workload;
+
 use std::{
     future::Future,
     pin::Pin,
-    sync::{Arc, Mutex},
-    task::{Context, Poll, Waker},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use atomic_waker::AtomicWaker;
+
 // Let's start by defining the future type itself. Our future needs a way for the thread to
 // communicate that the timer has elapsed and the future should complete.
-// We'll use a shared Arc<Mutex<..>> value to communicate between the thread and the future.
+// We'll share a flag and an `AtomicWaker` between the thread and the future through an Arc.
+// Neither needs a lock: the flag is a single atomic, and `AtomicWaker` takes care of handing
+// the waker from the polling task over to the timer thread.
 
 pub struct TimerFuture {
-    shared_state: Arc<Mutex<SharedState>>,
+    shared_state: Arc<SharedState>,
 }
 
 /// Shared state between the future and the waiting thread
 struct SharedState {
     /// Whether or not the sleep time has elapsed
-    completed: bool,
+    completed: AtomicBool,
 
     /// The waker for the task that `TimerFuture` is running on.
     /// The thread can use this after setting `completed = true` to tell
     /// `TimerFuture`'s task to wake up, see that `completed = true`, and
     /// move forward.
-    waker: Option<Waker>,
+    waker: AtomicWaker,
 }
 
 impl Future for TimerFuture {
@@ -35,25 +74,25 @@ impl Future for TimerFuture {
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         // Look at the shared state to see if the timer has already completed.
-        let mut shared_state = self.shared_state.lock().unwrap();
+        if self.shared_state.completed.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+
+        // Set waker so that the thread can wake up the current task
+        // when the timer has completed, ensuring that the future is polled
+        // again and sees that `completed = true`.
+        //
+        // It's tempting to do this once rather than on every poll. However, the
+        // `TimerFuture` can move between tasks on the executor, which could cause a
+        // stale waker pointing to the wrong task, preventing `TimerFuture` from waking
+        // up correctly. `register` only clones the waker when it has changed.
+        self.shared_state.waker.register(cx.waker());
 
-        if shared_state.completed {
+        // Without a lock, the thread may have completed between our first check and
+        // registering, in which case it found no waker to wake. Check again.
+        if self.shared_state.completed.load(Ordering::SeqCst) {
             Poll::Ready(())
         } else {
-            // Set waker so that the thread can wake up the current task
-            // when the timer has completed, ensuring that the future is polled
-            // again and sees that `completed = true`.
-            //
-            // It's tempting to do this once rather than repeatedly cloning
-            // the waker each time. However, the `TimerFuture` can move between
-            // tasks on the executor, which could cause a stale waker pointing
-            // to the wrong task, preventing `TimerFuture` from waking up
-            // correctly.
-            // This will happen when futures are passed around between tasks after being polled.
-            //
-            // N.B. it's possible to check for this using the `Waker::will_wake`
-            // function, but we omit that here to keep things simple.
-            shared_state.waker = Some(cx.waker().clone());
             Poll::Pending
         }
     }
@@ -63,25 +102,33 @@ impl TimerFuture {
     /// Create a new `TimerFuture` which will complete after the provided
     /// timeout.
     pub fn new(duration: Duration) -> Self {
-        let shared_state = Arc::new(Mutex::new(SharedState {
-            completed: false,
-            waker: None,
-        }));
+        let _memory_scope = memory::scope(memory::Subsystem::Timers);
+        let shared_state = Arc::new(SharedState {
+            completed: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
 
         // Spawn the new thread
         let thread_shared_state = shared_state.clone();
+        let deadline = Instant::now() + duration;
         thread::spawn(move || {
             thread::sleep(duration);
+            timer::record_lateness(Instant::now().saturating_duration_since(deadline));
 
-            let mut shared_state = thread_shared_state.lock().unwrap();
             // Signal that the timer has completed and wake up the last
             // task on which the future was polled, if one exists.
-            shared_state.completed = true;
-            if let Some(waker) = shared_state.waker.take() {
-                waker.wake()
-            }
+            thread_shared_state.completed.store(true, Ordering::SeqCst);
+            thread_shared_state.waker.wake();
         });
 
         TimerFuture { shared_state }
     }
-}
\ No newline at end of file
+}
+
+impl Drop for TimerFuture {
+    fn drop(&mut self) {
+        // The thread sleeps on regardless, but it shouldn't keep the task that gave up on the
+        // timer alive with it, and with the task whatever the waker holds onto.
+        drop(self.shared_state.waker.take());
+    }
+}
@@ -0,0 +1,395 @@
+// `executor::Executor` hands tasks between threads, so every future it runs must be `Send`.
+// A future holding an `Rc` or a `RefCell` borrow across an `.await` can't be spawned there,
+// even when the program only ever needs one thread.
+//
+// `LocalExecutor` keeps its futures on the thread that created it and never lets them leave.
+// A `Waker` still has to be `Send` (a timer thread wakes our tasks), so wakers don't point at
+// the task itself: they carry the task's index and a thread-safe queue of woken indices, which
+// the executor drains on its own thread.
+//
+// `LocalSet` holds `!Send` tasks the same way, but doesn't take over the thread to run them.
+// Its `run_until` future polls them whenever it is polled itself, so the set can be driven
+// from inside another executor, on the thread that owns it, next to that executor's own tasks.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    future::Future,
+    pin::{pin, Pin},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread,
+};
+
+use futures::{
+    future::{FutureExt, LocalBoxFuture},
+    task::{LocalFutureObj, LocalSpawn, SpawnError},
+};
+
+use crate::{atomic_waker::AtomicWaker, context, coop, memory};
+
+/// Index the root future of `block_on` is woken with; never used for a spawned task.
+const ROOT: usize = usize::MAX;
+
+/// Runs `!Send` futures on the thread that created it.
+pub struct LocalExecutor {
+    shared: Rc<Shared>,
+}
+
+/// Spawns `!Send` futures onto a `LocalExecutor`. Like the executor, it can't leave its thread.
+#[derive(Clone)]
+pub struct LocalSpawner {
+    shared: Rc<Shared>,
+}
+
+struct Shared {
+    /// Spawned tasks by index. A slot is `None` while its task is being polled, and after it
+    /// has completed until the index is reused.
+    tasks: RefCell<Vec<Option<LocalTask>>>,
+    /// Indices of completed tasks, free for new ones.
+    free: RefCell<Vec<usize>>,
+    /// Spawned tasks which haven't completed yet.
+    live_tasks: Cell<usize>,
+    ready: Arc<ReadyQueue>,
+}
+
+struct LocalTask {
+    future: LocalBoxFuture<'static, ()>,
+    waker: Arc<TaskWaker>,
+}
+
+/// Indices of woken tasks, filled by wakers on any thread.
+struct ReadyQueue {
+    indices: Mutex<VecDeque<usize>>,
+    /// The executor's thread, unparked whenever a task is woken.
+    thread: thread::Thread,
+    /// For a `LocalSet`, the task its `run_until` future was last polled by, woken whenever
+    /// one of the set's tasks is.
+    driver: AtomicWaker,
+}
+
+struct TaskWaker {
+    index: usize,
+    /// Whether the index is in the ready queue, so waking twice doesn't queue it twice.
+    queued: AtomicBool,
+    ready: Arc<ReadyQueue>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.ready.indices.lock().unwrap().push_back(self.index);
+            self.ready.thread.unpark();
+            self.ready.driver.wake();
+        }
+    }
+}
+
+impl Default for LocalExecutor {
+    fn default() -> Self {
+        LocalExecutor::new()
+    }
+}
+
+impl LocalExecutor {
+    /// Create an executor which runs its tasks on the current thread.
+    pub fn new() -> Self {
+        LocalExecutor {
+            shared: Shared::new(),
+        }
+    }
+
+    pub fn spawner(&self) -> LocalSpawner {
+        LocalSpawner {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Run until every spawned task has completed, parking whenever none is ready.
+    pub fn run(&self) {
+        // Let code running inside tasks know it is on the executor thread.
+        let _enter = context::enter();
+        while self.shared.live_tasks.get() > 0 {
+            match self.shared.next_ready() {
+                Some(index) => self.shared.poll_task(index),
+                None => thread::park(),
+            }
+        }
+    }
+
+    /// Run spawned tasks until `future` completes, and return its output. `future` is polled
+    /// on this thread too, so it needn't be `Send` either. Tasks still running at that point
+    /// stay in the executor for the next `run` or `block_on`.
+    pub fn block_on<T>(&self, future: impl Future<Output = T>) -> T {
+        let mut future = pin!(future);
+        let root_waker = Arc::new(TaskWaker {
+            index: ROOT,
+            queued: AtomicBool::new(false),
+            ready: self.shared.ready.clone(),
+        });
+        let waker = Waker::from(root_waker.clone());
+        let mut cx = Context::from_waker(&waker);
+        root_waker.wake_by_ref();
+
+        let _enter = context::enter();
+        loop {
+            match self.shared.next_ready() {
+                Some(ROOT) => {
+                    root_waker.queued.store(false, Ordering::Release);
+                    if let Poll::Ready(output) = coop::budget(|| future.as_mut().poll(&mut cx)) {
+                        return output;
+                    }
+                }
+                Some(index) => self.shared.poll_task(index),
+                None => thread::park(),
+            }
+        }
+    }
+}
+
+impl Shared {
+    fn new() -> Rc<Self> {
+        Rc::new(Shared {
+            tasks: RefCell::new(Vec::new()),
+            free: RefCell::new(Vec::new()),
+            live_tasks: Cell::new(0),
+            ready: Arc::new(ReadyQueue {
+                indices: Mutex::new(VecDeque::new()),
+                thread: thread::current(),
+                driver: AtomicWaker::new(),
+            }),
+        })
+    }
+
+    fn next_ready(&self) -> Option<usize> {
+        self.ready.indices.lock().unwrap().pop_front()
+    }
+
+    fn poll_task(&self, index: usize) {
+        // Take the task out while polling it, so it can spawn more tasks.
+        let task = self.tasks.borrow_mut()[index].take();
+        let mut task = match task {
+            Some(task) => task,
+            // A wake-up for a task which has completed since.
+            None => return,
+        };
+
+        // Clear the flag before polling, so a wake during the poll queues the task again.
+        task.waker.queued.store(false, Ordering::Release);
+        let waker = Waker::from(task.waker.clone());
+        let mut cx = Context::from_waker(&waker);
+        if coop::budget(|| task.future.as_mut().poll(&mut cx)).is_pending() {
+            self.tasks.borrow_mut()[index] = Some(task);
+        } else {
+            self.live_tasks.set(self.live_tasks.get() - 1);
+            self.free.borrow_mut().push(index);
+        }
+    }
+}
+
+impl LocalSpawner {
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static) {
+        let _memory_scope = memory::scope(memory::Subsystem::Tasks);
+        let shared = &self.shared;
+        let index = shared.free.borrow_mut().pop().unwrap_or_else(|| {
+            let mut tasks = shared.tasks.borrow_mut();
+            tasks.push(None);
+            tasks.len() - 1
+        });
+        let waker = Arc::new(TaskWaker {
+            index,
+            queued: AtomicBool::new(false),
+            ready: shared.ready.clone(),
+        });
+        waker.wake_by_ref();
+        shared.tasks.borrow_mut()[index] = Some(LocalTask {
+            future: future.boxed_local(),
+            waker,
+        });
+        shared.live_tasks.set(shared.live_tasks.get() + 1);
+    }
+}
+
+/// Local tasks `RunUntil` polls at most, each time it is polled, before letting the executor
+/// driving it get on with its other tasks.
+const LOCAL_POLLS_PER_RUN: usize = 32;
+
+/// `!Send` tasks run from inside another executor's task, with `run_until`.
+pub struct LocalSet {
+    shared: Rc<Shared>,
+}
+
+impl Default for LocalSet {
+    fn default() -> Self {
+        LocalSet::new()
+    }
+}
+
+impl LocalSet {
+    pub fn new() -> Self {
+        LocalSet {
+            shared: Shared::new(),
+        }
+    }
+
+    pub fn spawner(&self) -> LocalSpawner {
+        LocalSpawner {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// A future which runs the set's tasks whenever it is polled, until `future` completes,
+    /// and then returns its output. Tasks still running at that point stay in the set.
+    ///
+    /// The future is `!Send`, like the tasks, so whatever drives it has to keep it on one
+    /// thread; see `Executor::block_on_local`.
+    pub fn run_until<F: Future>(&self, future: F) -> RunUntil<F> {
+        RunUntil {
+            shared: self.shared.clone(),
+            future: Box::pin(future),
+        }
+    }
+}
+
+/// Future returned by `LocalSet::run_until`.
+pub struct RunUntil<F> {
+    shared: Rc<Shared>,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for RunUntil<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // Registered first, so a task woken while we look at the queue wakes us again.
+        self.shared.ready.driver.register(cx.waker());
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(output);
+        }
+        for _ in 0..LOCAL_POLLS_PER_RUN {
+            match self.shared.next_ready() {
+                Some(index) => self.shared.poll_task(index),
+                None => return Poll::Pending,
+            }
+        }
+        // More tasks are ready, but the executor's other tasks get a turn first.
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+impl<F> Drop for RunUntil<F> {
+    fn drop(&mut self) {
+        // The set can outlive this future, and the driving task shouldn't live on with it.
+        drop(self.shared.ready.driver.take());
+    }
+}
+
+// The local counterpart of `Spawn`, for code that is generic over local executors.
+impl LocalSpawn for LocalSpawner {
+    fn spawn_local_obj(&self, future: LocalFutureObj<'static, ()>) -> Result<(), SpawnError> {
+        self.spawn(future);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TimerFuture;
+    use futures::task::LocalSpawnExt;
+    use std::time::Duration;
+
+    #[test]
+    fn runs_futures_holding_an_rc() {
+        let executor = LocalExecutor::new();
+        let spawner = executor.spawner();
+        let log = Rc::new(RefCell::new(Vec::new()));
+
+        for (name, millis) in [("slow", 30), ("fast", 10)] {
+            let log = log.clone();
+            spawner.spawn(async move {
+                // The `Rc` is held across an `.await`, which makes this future `!Send`.
+                TimerFuture::new(Duration::from_millis(millis)).await;
+                log.borrow_mut().push(name);
+            });
+        }
+
+        executor.run();
+        assert_eq!(*log.borrow(), ["fast", "slow"]);
+    }
+
+    #[test]
+    fn local_set_runs_its_tasks_while_its_future_is_polled() {
+        let local = LocalSet::new();
+        let spawner = local.spawner();
+        let count = Rc::new(Cell::new(0));
+        for millis in [20, 10] {
+            let count = count.clone();
+            spawner.spawn(async move {
+                TimerFuture::new(Duration::from_millis(millis)).await;
+                count.set(count.get() + 1);
+            });
+        }
+
+        // `futures`' `block_on` only polls the one future; the set's tasks run inside it.
+        let seen = futures::executor::block_on(local.run_until(async {
+            TimerFuture::new(Duration::from_millis(50)).await;
+            count.get()
+        }));
+        assert_eq!(seen, 2);
+    }
+
+    #[test]
+    fn block_on_runs_tasks_spawned_from_inside_tasks() {
+        let executor = LocalExecutor::new();
+        let spawner = executor.spawner();
+        let count = Rc::new(Cell::new(0));
+
+        let inner_count = count.clone();
+        let inner_spawner = spawner.clone();
+        spawner
+            .spawn_local(async move {
+                for _ in 0..3 {
+                    let count = inner_count.clone();
+                    inner_spawner.spawn(async move { count.set(count.get() + 1) });
+                }
+            })
+            .unwrap();
+
+        let count_in_root = count.clone();
+        let seen = executor.block_on(async move {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            count_in_root.get()
+        });
+        assert_eq!(seen, 3);
+        assert_eq!(count.get(), 3);
+    }
+
+    #[test]
+    fn tasks_and_the_root_future_run_on_an_executor_thread() {
+        let executor = LocalExecutor::new();
+        let spawner = executor.spawner();
+        let in_task = Rc::new(Cell::new(false));
+        let flag = in_task.clone();
+        spawner.spawn(async move { flag.set(context::in_executor()) });
+
+        assert!(executor.block_on(async { context::in_executor() }));
+        assert!(in_task.get());
+        assert!(!context::in_executor());
+
+        in_task.set(false);
+        let flag = in_task.clone();
+        spawner.spawn(async move { flag.set(context::in_executor()) });
+        executor.run();
+        assert!(in_task.get());
+    }
+}
@@ -1,135 +1,638 @@
-use futures::{
-    future::{BoxFuture, FutureExt},
-    task::{waker_ref, ArcWake},
-};
+use futures::task::Spawn;
 use std::{
-    future::Future,
-    sync::mpsc::{sync_channel, Receiver, SyncSender},
-    sync::{Arc, Mutex},
-    task::Context,
-    time::Duration,
+    cell::RefCell,
+    collections::VecDeque,
+    rc::Rc,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{mpsc, Arc, Mutex},
+    task::Poll,
+    thread,
+    time::{Duration, Instant},
+};
+use timer_future::{
+    channel, coop, core_executor, defer_async,
+    executor::{new_executor_and_spawner, Executor, Handle, PanicPolicy, SyncHandle, TaskGroup},
+    fair_executor::FairExecutor,
+    local_executor::{LocalExecutor, LocalSet},
+    memory, oneshot, raw_executor,
+    replay::{Recorder, ReplayExecutor, Schedule},
+    runtime::{Flavor, Runtime},
+    scope,
+    TimerFuture,
 };
-use timer_future::TimerFuture;
 
-// In this section, we'll write our own simple executor capable of running a large number
-// of top-level futures to completion concurrently.
+// Build with `--features track-memory` to see where the example's memory goes.
+#[cfg(feature = "track-memory")]
+#[global_allocator]
+static ALLOCATOR: memory::TrackingAllocator = memory::TrackingAllocator;
+
+fn main() {
+    let (executor, spawner) = new_executor_and_spawner();
+
+    // Spawn a task to print before and after waiting on a timer.
+    spawner.spawn(async {
+        println!("howdy!");
+        // Wait for our time future to complete after two seconds.
+        TimerFuture::new(Duration::new(2, 0)).await;
+        println!("done!");
+    });
+
+    spawner.spawn(async {
+        println!("howdy 2!");
+        // Wait for our time future to complete after two seconds.
+        TimerFuture::new(Duration::new(2, 0)).await;
+        println!("done 2!");
+    });
+
+    // Long-running tasks can let the others run between steps of their work.
+    for name in ["ping", "pong"] {
+        spawner.spawn(async move {
+            for _ in 0..2 {
+                println!("{}", name);
+                coop::yield_now().await;
+            }
+        });
+    }
 
-// Future executors take a set of top-level Futures and run them to completion by calling poll
-// whenever the Future can make progress. Typically, an executor will poll a future once to start off.
-// When Futures indicate that they are ready to make progress by calling wake(),
-// they are placed back onto a queue and poll is called again, repeating until the Future has completed.
+    // Rust has no async `Drop`, so cleanup which needs to `.await` (flushing, closing a
+    // connection) is spawned as a new task when the scope ends.
+    let cleanup_spawner = spawner.clone();
+    spawner.spawn(async move {
+        defer_async!(cleanup_spawner, async {
+            TimerFuture::new(Duration::from_millis(500)).await;
+            println!("connection closed!");
+        });
+        println!("using connection");
+    });
+
+    // Synchronous code running on another thread, e.g. a callback from a C library,
+    // can hand work to the executor and wait for the result.
+    let sync_handle = SyncHandle::new(spawner.clone());
+    thread::spawn(move || {
+        let answer = sync_handle.block_on(async {
+            TimerFuture::new(Duration::from_millis(100)).await;
+            42
+        });
+        println!("callback got {}", answer);
+    });
+
+    // `block_on` drives one root future to completion, running the tasks above along the way.
+    // Here the root waits for the output of another task through its `JoinHandle`.
+    let inner_spawner = spawner.clone();
+    let answer = executor.block_on(async move {
+        let answer = inner_spawner.spawn(async {
+            TimerFuture::new(Duration::from_millis(100)).await;
+            6 * 7
+        });
+        answer.await
+    });
+    println!("joined task returned {}", answer);
+
+    // Drop the spawner so that our executor knows it is finished and won't
+    // receive more incoming tasks to run.
+    drop(spawner);
+
+    // Run the executor until the task queue is empty.
+    executor.run();
+    println!("{:?}", executor.metrics());
+
+    shutdown_example();
+    backpressure_example();
+    stepping_example();
+    introspection_example();
+    dump_example();
+    stale_waker_example();
+    task_lifetime_example();
+    worker_pool_example();
+    lifo_slot_example();
+    runtime_example();
+    task_group_example();
+    handle_example();
+    local_executor_example();
+    local_set_example();
+    scope_example();
+    core_executor_example();
+    raw_executor_example();
+    fair_executor_example();
+    replay_example();
+
+    if cfg!(feature = "track-memory") {
+        print!("{}", memory::report());
+    }
+}
+
+// Shutting down stops new spawns, but lets the tasks already spawned finish,
+// here for up to half a second.
+fn shutdown_example() {
+    let (executor, spawner) = new_executor_and_spawner();
+    spawner.spawn(async {
+        TimerFuture::new(Duration::from_millis(100)).await;
+        println!("quick task finished during shutdown");
+    });
+    spawner.spawn(async {
+        TimerFuture::new(Duration::from_secs(10)).await;
+        println!("slow task finished during shutdown");
+    });
+    executor.on_shutdown(|| async {
+        println!("shutdown hook ran");
+    });
 
-/// Task executor that receives tasks off of a channel and runs them.
-struct Executor {
-    ready_queue: Receiver<Arc<Task>>,
+    let pending = executor.shutdown_timeout(Duration::from_millis(500));
+    println!("can still spawn: {}", spawner.status().is_ok());
+    // The slow task was reported on stderr as leaked; the list is there for code to check too.
+    for task in pending {
+        println!("still pending after shutdown: {}", task);
+    }
 }
 
-/// `Spawner` spawns new futures onto the task channel.
-#[derive(Clone)]
-struct Spawner {
-    task_sender: SyncSender<Arc<Task>>
+// With a small task queue, a burst of work has to wait for room (`spawn_async`) or be
+// turned away (`try_spawn`) rather than blocking the thread that spawns it.
+fn backpressure_example() {
+    let (executor, spawner) = Executor::builder().queue_capacity(4).build();
+
+    let producer = spawner.clone();
+    let total = executor.block_on(async move {
+        let mut handles = Vec::new();
+        for job in 0..10u32 {
+            let handle = producer.spawn_async(async move { job * job }).await;
+            handles.push(handle.expect("executor shut down"));
+        }
+        let mut total = 0;
+        for handle in handles {
+            total += handle.await;
+        }
+        total
+    });
+    println!("10 jobs through a queue of 4 summed to {}", total);
+
+    let accepted = (0..6).filter(|_| spawner.try_spawn(async {}).is_ok()).count();
+    println!("a queue of 4 accepted {} of 6 tasks spawned at once", accepted);
+    executor.shutdown(None);
 }
 
-impl Spawner {
-    fn spawn(&self, future: impl Future<Output = ()> + 'static + Send) {
-        let future = future.boxed();
-        let task = Arc::new(Task {
-            future: Mutex::new(Some(future)),
-            task_sender: self.task_sender.clone()
+// Driving the executor a poll at a time shows how tasks interleave at each `.await`.
+fn stepping_example() {
+    let (executor, spawner) = new_executor_and_spawner();
+    for name in ["first", "second"] {
+        spawner.spawn(async move {
+            println!("{} task: step 1", name);
+            coop::yield_now().await;
+            println!("{} task: step 2", name);
         });
-        self.task_sender.send(task).expect("too many tasks queued");
     }
+
+    executor.try_run_one();
+    println!("-- polled one task");
+    let polls = executor.run_until_idle();
+    println!("-- idle after {} more polls", polls);
+}
+
+// A connection task stuck waiting forever is cancelled once it outlives the executor's
+// maximum task lifetime; the accept loop, spawned as long-lived, is left to finish.
+fn task_lifetime_example() {
+    let (executor, spawner) = Executor::builder()
+        .max_task_lifetime(Duration::from_millis(200))
+        .build();
+    spawner.spawn(async {
+        // A peer that never sends anything.
+        TimerFuture::new(Duration::from_secs(3600)).await;
+        println!("stuck connection finished");
+    });
+    spawner.spawn_long_lived(async {
+        TimerFuture::new(Duration::from_millis(400)).await;
+        println!("accept loop finished");
+    });
+    drop(spawner);
+
+    executor.run();
+    println!("tasks cancelled for running too long: {}", executor.metrics().expired_tasks);
 }
 
-/// A future that can reschedule itself to be polled by an `Executor`.
-struct Task {
-    /// In-progress future that should be pushed to completion.
-    ///
-    /// The `Mutex` is not necessary for correctness, since we only have
-    /// one thread executing tasks at once. However, Rust isn't smart
-    /// enough to know that `future` is only mutated from one thread,
-    /// so we need to use the `Mutex` to prove thread-safety. A production
-    /// executor would not need this, and could use `UnsafeCell` instead.
-    future: Mutex<Option<BoxFuture<'static, ()>>>,
-
-    /// Handle to place the task itself back onto the task queue.
-    task_sender: SyncSender<Arc<Task>>
-}
-
-fn new_executor_and_spawner() -> (Executor, Spawner) {
-    // Maximum number of tasks to allow queueing in the channel at once.
-    // This is just to make `sync_channel` happy, and wouldn't be present in
-    // a real executor.
-    const MAX_QUEUED_TASKS : usize = 10_000;
-    let (task_sender, ready_queue) = sync_channel(MAX_QUEUED_TASKS);
-    (Executor { ready_queue }, Spawner { task_sender })
-}
-
-// To poll futures, we'll need to create a Waker.
-// Wakers are responsible for scheduling a task to be polled again once wake is called.
-// Remember that Wakers tell the executor exactly which task has become ready,
-// allowing them to poll just the futures that are ready to make progress.
-impl ArcWake for Task {
-    fn wake_by_ref(arc_self: &Arc<Self>) {
-        // Implement `wake` by sending this task back onto the task channel
-        // so that it will be polled again by the executor.
-        let cloned = arc_self.clone();
-        arc_self.task_sender.send(cloned).expect("too many tasks queued");
+// Listing the tasks shows which are waiting on something and which have work to do.
+fn introspection_example() {
+    let (executor, spawner) = new_executor_and_spawner();
+    spawner.spawn(TimerFuture::new(Duration::from_millis(50)));
+    spawner.spawn(coop::yield_now());
+    executor.try_run_one();
+
+    println!(
+        "{} tasks, {} active:",
+        executor.task_count(),
+        executor.active_tasks()
+    );
+    for task in executor.tasks() {
+        println!("  task {} spawned at {}: {:?}", task.id, task.spawned_at, task.state);
     }
+    drop(spawner);
+    executor.run();
 }
 
+// When a program seems stuck, a dump shows what every task is waiting for and for how long:
+// here a request waiting on a reply nobody is going to send.
+fn dump_example() {
+    let (executor, spawner) = new_executor_and_spawner();
+    let (reply_sender, reply_receiver) = oneshot::channel::<u32>();
+    spawner.spawn(async {
+        let _ = reply_receiver.await;
+    });
+    spawner.spawn(async {
+        for _ in 0..3 {
+            coop::yield_now().await;
+        }
+        TimerFuture::new(Duration::from_millis(100)).await;
+    });
+    executor.run_until_idle();
+    thread::sleep(Duration::from_millis(20));
+
+    print!("{}", executor.dump());
+    drop(reply_sender);
+    drop(spawner);
+    executor.run();
+}
+
+// A hand-written future which registers its waker and forgets to take it back: once the task
+// completes, nothing should still be holding a way to wake it.
+fn stale_waker_example() {
+    let (executor, spawner) = Executor::builder().warn_on_stale_wakers(true).build();
+    let registered: Arc<Mutex<Option<std::task::Waker>>> = Arc::new(Mutex::new(None));
+    let slot = registered.clone();
+    let mut polled = false;
+    spawner.spawn(futures::future::poll_fn(move |cx| {
+        if polled {
+            // Done, but the waker stays in `registered`.
+            return Poll::Ready(());
+        }
+        polled = true;
+        *slot.lock().unwrap() = Some(cx.waker().clone());
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }));
+    drop(spawner);
+    executor.run_until_idle();
+    println!("tasks completed with stale wakers: {}", executor.metrics().stale_wakers);
+}
+
+// CPU-bound futures spread across the pool's threads instead of waiting for each other.
+// One job panics, which with `PanicPolicy::DropTask` only ends that job.
+fn worker_pool_example() {
+    let (pool, spawner) = Executor::builder()
+        .worker_threads(4)
+        .thread_name("pool")
+        .panic_policy(PanicPolicy::DropTask)
+        // These jobs hog their worker on purpose, so only complain about really slow polls.
+        .slow_poll_threshold(Duration::from_secs(5))
+        .build_worker_pool();
+
+    spawner.spawn(async { panic!("this job fails, the others carry on") });
+
+    for job in 0..4 {
+        spawner.spawn(async move {
+            let sum: u64 = (0..20_000_000u64).sum();
+            println!(
+                "job {} summed to {} on {}",
+                job,
+                sum,
+                thread::current().name().unwrap_or("?")
+            );
+        });
+    }
+    drop(spawner);
+
+    pool.run();
+}
+
+/// Times `ROUND_TRIPS` messages bounced between two tasks on a one-worker pool, while ten
+/// other tasks keep the worker's queue busy.
+fn ping_pong(lifo_slot: bool) -> Duration {
+    const ROUND_TRIPS: usize = 200;
+    let (pool, spawner) = Executor::builder()
+        .worker_threads(1)
+        .lifo_slot(lifo_slot)
+        .build_worker_pool();
+    let (elapsed_sender, elapsed) = mpsc::channel();
+
+    // Everything is spawned from a task on the worker, so it all shares the worker's queue.
+    let inner_spawner = spawner.clone();
+    spawner.spawn(async move {
+        let done = Arc::new(AtomicBool::new(false));
+        for _ in 0..10 {
+            let done = done.clone();
+            inner_spawner.spawn(async move {
+                while !done.load(Ordering::Relaxed) {
+                    let started = Instant::now();
+                    while started.elapsed() < Duration::from_micros(20) {}
+                    coop::yield_now().await;
+                }
+            });
+        }
 
-// When a Waker is created from an Arc<Task>, calling wake() on it will cause a copy
-// of the Arc to be sent onto the task channel.
-// Our executor then needs to pick up the task and poll it.
-impl Executor {
-    fn run(&self) {
-        while let Ok(task) = self.ready_queue.recv() {
-            // Take the future, and if it has not yet completed (is still Some),
-            // poll it in an attempt to complete it.
-            let mut future_slot = task.future.lock().unwrap();
-            if let Some(mut future) = future_slot.take() {
-                // Create a `LocalWaker` form the task itself
-                let waker = waker_ref(&task);
-                let context = &mut Context::from_waker(&*waker);
-
-                // `BoxFuture<T>` is a type alias for
-                // `Pin<Box<dyn Future<Output = T> + Send + 'static>>`.
-                // We can get a `Pin<&mut dyn Future + Send + 'static>`
-                // from it by calling the `Pin::as_mut` method.
-                if future.as_mut().poll(context).is_pending() {
-                    // We're not done processing the future, so put it
-                    // back in its task to be run again in the future.
-                    *future_slot = Some(future);
+        let (ping_sender, ping_receiver) = channel::channel(1);
+        let (pong_sender, pong_receiver) = channel::channel(1);
+        inner_spawner.spawn(async move {
+            while let Some(ball) = ping_receiver.recv().await {
+                if pong_sender.send(ball).await.is_err() {
+                    break;
                 }
             }
+        });
+
+        let started = Instant::now();
+        for ball in 0..ROUND_TRIPS {
+            ping_sender.send(ball).await.unwrap();
+            pong_receiver.recv().await.unwrap();
         }
+        elapsed_sender.send(started.elapsed()).unwrap();
+        done.store(true, Ordering::Relaxed);
+    });
+    drop(spawner);
+
+    pool.run();
+    elapsed.recv().unwrap()
+}
+
+// A task woken by the one running is polled next from the worker's LIFO slot, instead of
+// waiting behind everything else in the queue. Message passing gets much faster when the
+// worker is busy.
+fn lifo_slot_example() {
+    println!("ping-pong with a LIFO slot: {:?}", ping_pong(true));
+    println!("ping-pong through the FIFO queue: {:?}", ping_pong(false));
+}
+
+// `Runtime` puts the pieces above behind one value: spawn, block on a result, shut down.
+fn runtime_example() {
+    let runtime = Runtime::builder()
+        .worker_threads(2)
+        .thread_name("runtime")
+        .slow_poll_threshold(Duration::from_millis(50))
+        .build();
+
+    runtime.spawn(async {
+        TimerFuture::new(Duration::from_millis(200)).await;
+        println!("background task finished on {}", thread::current().name().unwrap_or("?"));
+    });
+    let answer = runtime.block_on(async {
+        TimerFuture::new(Duration::from_millis(100)).await;
+        6 * 7
+    });
+    println!("runtime computed {}", answer);
+    for (index, metrics) in runtime.worker_metrics().iter().enumerate() {
+        println!("runtime worker {}: {:?}", index, metrics);
     }
+
+    println!("runtime slow polls so far: {}", runtime.metrics().slow_polls);
+    // The background task is still waiting on its timer.
+    print!("{}", runtime.dump());
+
+    // Waits for the background task too.
+    runtime.shutdown(None);
+
+    // The current-thread flavor runs everything right here instead.
+    let runtime = Runtime::builder().flavor(Flavor::CurrentThread).build();
+    let thread_name = runtime.block_on(async { thread::current().name().map(str::to_owned) });
+    println!("current-thread runtime ran on {}", thread_name.as_deref().unwrap_or("?"));
 }
 
+// Children spawned in a `TaskGroup` are waited for, or cancelled, before the parent moves on,
+// rather than being left running in the background.
+fn task_group_example() {
+    let (executor, spawner) = new_executor_and_spawner();
+    let group_spawner = spawner.clone();
+    executor.block_on(async move {
+        let mut group = TaskGroup::new(&group_spawner);
+        for (name, millis) in [("fetch a", 100), ("fetch b", 50)] {
+            group.spawn(async move {
+                TimerFuture::new(Duration::from_millis(millis)).await;
+                println!("group child {} finished", name);
+            });
+        }
+        group.join().await;
+        println!("every child in the group finished");
+
+        let mut group = TaskGroup::new(&group_spawner);
+        group.spawn(async {
+            TimerFuture::new(Duration::from_secs(10)).await;
+            println!("never printed: the group was cancelled");
+        });
+        group.cancel();
+    });
+    drop(spawner);
+    executor.run();
+}
+
+// Code deep inside a task can spawn through `Handle::current` instead of taking a `Spawner`.
+fn handle_example() {
+    // Nothing here was handed a `Spawner`.
+    async fn audit(event: &'static str) {
+        Handle::current().spawn(async move {
+            println!("audit log: {}", event);
+        });
+    }
 
-fn main() {
     let (executor, spawner) = new_executor_and_spawner();
+    let total = executor.block_on(async {
+        audit("job started").await;
+        // Anything else taking a `Spawner` can be given the current one.
+        let mut group = TaskGroup::new(Handle::current().spawner());
+        group.spawn(async { println!("group child spawned through the current handle") });
+        group.join().await;
+        Handle::current().spawn(async { 2 + 2 }).await
+    });
+    println!("spawned through the current handle: {}", total);
+    drop(spawner);
+    executor.run();
+}
 
-    // Spawn a task to print before and after waiting on a timer.
+// Futures holding an `Rc` across an `.await` aren't `Send`, so they can't go on the executors
+// above. The local executor runs them without ever leaving this thread.
+fn local_executor_example() {
+    let executor = LocalExecutor::new();
+    let spawner = executor.spawner();
+    let greetings = Rc::new(RefCell::new(Vec::new()));
+
+    for (name, millis) in [("world", 200), ("local executor", 100)] {
+        let greetings = greetings.clone();
+        spawner.spawn(async move {
+            TimerFuture::new(Duration::from_millis(millis)).await;
+            greetings.borrow_mut().push(format!("hello, {}!", name));
+        });
+    }
+
+    executor.run();
+    println!("{}", greetings.borrow().join(" "));
+}
+
+// A `LocalSet` runs `Rc`-holding tasks on the executor's thread, between its `Send` tasks.
+fn local_set_example() {
+    let (executor, spawner) = new_executor_and_spawner();
     spawner.spawn(async {
-        println!("howdy!");
-        // Wait for our time future to complete after two seconds.
-        TimerFuture::new(Duration::new(2, 0)).await;
-        println!("done!");
+        TimerFuture::new(Duration::from_millis(50)).await;
+        println!("Send task finished next to the local set");
+    });
+
+    let local = LocalSet::new();
+    let greetings = Rc::new(RefCell::new(Vec::new()));
+    for name in ["Rc", "LocalSet"] {
+        let greetings = greetings.clone();
+        local.spawner().spawn(async move {
+            TimerFuture::new(Duration::from_millis(20)).await;
+            greetings.borrow_mut().push(format!("hello, {}!", name));
+        });
+    }
+    let greeted = executor.block_on_local(local.run_until(async move {
+        TimerFuture::new(Duration::from_millis(100)).await;
+        greetings.borrow().join(" ")
+    }));
+    println!("{}", greeted);
+    drop(spawner);
+    executor.run();
+}
+
+// Tasks spawned in a scope can borrow the caller's locals instead of taking copies, because
+// the scope waits for them before returning.
+fn scope_example() {
+    let names = ["world".to_string(), "scoped tasks".to_string()];
+    let greetings = Mutex::new(Vec::new());
+
+    scope::scope(|s| {
+        for (name, millis) in names.iter().zip([200, 100]) {
+            let greetings = &greetings;
+            s.spawn(async move {
+                TimerFuture::new(Duration::from_millis(millis)).await;
+                greetings.lock().unwrap().push(format!("hello, {}!", name));
+            });
+        }
     });
+    println!("{}", greetings.into_inner().unwrap().join(" "));
+}
+
+// The same executor built without std threads or channels, which could run on an embedded
+// target given a suitable queue and parker. Here we plug in the std ones.
+fn core_executor_example() {
+    let (mut executor, spawner) = core_executor::new_executor_and_spawner(
+        Mutex::new(VecDeque::new()),
+        core_executor::ThreadPark::current(),
+    );
 
     spawner.spawn(async {
-        println!("howdy 2!");
-        // Wait for our time future to complete after two seconds.
-        TimerFuture::new(Duration::new(2, 0)).await;
-        println!("done 2!");
+        println!("howdy from the core executor!");
+        TimerFuture::new(Duration::from_millis(500)).await;
+        println!("done on the core executor!");
     });
 
-    // Drop the spawner so that our executor knows it is finished and won't
-    // receive more incoming tasks to run.
+    executor.run();
+}
+
+// The same kind of executor again, with its wakers built from a hand-written vtable.
+fn raw_executor_example() {
+    let (executor, spawner) = raw_executor::new_executor_and_spawner();
+    spawner.spawn(async {
+        TimerFuture::new(Duration::from_millis(100)).await;
+        println!("woken through a hand-rolled vtable!");
+    });
     drop(spawner);
+    executor.run();
+}
+
+// Background jobs and request handlers share one thread, but requests get three times the
+// CPU time while both have work.
+fn fair_executor_example() {
+    let executor = FairExecutor::new();
+    let requests = executor.add_group("requests", 3);
+    let background = executor.add_group("background", 1);
+    // Both groups have work until the same deadline, so neither runs out early and leaves the
+    // other alone; CPU time splits 3:1 between them.
+    let deadline = Instant::now() + Duration::from_millis(100);
+    for (name, spawner) in [("background", &background), ("requests", &requests)] {
+        spawner.spawn(async move {
+            let mut slices = 0;
+            while Instant::now() < deadline {
+                let started = Instant::now();
+                while started.elapsed() < Duration::from_micros(500) {}
+                slices += 1;
+                coop::yield_now().await;
+            }
+            println!("{} did {} slices of work", name, slices);
+        });
+    }
 
-    // Run the executor until the task queue is empty.
     executor.run();
+    for group in executor.group_metrics() {
+        println!(
+            "group {} (weight {}) used {:?}",
+            group.name, group.weight, group.cpu_time
+        );
+    }
+}
+
+/// Two tasks taking turns to append to a shared log. How their entries interleave depends on
+/// the order the executor polls them in, which on a `WorkerPool` varies from run to run.
+fn interleaving_program(spawner: &impl Spawn, log: Arc<Mutex<Vec<&'static str>>>) {
+    use futures::task::SpawnExt;
+
+    for name in ["tick", "tock"] {
+        let log = log.clone();
+        spawner
+            .spawn(async move {
+                for _ in 0..3 {
+                    log.lock().unwrap().push(name);
+                    coop::yield_now().await;
+                }
+            })
+            .expect("executor shut down");
+    }
+}
+
+/// Records a run on a worker pool, then replays it from the file on one thread. The replay
+/// interleaves the tasks exactly as the recorded run did, however that turned out.
+///
+/// With more than one worker, two polls can overlap; the schedule has them in the order they
+/// started, which the replay follows even if their log entries landed the other way round.
+fn record_and_replay(
+    workers: usize,
+    path: &std::path::Path,
+) -> (Vec<&'static str>, Vec<&'static str>) {
+    let recorder = Arc::new(Recorder::new());
+    let (pool, spawner) = Executor::builder()
+        .worker_threads(workers)
+        .record_schedule(recorder.clone())
+        .build_worker_pool();
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    interleaving_program(&spawner, recorded.clone());
+    drop(spawner);
+    pool.run();
+    recorder.schedule().save(path).expect("couldn't save the schedule");
+
+    let executor = ReplayExecutor::new(Schedule::load(path).expect("couldn't load the schedule"));
+    let replayed = Arc::new(Mutex::new(Vec::new()));
+    interleaving_program(&executor.spawner(), replayed.clone());
+    executor.run().expect("replay diverged from the recording");
+
+    let recorded = recorded.lock().unwrap().clone();
+    let replayed = replayed.lock().unwrap().clone();
+    (recorded, replayed)
+}
+
+fn replay_example() {
+    let path = std::env::temp_dir().join("timer-future-schedule.txt");
+    let (recorded, replayed) = record_and_replay(2, &path);
+    println!("recorded {:?}", recorded);
+    println!("replayed {:?}", replayed);
+    let _ = std::fs::remove_file(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_follows_a_recorded_worker_pool_schedule() {
+        let path = std::env::temp_dir().join(format!("schedule-{}.txt", std::process::id()));
+        // One worker, so polls never overlap and the two logs must match exactly.
+        for _ in 0..10 {
+            let (recorded, replayed) = record_and_replay(1, &path);
+            assert_eq!(recorded.len(), 6);
+            assert_eq!(replayed, recorded);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,216 @@
+// Where does an async program's memory go? Every spawned task boxes its future, every timer
+// allocates shared state (and a thread), every channel a queue. `TrackingAllocator` answers
+// that question per subsystem: code marks what it is doing with `memory::scope`, and every
+// allocation made inside the scope is charged to that subsystem.
+//
+// The allocator is opt-in; nothing is tracked unless a program installs it:
+//
+//     #[global_allocator]
+//     static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+//
+// An allocation can be freed far away from where it was made, so each one carries a small
+// header recording its subsystem, and the free is charged back to the right place.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The parts of the crate which allocations can be attributed to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+    /// Anything not inside a `scope`.
+    Other,
+    /// Spawned tasks and their futures.
+    Tasks,
+    /// `TimerFuture` state and timer threads.
+    Timers,
+    /// Channel queues and waker lists.
+    Channels,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 4] = [
+        Subsystem::Other,
+        Subsystem::Tasks,
+        Subsystem::Timers,
+        Subsystem::Channels,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+thread_local! {
+    // Must not allocate itself, since it's read from inside the allocator.
+    static CURRENT: Cell<Subsystem> = const { Cell::new(Subsystem::Other) };
+}
+
+/// Charge allocations on this thread to `subsystem` until the guard is dropped.
+pub fn scope(subsystem: Subsystem) -> ScopeGuard {
+    let previous = CURRENT.with(|current| current.replace(subsystem));
+    ScopeGuard { previous }
+}
+
+/// Returned by `scope`; restores the previous subsystem when dropped.
+pub struct ScopeGuard {
+    previous: Subsystem,
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+struct Counters {
+    allocations: AtomicUsize,
+    allocated_bytes: AtomicUsize,
+    live_bytes: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: Counters = Counters {
+    allocations: AtomicUsize::new(0),
+    allocated_bytes: AtomicUsize::new(0),
+    live_bytes: AtomicUsize::new(0),
+};
+
+static COUNTERS: [Counters; Subsystem::ALL.len()] = [ZERO; Subsystem::ALL.len()];
+
+/// A global allocator which forwards to the system allocator and counts usage per `Subsystem`.
+pub struct TrackingAllocator;
+
+// Every allocation is preceded by a header holding its subsystem. The header is padded to
+// the allocation's alignment so the pointer handed out stays correctly aligned.
+fn header_size(layout: Layout) -> usize {
+    layout.align().max(std::mem::size_of::<usize>())
+}
+
+fn with_header(layout: Layout) -> Option<Layout> {
+    let size = layout.size().checked_add(header_size(layout))?;
+    Layout::from_size_align(size, layout.align().max(std::mem::align_of::<usize>())).ok()
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let full_layout = match with_header(layout) {
+            Some(full_layout) => full_layout,
+            None => return std::ptr::null_mut(),
+        };
+        let base = System.alloc(full_layout);
+        if base.is_null() {
+            return base;
+        }
+
+        // `try_with` because the thread-local may already be gone during thread teardown.
+        let subsystem = CURRENT
+            .try_with(|current| current.get())
+            .unwrap_or(Subsystem::Other);
+        let user = base.add(header_size(layout));
+        (user as *mut usize).sub(1).write(subsystem.index());
+
+        let counters = &COUNTERS[subsystem.index()];
+        counters.allocations.fetch_add(1, Ordering::Relaxed);
+        counters.allocated_bytes.fetch_add(layout.size(), Ordering::Relaxed);
+        counters.live_bytes.fetch_add(layout.size(), Ordering::Relaxed);
+        user
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let index = (ptr as *mut usize).sub(1).read();
+        COUNTERS[index]
+            .live_bytes
+            .fetch_sub(layout.size(), Ordering::Relaxed);
+
+        let full_layout = with_header(layout).expect("layout was valid when allocated");
+        System.dealloc(ptr.sub(header_size(layout)), full_layout);
+    }
+}
+
+/// Memory usage of one subsystem.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Number of allocations made.
+    pub allocations: usize,
+    /// Total bytes ever allocated.
+    pub allocated_bytes: usize,
+    /// Bytes allocated and not yet freed.
+    pub live_bytes: usize,
+}
+
+/// Usage of every subsystem so far. All zero unless `TrackingAllocator` is installed.
+pub fn report() -> Report {
+    Report(Subsystem::ALL.map(|subsystem| {
+        let counters = &COUNTERS[subsystem.index()];
+        (
+            subsystem,
+            Usage {
+                allocations: counters.allocations.load(Ordering::Relaxed),
+                allocated_bytes: counters.allocated_bytes.load(Ordering::Relaxed),
+                live_bytes: counters.live_bytes.load(Ordering::Relaxed),
+            },
+        )
+    }))
+}
+
+/// Snapshot returned by `report`, printable as a small table.
+#[derive(Clone, Debug)]
+pub struct Report(pub [(Subsystem, Usage); Subsystem::ALL.len()]);
+
+impl Report {
+    pub fn get(&self, subsystem: Subsystem) -> Usage {
+        self.0[subsystem.index()].1
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<10} {:>12} {:>16} {:>12}",
+            "subsystem", "allocations", "allocated bytes", "live bytes"
+        )?;
+        for (subsystem, usage) in &self.0 {
+            writeln!(
+                f,
+                "{:<10} {:>12} {:>16} {:>12}",
+                format!("{:?}", subsystem),
+                usage.allocations,
+                usage.allocated_bytes,
+                usage.live_bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_are_charged_to_the_current_scope() {
+        let allocator = TrackingAllocator;
+        let layout = Layout::from_size_align(100, 32).unwrap();
+        let before = report().get(Subsystem::Channels);
+
+        let ptr = {
+            let _scope = scope(Subsystem::Channels);
+            unsafe { allocator.alloc(layout) }
+        };
+        assert_eq!(ptr as usize % 32, 0);
+        let during = report().get(Subsystem::Channels);
+        assert_eq!(during.allocations, before.allocations + 1);
+        assert_eq!(during.live_bytes, before.live_bytes + 100);
+
+        // Freed outside the scope, but still charged back to channels.
+        unsafe { allocator.dealloc(ptr, layout) };
+        let after = report().get(Subsystem::Channels);
+        assert_eq!(after.live_bytes, before.live_bytes);
+        assert_eq!(after.allocated_bytes, before.allocated_bytes + 100);
+    }
+}
@@ -0,0 +1,161 @@
+// A channel for exactly one value, like a task handing its output to whoever awaits it.
+//
+// The general `channel` keeps a queue, capacity accounting and sets of waiting tasks behind a
+// `Mutex`. Here there is one value and one receiver, so an `AtomicWaker` holds the receiver's
+// waker and a flag says when the sender is done, either by sending or by being dropped.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use crate::{atomic_waker::AtomicWaker, channel::TryRecvError};
+
+/// Create a oneshot channel.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        value: Mutex::new(None),
+        complete: AtomicBool::new(false),
+        receiver_dropped: AtomicBool::new(false),
+        receiver_waker: AtomicWaker::new(),
+    });
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+struct Inner<T> {
+    value: Mutex<Option<T>>,
+    /// Set once the sender has sent or been dropped; nothing changes `value` afterwards.
+    complete: AtomicBool,
+    receiver_dropped: AtomicBool,
+    receiver_waker: AtomicWaker,
+}
+
+/// Returned by the `Receiver` when the `Sender` was dropped without sending.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Canceled;
+
+impl fmt::Display for Canceled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "oneshot sender dropped without sending")
+    }
+}
+
+impl std::error::Error for Canceled {}
+
+/// The sending half of a oneshot channel.
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+/// The receiving half of a oneshot channel. Await it to get the value.
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send `value`, handing it back if the receiver has been dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        if self.inner.receiver_dropped.load(Ordering::SeqCst) {
+            return Err(value);
+        }
+        *self.inner.value.lock().unwrap() = Some(value);
+        // Dropping `self` marks the channel complete and wakes the receiver.
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.inner.complete.store(true, Ordering::SeqCst);
+        self.inner.receiver_waker.wake();
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Take the value if it has been sent, without waiting.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        if !self.inner.complete.load(Ordering::SeqCst) {
+            return Err(TryRecvError::Empty);
+        }
+        self.inner
+            .value
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(TryRecvError::Disconnected)
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, Canceled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Ok(value)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(Err(Canceled)),
+            Err(TryRecvError::Empty) => {}
+        }
+        self.inner.receiver_waker.register(cx.waker());
+        // The sender may have finished between the check above and registering.
+        match self.try_recv() {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(Err(Canceled)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.receiver_dropped.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::waker::CountingWaker;
+    use futures::executor::block_on;
+    use std::thread;
+
+    #[test]
+    fn receives_a_value_sent_from_another_thread() {
+        let (sender, receiver) = channel();
+        thread::spawn(move || sender.send(7).unwrap());
+        assert_eq!(block_on(receiver), Ok(7));
+    }
+
+    #[test]
+    fn dropping_the_sender_cancels_and_wakes_the_receiver() {
+        let (sender, mut receiver) = channel::<()>();
+        let counter = CountingWaker::new();
+        let waker = counter.waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(Pin::new(&mut receiver).poll(&mut cx).is_pending());
+        drop(sender);
+        assert_eq!(counter.wake_count(), 1);
+        assert_eq!(
+            Pin::new(&mut receiver).poll(&mut cx),
+            Poll::Ready(Err(Canceled))
+        );
+    }
+
+    #[test]
+    fn send_fails_once_the_receiver_is_dropped() {
+        let (sender, receiver) = channel();
+        drop(receiver);
+        assert_eq!(sender.send("lost"), Err("lost"));
+    }
+}
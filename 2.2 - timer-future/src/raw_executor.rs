@@ -0,0 +1,164 @@
+// `executor::Executor` gets its wakers from `ArcWake`: it implements `wake_by_ref` for
+// `Arc<Task>` and `waker_ref` does the rest. This is the same executor with that step done by
+// hand, to show what `ArcWake` generates.
+//
+// A `Waker` is a data pointer plus a `RawWakerVTable` of four functions. Here the pointer is
+// an `Arc<Task>` turned into a raw pointer with `Arc::into_raw`, and every `Waker` (and every
+// `RawWaker` made by `clone`) owns one strong reference to the task:
+//
+// - `clone` adds a reference for the new waker,
+// - `wake` consumes the waker, so it takes its reference back and queues the task with it,
+// - `wake_by_ref` only borrows the waker, so it queues a new reference and leaves its own,
+// - `drop` releases the waker's reference.
+
+use std::{
+    future::Future,
+    mem::ManuallyDrop,
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    task::{Context, RawWaker, RawWakerVTable, Waker},
+};
+
+use futures::future::{BoxFuture, FutureExt};
+
+/// How many tasks the task channel holds before spawning or waking blocks.
+const MAX_QUEUED_TASKS: usize = 10_000;
+
+/// Runs tasks received off the task channel on the current thread.
+pub struct Executor {
+    ready_queue: Receiver<Arc<Task>>,
+}
+
+/// Spawns futures onto an `Executor`.
+#[derive(Clone)]
+pub struct Spawner {
+    task_sender: SyncSender<Arc<Task>>,
+}
+
+struct Task {
+    /// `None` once the future has completed.
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    task_sender: SyncSender<Arc<Task>>,
+}
+
+impl Task {
+    fn schedule(self: Arc<Self>) {
+        let task_sender = self.task_sender.clone();
+        task_sender.send(self).expect("too many tasks queued");
+    }
+}
+
+pub fn new_executor_and_spawner() -> (Executor, Spawner) {
+    let (task_sender, ready_queue) = sync_channel(MAX_QUEUED_TASKS);
+    (Executor { ready_queue }, Spawner { task_sender })
+}
+
+impl Spawner {
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(future.boxed())),
+            task_sender: self.task_sender.clone(),
+        });
+        task.schedule();
+    }
+}
+
+impl Executor {
+    /// Run tasks until every `Spawner` and task has been dropped.
+    pub fn run(&self) {
+        while let Ok(task) = self.ready_queue.recv() {
+            let mut future_slot = task.future.lock().unwrap();
+            if let Some(mut future) = future_slot.take() {
+                let waker = task_waker(task.clone());
+                let context = &mut Context::from_waker(&waker);
+                if future.as_mut().poll(context).is_pending() {
+                    *future_slot = Some(future);
+                }
+            }
+        }
+    }
+}
+
+const TASK_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(task_clone, task_wake, task_wake_by_ref, task_drop);
+
+/// Create a `Waker` which queues `task` when woken, taking over the reference passed in.
+fn task_waker(task: Arc<Task>) -> Waker {
+    let data = Arc::into_raw(task) as *const ();
+    // Safety: the vtable functions treat `data` as the `Arc<Task>` created above and keep
+    // its reference count balanced.
+    unsafe { Waker::from_raw(RawWaker::new(data, &TASK_VTABLE)) }
+}
+
+unsafe fn task_clone(data: *const ()) -> RawWaker {
+    Arc::increment_strong_count(data as *const Task);
+    RawWaker::new(data, &TASK_VTABLE)
+}
+
+unsafe fn task_wake(data: *const ()) {
+    // The waker's reference goes back onto the task channel, rather than being dropped.
+    let task = Arc::from_raw(data as *const Task);
+    task.schedule();
+}
+
+unsafe fn task_wake_by_ref(data: *const ()) {
+    // Borrow the waker's reference without releasing it, then queue a clone.
+    let task = ManuallyDrop::new(Arc::from_raw(data as *const Task));
+    Arc::clone(&task).schedule();
+}
+
+unsafe fn task_drop(data: *const ()) {
+    drop(Arc::from_raw(data as *const Task));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TimerFuture;
+    use std::{sync::mpsc, time::Duration};
+
+    #[test]
+    fn runs_tasks_woken_by_timers() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (done_sender, done_receiver) = mpsc::channel();
+        for (name, millis) in [("slow", 30), ("fast", 10)] {
+            let done_sender = done_sender.clone();
+            spawner.spawn(async move {
+                TimerFuture::new(Duration::from_millis(millis)).await;
+                done_sender.send(name).unwrap();
+            });
+        }
+        drop((spawner, done_sender));
+
+        executor.run();
+        assert_eq!(done_receiver.iter().collect::<Vec<_>>(), ["fast", "slow"]);
+    }
+
+    #[test]
+    fn wakers_keep_the_task_reference_count_balanced() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let task = Arc::new(Task {
+            future: Mutex::new(None),
+            task_sender: spawner.task_sender.clone(),
+        });
+
+        let waker = task_waker(task.clone());
+        let cloned = waker.clone();
+        assert_eq!(Arc::strong_count(&task), 3);
+
+        // Each wake queues the task once; `wake` hands over the waker's own reference.
+        cloned.wake_by_ref();
+        cloned.wake();
+        assert_eq!(Arc::strong_count(&task), 4);
+        drop(waker);
+        assert_eq!(Arc::strong_count(&task), 3);
+
+        let queued: Vec<_> = executor.ready_queue.try_iter().collect();
+        assert_eq!(queued.len(), 2);
+        assert!(queued.iter().all(|queued| Arc::ptr_eq(queued, &task)));
+        drop(queued);
+        assert_eq!(Arc::strong_count(&task), 1);
+    }
+}
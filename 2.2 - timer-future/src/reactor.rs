@@ -0,0 +1,283 @@
+// Waiting on sockets. A socket in non-blocking mode answers a read that has nothing to return
+// with `WouldBlock` instead of waiting, which is half of what a task needs; the other half is
+// being told when it's worth trying again. The operating system's readiness APIs (epoll on
+// Linux, kqueue on the BSDs and macOS) do that, and the `polling` crate puts one interface over
+// them.
+//
+// The reactor is a thread sitting in `Poller::wait`. For each socket that becomes ready it wakes
+// the task waiting on it, just as a `TimerFuture` thread wakes the task waiting on a timer, so
+// any executor in this crate can run tasks doing socket I/O. It starts the first time a socket
+// is registered.
+//
+// `polling` reports each readiness once: a socket is disarmed by the event, and a task waiting
+// on it arms it again when it registers its waker. Arming a socket which is already ready
+// reports it straight away, so readiness arriving between the `WouldBlock` and the
+// registration isn't lost.
+
+use std::{
+    collections::HashMap,
+    future::poll_fn,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::io::{AsRawFd, RawFd},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use futures::io::{AsyncRead, AsyncWrite};
+use polling::{Event, Poller};
+
+/// The poller, and the sockets registered with it, by key.
+struct Reactor {
+    poller: Poller,
+    sources: Mutex<HashMap<usize, Arc<Source>>>,
+    next_key: AtomicUsize,
+}
+
+/// A registered socket, and the tasks waiting for it.
+struct Source {
+    raw: RawFd,
+    key: usize,
+    wakers: Mutex<Wakers>,
+}
+
+#[derive(Default)]
+struct Wakers {
+    /// Waiting for the socket to become readable.
+    reader: Option<Waker>,
+    /// Waiting for the socket to become writable.
+    writer: Option<Waker>,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Read,
+    Write,
+}
+
+/// The process-wide reactor, starting its thread the first time it's needed.
+fn reactor() -> &'static Reactor {
+    static REACTOR: OnceLock<Reactor> = OnceLock::new();
+    REACTOR.get_or_init(|| {
+        thread::Builder::new()
+            .name("reactor".to_owned())
+            .spawn(|| reactor().run())
+            .expect("failed to spawn the reactor thread");
+        Reactor {
+            poller: Poller::new().expect("failed to create a poller"),
+            sources: Mutex::new(HashMap::new()),
+            next_key: AtomicUsize::new(0),
+        }
+    })
+}
+
+impl Reactor {
+    fn run(&self) {
+        let mut events = Vec::new();
+        loop {
+            events.clear();
+            match self.poller.wait(&mut events, None) {
+                Ok(_) => {}
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => panic!("reactor failed to wait for events: {}", error),
+            }
+            for event in &events {
+                // The socket may have been deregistered since the event came in.
+                let source = self.sources.lock().unwrap().get(&event.key).cloned();
+                if let Some(source) = source {
+                    source.ready(event.readable, event.writable);
+                }
+            }
+        }
+    }
+
+    fn register(&self, raw: RawFd) -> io::Result<Arc<Source>> {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        // Nothing is waiting on the socket yet, so it starts out disarmed.
+        self.poller.add(raw, Event::none(key))?;
+        let source = Arc::new(Source {
+            raw,
+            key,
+            wakers: Mutex::new(Wakers::default()),
+        });
+        self.sources.lock().unwrap().insert(key, source.clone());
+        Ok(source)
+    }
+
+    fn deregister(&self, source: &Source) -> io::Result<()> {
+        self.sources.lock().unwrap().remove(&source.key);
+        self.poller.delete(source.raw)
+    }
+}
+
+impl Source {
+    /// Stores `cx`'s waker to be woken once the socket is ready for `direction`. The caller has
+    /// just been told `WouldBlock`, so this is always `Pending`, unless arming the socket fails.
+    fn poll_ready(&self, cx: &mut Context<'_>, direction: Direction) -> Poll<io::Result<()>> {
+        let mut wakers = self.wakers.lock().unwrap();
+        let slot = match direction {
+            Direction::Read => &mut wakers.reader,
+            Direction::Write => &mut wakers.writer,
+        };
+        if !slot.as_ref().is_some_and(|waker| waker.will_wake(cx.waker())) {
+            *slot = Some(cx.waker().clone());
+        }
+        match self.arm(&wakers) {
+            Ok(()) => Poll::Pending,
+            Err(error) => Poll::Ready(Err(error)),
+        }
+    }
+
+    /// Wakes the tasks waiting for what the socket is now ready for.
+    fn ready(&self, readable: bool, writable: bool) {
+        let mut wakers = self.wakers.lock().unwrap();
+        let reader = if readable { wakers.reader.take() } else { None };
+        let writer = if writable { wakers.writer.take() } else { None };
+        // The event disarmed the socket, so listen again for whoever is still waiting.
+        if wakers.reader.is_some() || wakers.writer.is_some() {
+            // Failing here leaves them waiting; they'll see the error on their next poll.
+            let _ = self.arm(&wakers);
+        }
+        drop(wakers);
+        reader.into_iter().chain(writer).for_each(Waker::wake);
+    }
+
+    fn arm(&self, wakers: &Wakers) -> io::Result<()> {
+        let interest = Event {
+            key: self.key,
+            readable: wakers.reader.is_some(),
+            writable: wakers.writer.is_some(),
+        };
+        reactor().poller.modify(self.raw, interest)
+    }
+}
+
+/// A socket in non-blocking mode, registered with the reactor so tasks can wait on it.
+pub struct Async<T: AsRawFd> {
+    io: T,
+    source: Arc<Source>,
+}
+
+impl<T: AsRawFd> Async<T> {
+    fn register(io: T) -> io::Result<Self> {
+        let source = reactor().register(io.as_raw_fd())?;
+        Ok(Async { io, source })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Runs `op` on the socket, and if it would block, arranges for the task to be woken when
+    /// the socket is ready for `direction` again.
+    fn poll_io<R>(
+        &self,
+        cx: &mut Context<'_>,
+        direction: Direction,
+        mut op: impl FnMut(&T) -> io::Result<R>,
+    ) -> Poll<io::Result<R>> {
+        match op(&self.io) {
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                match self.source.poll_ready(cx, direction) {
+                    Poll::Ready(Err(error)) => Poll::Ready(Err(error)),
+                    _ => Poll::Pending,
+                }
+            }
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+impl<T: AsRawFd> Drop for Async<T> {
+    fn drop(&mut self) {
+        // Before `io` is dropped, and its file descriptor closed and maybe reused.
+        let _ = reactor().deregister(&self.source);
+    }
+}
+
+impl Async<TcpListener> {
+    /// Binds a listener to `addr`, as `TcpListener::bind` does.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::new(TcpListener::bind(addr)?)
+    }
+
+    pub fn new(listener: TcpListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Self::register(listener)
+    }
+
+    /// Waits for the next connection.
+    pub async fn accept(&self) -> io::Result<(Async<TcpStream>, SocketAddr)> {
+        let (stream, peer) = poll_fn(|cx| self.poll_io(cx, Direction::Read, TcpListener::accept)).await?;
+        Ok((Async::<TcpStream>::new(stream)?, peer))
+    }
+}
+
+impl Async<TcpStream> {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Self::register(stream)
+    }
+}
+
+impl AsyncRead for Async<TcpStream> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.poll_io(cx, Direction::Read, |mut stream| stream.read(buf))
+    }
+}
+
+impl AsyncWrite for Async<TcpStream> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.poll_io(cx, Direction::Write, |mut stream| stream.write(buf))
+    }
+
+    // TCP sockets have no user-space buffer to flush.
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.io.shutdown(Shutdown::Write))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        executor::block_on,
+        io::{AsyncReadExt, AsyncWriteExt},
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn tasks_wait_for_sockets_to_become_ready() {
+        let listener = Async::<TcpListener>::bind("127.0.0.1:0").unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+        // Takes its time, so the server's accept and read both have to wait for the reactor.
+        let client = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let mut stream = TcpStream::connect(addr).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            stream.write_all(b"ping").unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).unwrap();
+            reply
+        });
+
+        block_on(async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"ping");
+            stream.write_all(b"pong").await.unwrap();
+            stream.close().await.unwrap();
+        });
+        assert_eq!(client.join().unwrap(), b"pong");
+    }
+}
@@ -0,0 +1,335 @@
+// Ordering bugs are hard to chase because the order tasks are polled in changes from run to
+// run: a worker pool polls on several threads, and timers fire a little early or late. By the
+// time a bug is noticed the order that caused it is gone.
+//
+// A `Recorder` keeps that order. An executor tells it each time a task is spawned, woken and
+// polled, and the resulting `Schedule` can be written to a file. Later, `ReplayExecutor` runs
+// the same program on one thread, polling tasks in exactly the recorded order, so the bug
+// happens again every time and can be stepped through.
+//
+// Tasks are named by the order they were spawned in, counting from zero in each executor, so
+// a replay spawns the same tasks in the same order as long as the program spawns them only
+// from inside its tasks, or before it starts the executor.
+
+use std::{
+    fmt, fs,
+    future::Future,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+    task::{Context, Wake, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{BoxFuture, FutureExt},
+    task::{FutureObj, Spawn, SpawnError},
+};
+
+use crate::coop;
+
+/// How long `ReplayExecutor::run` waits for the next task in the schedule to be woken, e.g. by
+/// a timer, before deciding the program has gone a different way.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something that happened to a task, identified by its spawn order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Spawned(usize),
+    Woken(usize),
+    Polled(usize),
+}
+
+/// The events of one run, in the order they happened.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Schedule {
+    events: Vec<Event>,
+}
+
+impl Schedule {
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Write the schedule to `path`, one event per line, e.g. `polled 3`.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    /// Read a schedule written by `save`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(text: &str) -> io::Result<Self> {
+        let invalid = |line: &str| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("bad schedule line: {:?}", line))
+        };
+        let events = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let (kind, task) = line.trim().split_once(' ').ok_or_else(|| invalid(line))?;
+                let task = task.parse().map_err(|_| invalid(line))?;
+                match kind {
+                    "spawned" => Ok(Event::Spawned(task)),
+                    "woken" => Ok(Event::Woken(task)),
+                    "polled" => Ok(Event::Polled(task)),
+                    _ => Err(invalid(line)),
+                }
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Schedule { events })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for event in &self.events {
+            match event {
+                Event::Spawned(task) => writeln!(f, "spawned {}", task)?,
+                Event::Woken(task) => writeln!(f, "woken {}", task)?,
+                Event::Polled(task) => writeln!(f, "polled {}", task)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Collects the events an executor reports, from any number of threads.
+#[derive(Debug, Default)]
+pub struct Recorder {
+    events: Mutex<Vec<Event>>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder::default()
+    }
+
+    pub fn record(&self, event: Event) {
+        self.events.lock().unwrap().push(event);
+    }
+
+    /// The events recorded so far.
+    pub fn schedule(&self) -> Schedule {
+        Schedule {
+            events: self.events.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Why a replay couldn't follow its schedule: the program did something different this time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The schedule polls a task the program hasn't spawned.
+    NotSpawned { step: usize, task: usize },
+    /// The schedule polls a task which has already completed.
+    Completed { step: usize, task: usize },
+    /// The task due to be polled wasn't woken in time.
+    NotWoken { step: usize, task: usize },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::NotSpawned { step, task } => {
+                write!(f, "poll {}: task {} was never spawned", step, task)
+            }
+            ReplayError::Completed { step, task } => {
+                write!(f, "poll {}: task {} has already completed", step, task)
+            }
+            ReplayError::NotWoken { step, task } => {
+                write!(f, "poll {}: task {} was not woken", step, task)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Runs tasks on the current thread in the order of a recorded `Schedule`.
+pub struct ReplayExecutor {
+    schedule: Schedule,
+    shared: Arc<Shared>,
+}
+
+/// Spawns tasks onto a `ReplayExecutor`.
+#[derive(Clone)]
+pub struct ReplaySpawner {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    /// Spawned tasks by spawn order. A slot is `None` while its task is being polled, and
+    /// after it has completed.
+    tasks: Mutex<Vec<Option<BoxFuture<'static, ()>>>>,
+    /// Whether each task has been woken (or spawned) since it was last polled.
+    woken: Mutex<Vec<bool>>,
+    /// The executor's thread, unparked whenever a task is woken.
+    thread: thread::Thread,
+}
+
+struct TaskWaker {
+    index: usize,
+    shared: Arc<Shared>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.shared.woken.lock().unwrap()[self.index] = true;
+        self.shared.thread.unpark();
+    }
+}
+
+impl ReplayExecutor {
+    /// Create an executor which follows `schedule` on the current thread.
+    pub fn new(schedule: Schedule) -> Self {
+        ReplayExecutor {
+            schedule,
+            shared: Arc::new(Shared {
+                tasks: Mutex::new(Vec::new()),
+                woken: Mutex::new(Vec::new()),
+                thread: thread::current(),
+            }),
+        }
+    }
+
+    pub fn spawner(&self) -> ReplaySpawner {
+        ReplaySpawner {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Poll tasks in the order the schedule polled them, then return. Each task is polled
+    /// only once it has been woken, as it was in the recording; tasks the schedule doesn't
+    /// poll again are left as they are.
+    pub fn run(&self) -> Result<(), ReplayError> {
+        let polls = self.schedule.events.iter().filter_map(|event| match event {
+            Event::Polled(task) => Some(*task),
+            _ => None,
+        });
+        for (step, task) in polls.enumerate() {
+            self.wait_until_woken(step, task)?;
+            self.poll_task(task);
+        }
+        Ok(())
+    }
+
+    fn wait_until_woken(&self, step: usize, task: usize) -> Result<(), ReplayError> {
+        let deadline = Instant::now() + WAKE_TIMEOUT;
+        loop {
+            match self.shared.tasks.lock().unwrap().get(task) {
+                None => return Err(ReplayError::NotSpawned { step, task }),
+                Some(None) => return Err(ReplayError::Completed { step, task }),
+                Some(Some(_)) => {}
+            }
+            if std::mem::take(&mut self.shared.woken.lock().unwrap()[task]) {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ReplayError::NotWoken { step, task });
+            }
+            // Parking can wake up spuriously, which just means checking again.
+            thread::park_timeout(deadline - now);
+        }
+    }
+
+    fn poll_task(&self, index: usize) {
+        // Take the future out while polling it, so it can spawn more tasks.
+        let mut future = self.shared.tasks.lock().unwrap()[index]
+            .take()
+            .expect("checked by wait_until_woken");
+        let waker = Waker::from(Arc::new(TaskWaker {
+            index,
+            shared: self.shared.clone(),
+        }));
+        let mut cx = Context::from_waker(&waker);
+        if coop::budget(|| future.as_mut().poll(&mut cx)).is_pending() {
+            self.shared.tasks.lock().unwrap()[index] = Some(future);
+        }
+    }
+}
+
+impl ReplaySpawner {
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        // Hold both locks, so the task is never seen in one list but not yet the other.
+        let mut woken = self.shared.woken.lock().unwrap();
+        let mut tasks = self.shared.tasks.lock().unwrap();
+        tasks.push(Some(future.boxed()));
+        // A new task is ready to be polled, just as if it had been woken.
+        woken.push(true);
+    }
+}
+
+// Lets a program written against `Spawn` run on either the executor it was recorded on or
+// this one.
+impl Spawn for ReplaySpawner {
+    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        self.spawn(future);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TimerFuture;
+    use futures::task::SpawnExt;
+
+    #[test]
+    fn schedules_survive_a_round_trip_through_a_file() {
+        let recorder = Recorder::new();
+        for event in [Event::Spawned(0), Event::Polled(0), Event::Woken(0), Event::Polled(0)] {
+            recorder.record(event);
+        }
+        let path = std::env::temp_dir().join(format!("schedule-{}.txt", std::process::id()));
+        recorder.schedule().save(&path).unwrap();
+        let loaded = Schedule::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, recorder.schedule());
+        assert!(Schedule::parse("polled three").is_err());
+    }
+
+    #[test]
+    fn replays_tasks_in_the_recorded_order() {
+        // Two tasks that would normally run in spawn order, replayed the other way round.
+        let schedule = Schedule {
+            events: vec![Event::Polled(1), Event::Polled(0)],
+        };
+        let executor = ReplayExecutor::new(schedule);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for name in ["first", "second"] {
+            let log = log.clone();
+            SpawnExt::spawn(&executor.spawner(), async move { log.lock().unwrap().push(name) })
+                .unwrap();
+        }
+
+        executor.run().unwrap();
+        assert_eq!(*log.lock().unwrap(), ["second", "first"]);
+    }
+
+    #[test]
+    fn replay_waits_for_wakes_and_reports_divergence() {
+        let schedule = Schedule {
+            events: vec![Event::Polled(0), Event::Polled(0), Event::Polled(0)],
+        };
+        let executor = ReplayExecutor::new(schedule);
+        executor.spawner().spawn(async {
+            TimerFuture::new(Duration::from_millis(10)).await;
+        });
+
+        // The second poll waits for the timer; by the third the task has completed.
+        assert_eq!(
+            executor.run(),
+            Err(ReplayError::Completed { step: 2, task: 0 })
+        );
+    }
+}
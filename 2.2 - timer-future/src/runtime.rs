@@ -0,0 +1,346 @@
+// `Runtime` puts an `Executor`, or a `WorkerPool` running it, behind one value, so a program
+// doesn't have to wire up the executor, its spawner and its threads itself.
+
+use std::{
+    future::Future,
+    sync::atomic::Ordering,
+    thread,
+    time::{Duration, Instant},
+};
+use crate::context;
+use crate::executor::{Executor, ExecutorMetrics, JoinHandle, Spawner};
+use crate::worker_pool::{WorkerMetrics, WorkerPool};
+
+/// An executor along with a `Spawner` for it: the one value a program needs to spawn
+/// tasks, wait for a result, and shut down.
+pub struct Runtime {
+    spawner: Spawner,
+    scheduler: Scheduler,
+}
+
+/// Which threads a `Runtime` polls its tasks on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flavor {
+    /// On the thread calling `block_on` or `shutdown`, with no threads of its own. Cheap to
+    /// create, and tasks run in a predictable order, which suits small tools and tests.
+    CurrentThread,
+    /// On a `WorkerPool` running in the background.
+    MultiThread,
+}
+
+enum Scheduler {
+    CurrentThread(Executor),
+    MultiThread {
+        pool: WorkerPool,
+        workers: Vec<thread::JoinHandle<()>>,
+    },
+}
+
+/// Configures a `Runtime`, see `Runtime::builder`.
+pub struct RuntimeBuilder {
+    flavor: Flavor,
+    worker_threads: usize,
+    thread_name: String,
+    slow_poll_threshold: Duration,
+    max_task_lifetime: Option<Duration>,
+    queue_capacity: Option<usize>,
+}
+
+impl RuntimeBuilder {
+    /// Defaults to `Flavor::MultiThread`.
+    pub fn flavor(mut self, flavor: Flavor) -> Self {
+        self.flavor = flavor;
+        self
+    }
+
+    /// How many threads poll tasks with `Flavor::MultiThread`. Defaults to the number of CPUs.
+    pub fn worker_threads(mut self, worker_threads: usize) -> Self {
+        self.worker_threads = worker_threads;
+        self
+    }
+
+    /// Worker threads are named `{name}-{index}`. Defaults to `runtime-worker`.
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// Polls taking at least this long are reported, as with
+    /// `ExecutorBuilder::slow_poll_threshold`. Defaults to 100ms.
+    pub fn slow_poll_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_poll_threshold = threshold;
+        self
+    }
+
+    /// Cancels tasks still running after `lifetime`, as with
+    /// `ExecutorBuilder::max_task_lifetime`. `block_on`'s future is exempt. Off by default.
+    pub fn max_task_lifetime(mut self, lifetime: Duration) -> Self {
+        self.max_task_lifetime = Some(lifetime);
+        self
+    }
+
+    /// How many new tasks can wait to be polled, as with `ExecutorBuilder::queue_capacity`.
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = Some(queue_capacity);
+        self
+    }
+
+    pub fn build(self) -> Runtime {
+        let mut builder = Executor::builder().slow_poll_threshold(self.slow_poll_threshold);
+        if let Some(lifetime) = self.max_task_lifetime {
+            builder = builder.max_task_lifetime(lifetime);
+        }
+        if let Some(queue_capacity) = self.queue_capacity {
+            builder = builder.queue_capacity(queue_capacity);
+        }
+        match self.flavor {
+            Flavor::CurrentThread => {
+                let (executor, spawner) = builder.build();
+                Runtime { spawner, scheduler: Scheduler::CurrentThread(executor) }
+            }
+            Flavor::MultiThread => {
+                let (pool, spawner) = builder
+                    .worker_threads(self.worker_threads)
+                    .thread_name(self.thread_name)
+                    .build_worker_pool();
+                let workers = pool.start();
+                Runtime { spawner, scheduler: Scheduler::MultiThread { pool, workers } }
+            }
+        }
+    }
+}
+
+impl Runtime {
+    pub fn builder() -> RuntimeBuilder {
+        RuntimeBuilder {
+            flavor: Flavor::MultiThread,
+            worker_threads: thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+            thread_name: "runtime-worker".to_owned(),
+            slow_poll_threshold: Duration::from_millis(100),
+            max_task_lifetime: None,
+            queue_capacity: None,
+        }
+    }
+
+    #[track_caller]
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> JoinHandle<T> {
+        self.spawner.spawn(future)
+    }
+
+    /// Runs `future` and blocks the current thread until it completes. With
+    /// `Flavor::CurrentThread`, this is when spawned tasks get to run.
+    pub fn block_on<T: Send + 'static>(&self, future: impl Future<Output = T> + Send + 'static) -> T {
+        // A worker blocked here can't run the task it is waiting for.
+        assert!(
+            !context::in_executor(),
+            "Runtime::block_on called from an executor thread, which would deadlock; \
+             `.await` the future instead"
+        );
+        match &self.scheduler {
+            Scheduler::CurrentThread(executor) => executor.block_on(future),
+            Scheduler::MultiThread { .. } => {
+                futures::executor::block_on(self.spawner.spawn_long_lived(future))
+            }
+        }
+    }
+
+    /// Stops accepting new tasks and waits for the spawned ones to complete, or until
+    /// `deadline` if one is given, then stops the workers. Returns whether every task
+    /// completed.
+    pub fn shutdown(mut self, deadline: Option<Instant>) -> bool {
+        self.stop(deadline)
+    }
+
+    /// Takes a snapshot of the runtime's task counters, including slow polls.
+    pub fn metrics(&self) -> ExecutorMetrics {
+        self.spawner.state.metrics()
+    }
+
+    /// Lists the pending tasks, as `Executor::dump` does.
+    pub fn dump(&self) -> String {
+        self.spawner.state.dump()
+    }
+
+    /// Each worker's counters with `Flavor::MultiThread`; empty with `Flavor::CurrentThread`,
+    /// which has no workers.
+    pub fn worker_metrics(&self) -> Vec<WorkerMetrics> {
+        match &self.scheduler {
+            Scheduler::CurrentThread(_) => Vec::new(),
+            Scheduler::MultiThread { pool, .. } => pool.worker_metrics(),
+        }
+    }
+
+    fn stop(&mut self, deadline: Option<Instant>) -> bool {
+        let (pool, workers) = match &mut self.scheduler {
+            Scheduler::CurrentThread(executor) => return executor.shutdown(deadline),
+            Scheduler::MultiThread { pool, workers } => (pool, workers),
+        };
+
+        let state = &self.spawner.state;
+        // `shutdown` has already stopped the workers and reported the leaks, and this is
+        // `Drop` coming after it.
+        if pool.stop.load(Ordering::SeqCst) {
+            return state.live_tasks.load(Ordering::SeqCst) == 0;
+        }
+        state.shut_down.store(true, Ordering::SeqCst);
+        // Waiting `spawn_async` calls can give up now.
+        state.capacity_waiters.lock().unwrap().notify_all();
+        // The workers are busy with the tasks, so just check on them now and then.
+        let finished = loop {
+            if state.live_tasks.load(Ordering::SeqCst) == 0 {
+                break true;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+
+        pool.stop.store(true, Ordering::SeqCst);
+        for worker in workers.drain(..) {
+            worker.join().expect("worker thread panicked");
+        }
+        state.report_leaks();
+        finished
+    }
+}
+
+impl Drop for Runtime {
+    // Dropping a runtime abandons its remaining tasks, but doesn't leave its workers running.
+    fn drop(&mut self) {
+        match self.scheduler {
+            // Nothing runs unless we run it, so there is nothing to stop.
+            Scheduler::CurrentThread(_) => {}
+            Scheduler::MultiThread { .. } => {
+                self.stop(Some(Instant::now()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Handle;
+    use crate::TimerFuture;
+    use std::sync::mpsc;
+
+    #[test]
+    fn runtime_runs_tasks_on_its_named_workers() {
+        let runtime = Runtime::builder()
+            .worker_threads(2)
+            .thread_name("test-runtime")
+            .build();
+
+        let name = runtime.block_on(async { thread::current().name().unwrap().to_owned() });
+        assert!(name.starts_with("test-runtime-"), "{}", name);
+        assert!(runtime.shutdown(None));
+    }
+
+    #[test]
+    fn runtime_reports_polls_over_its_threshold() {
+        for flavor in [Flavor::CurrentThread, Flavor::MultiThread] {
+            let runtime = Runtime::builder()
+                .flavor(flavor)
+                .worker_threads(1)
+                .slow_poll_threshold(Duration::from_millis(20))
+                .build();
+            runtime.block_on(async { thread::sleep(Duration::from_millis(30)) });
+            // A worker counts the slow poll after handing back the output, so wait for it
+            // to poll something else before looking.
+            runtime.block_on(async {});
+            assert_eq!(runtime.metrics().slow_polls, 1, "{:?}", flavor);
+            runtime.shutdown(None);
+        }
+    }
+
+    #[test]
+    fn runtime_block_on_runs_its_future_past_the_max_lifetime() {
+        for flavor in [Flavor::CurrentThread, Flavor::MultiThread] {
+            let runtime = Runtime::builder()
+                .flavor(flavor)
+                .worker_threads(1)
+                .max_task_lifetime(Duration::from_millis(20))
+                .build();
+            let output = runtime.block_on(async {
+                TimerFuture::new(Duration::from_millis(60)).await;
+                "done"
+            });
+            assert_eq!(output, "done", "{:?}", flavor);
+            assert_eq!(runtime.metrics().expired_tasks, 0, "{:?}", flavor);
+            assert!(runtime.shutdown(None));
+        }
+    }
+
+    #[test]
+    fn runtime_shutdown_waits_for_spawned_tasks() {
+        let runtime = Runtime::builder().worker_threads(1).build();
+        let (done_sender, done_receiver) = mpsc::channel();
+        runtime.spawn(async move {
+            TimerFuture::new(Duration::from_millis(10)).await;
+            done_sender.send(()).unwrap();
+        });
+
+        assert!(runtime.shutdown(None));
+        assert!(done_receiver.try_recv().is_ok());
+
+        let runtime = Runtime::builder().worker_threads(1).build();
+        runtime.spawn(TimerFuture::new(Duration::from_secs(3600)));
+        assert!(!runtime.shutdown(Some(Instant::now() + Duration::from_millis(10))));
+    }
+
+    #[test]
+    fn runtime_shutdown_wakes_a_spawn_async_waiting_for_room() {
+        let runtime = Runtime::builder().worker_threads(1).queue_capacity(1).build();
+        let spawner = runtime.block_on(async { Handle::current().spawner().clone() });
+
+        // Hold up the only worker, then fill the queue behind it.
+        let (started_sender, started_receiver) = mpsc::channel();
+        let (gate_sender, gate_receiver) = mpsc::channel::<()>();
+        runtime.spawn(async move {
+            started_sender.send(()).unwrap();
+            let _ = gate_receiver.recv();
+        });
+        started_receiver.recv().unwrap();
+        runtime.spawn(async {});
+
+        let (result_sender, result_receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let result = futures::executor::block_on(spawner.spawn_async(async {}));
+            result_sender.send(result.is_err()).unwrap();
+        });
+        // Let the worker go once it has been told to stop, so it never gets to the queued
+        // task and nothing makes room for the waiting spawn.
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(gate_sender);
+        });
+        thread::sleep(Duration::from_millis(10));
+        assert!(!runtime.shutdown(Some(Instant::now() + Duration::from_millis(10))));
+        assert_eq!(result_receiver.recv_timeout(Duration::from_secs(5)), Ok(true));
+    }
+
+    #[test]
+    fn current_thread_runtime_runs_tasks_in_order_on_the_caller() {
+        let runtime = Runtime::builder().flavor(Flavor::CurrentThread).build();
+        let caller = thread::current().id();
+        let (order_sender, order_receiver) = mpsc::channel();
+        for task in 0..3 {
+            let order_sender = order_sender.clone();
+            runtime.spawn(async move {
+                assert_eq!(thread::current().id(), caller);
+                order_sender.send(task).unwrap();
+            });
+        }
+        // Nothing has run yet; there are no other threads to run it.
+        assert!(order_receiver.try_recv().is_err());
+
+        runtime.block_on(async {});
+        assert!(runtime.shutdown(None));
+        drop(order_sender);
+        assert_eq!(order_receiver.iter().collect::<Vec<_>>(), [0, 1, 2]);
+    }
+}
@@ -0,0 +1,79 @@
+// Every executor in this crate wants `'static` futures, because a spawned task can outlive the
+// function which spawned it. A future that only needs to read a local `Vec` then has to be
+// given its own copy, or an `Arc` of it, even when the caller is about to wait for it anyway.
+//
+// `scope` makes that wait part of the API, as `std::thread::scope` does for threads. Futures
+// spawned on a `Scope` may borrow anything that outlives the call to `scope`, and `scope`
+// doesn't return until every one of them has completed, so the borrows can't dangle.
+//
+// The children run concurrently on the calling thread once the closure returns; they are
+// never handed to another thread, so they needn't be `Send` either. If the closure or a child
+// panics, the remaining children are dropped before the panic leaves `scope`.
+
+use std::{cell::RefCell, future::Future};
+
+use futures::{
+    executor::block_on,
+    future::{join_all, FutureExt, LocalBoxFuture},
+};
+
+/// Spawns futures which may borrow from the stack frame around `scope`.
+pub struct Scope<'env> {
+    children: RefCell<Vec<LocalBoxFuture<'env, ()>>>,
+}
+
+impl<'env> Scope<'env> {
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'env) {
+        self.children.borrow_mut().push(future.boxed_local());
+    }
+}
+
+/// Call `f` with a `Scope` to spawn futures on, then run them all to completion on this thread
+/// before returning `f`'s output.
+pub fn scope<'env, T>(f: impl FnOnce(&Scope<'env>) -> T) -> T {
+    let scope = Scope {
+        children: RefCell::new(Vec::new()),
+    };
+    let output = f(&scope);
+    block_on(join_all(scope.children.into_inner()));
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TimerFuture;
+    use std::{sync::Mutex, time::Duration};
+
+    #[test]
+    fn children_borrow_from_the_enclosing_frame() {
+        let words = vec!["hello", "scoped", "world"];
+        let lengths = Mutex::new(Vec::new());
+
+        let spawned = scope(|s| {
+            for word in &words {
+                let lengths = &lengths;
+                s.spawn(async move { lengths.lock().unwrap().push(word.len()) });
+            }
+            words.len()
+        });
+
+        assert_eq!(spawned, 3);
+        assert_eq!(lengths.into_inner().unwrap(), [5, 6, 5]);
+    }
+
+    #[test]
+    fn children_run_concurrently_and_all_finish() {
+        let log = Mutex::new(Vec::new());
+        scope(|s| {
+            for (name, millis) in [("slow", 30), ("fast", 10)] {
+                let log = &log;
+                s.spawn(async move {
+                    TimerFuture::new(Duration::from_millis(millis)).await;
+                    log.lock().unwrap().push(name);
+                });
+            }
+        });
+        assert_eq!(log.into_inner().unwrap(), ["fast", "slow"]);
+    }
+}
@@ -0,0 +1,225 @@
+// `core_executor` still allocates: every spawn boxes the future and puts the task in an `Arc`.
+// Small embedded targets often have no heap at all, so executors like embassy reserve storage
+// for every task up front, in a `static`. The number of tasks and the space each one may use
+// are fixed at compile time, and spawning just moves a future into a free slot.
+//
+// Because a slot lives for `'static`, its waker doesn't need reference counting either:
+// cloning a waker copies the pointer to the slot, and dropping it does nothing.
+
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    mem::{self, MaybeUninit},
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+/// Declare a `static` executor with room for `tasks` futures of up to `task_size` bytes each.
+///
+/// ```ignore
+/// static_executor!(static EXECUTOR: tasks = 4, task_size = 256);
+///
+/// EXECUTOR.spawn(async { /* ... */ }).ok().expect("no free task slot");
+/// EXECUTOR.run();
+/// ```
+#[macro_export]
+macro_rules! static_executor {
+    ($vis:vis static $name:ident: tasks = $tasks:expr, task_size = $size:expr) => {
+        $vis static $name: $crate::static_executor::StaticExecutor<$tasks, $size> =
+            $crate::static_executor::StaticExecutor::new();
+    };
+}
+
+/// Alignment of every slot. Futures needing more than this can't be spawned.
+const SLOT_ALIGN: usize = 16;
+
+#[repr(align(16))]
+struct Storage<const SIZE: usize>([MaybeUninit<u8>; SIZE]);
+
+struct Slot<const SIZE: usize> {
+    /// Set while the slot holds a future.
+    occupied: AtomicBool,
+    /// Set by the slot's waker; the executor polls the slot when it sees this.
+    woken: AtomicBool,
+    storage: UnsafeCell<Storage<SIZE>>,
+    /// Polls the future in `storage`, dropping it in place once it completes.
+    /// Written by `spawn` before `woken` is first set.
+    poll: UnsafeCell<unsafe fn(*mut u8, &mut Context<'_>) -> Poll<()>>,
+}
+
+impl<const SIZE: usize> Slot<SIZE> {
+    const fn new() -> Self {
+        Slot {
+            occupied: AtomicBool::new(false),
+            woken: AtomicBool::new(false),
+            storage: UnsafeCell::new(Storage([MaybeUninit::uninit(); SIZE])),
+            poll: UnsafeCell::new(poll_nothing),
+        }
+    }
+}
+
+unsafe fn poll_nothing(_: *mut u8, _: &mut Context<'_>) -> Poll<()> {
+    Poll::Ready(())
+}
+
+unsafe fn poll_future<F: Future<Output = ()>>(storage: *mut u8, cx: &mut Context<'_>) -> Poll<()> {
+    let future = storage as *mut F;
+    // The future never moves out of its slot while it's alive, so pinning it there is fine.
+    let poll = Pin::new_unchecked(&mut *future).poll(cx);
+    if poll.is_ready() {
+        ptr::drop_in_place(future);
+    }
+    poll
+}
+
+// The waker's data pointer is the slot's `woken` flag, which lives for `'static`.
+const WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(waker_clone, waker_wake, waker_wake, waker_drop);
+
+unsafe fn waker_clone(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &WAKER_VTABLE)
+}
+
+unsafe fn waker_wake(data: *const ()) {
+    (*(data as *const AtomicBool)).store(true, Ordering::Release);
+}
+
+unsafe fn waker_drop(_: *const ()) {}
+
+/// An executor whose task storage is allocated statically; see `static_executor!`.
+pub struct StaticExecutor<const TASKS: usize, const SIZE: usize> {
+    slots: [Slot<SIZE>; TASKS],
+    running: AtomicBool,
+}
+
+// Safety: a slot's storage is written by `spawn` only after claiming the slot through
+// `occupied`, and afterwards only touched by `run`, which refuses to run twice at once.
+unsafe impl<const TASKS: usize, const SIZE: usize> Sync for StaticExecutor<TASKS, SIZE> {}
+
+impl<const TASKS: usize, const SIZE: usize> StaticExecutor<TASKS, SIZE> {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        StaticExecutor {
+            slots: [const { Slot::new() }; TASKS],
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// Move `future` into a free slot. If every slot is taken, the future is handed back.
+    ///
+    /// Futures larger than the executor's task size fail to compile.
+    pub fn spawn<F>(&'static self, future: F) -> Result<(), F>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        const {
+            assert!(
+                mem::size_of::<F>() <= SIZE,
+                "future is larger than the task size"
+            );
+            assert!(
+                mem::align_of::<F>() <= SLOT_ALIGN,
+                "future needs a larger alignment"
+            );
+        }
+
+        let slot = self.slots.iter().find(|slot| {
+            slot.occupied
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        });
+        let slot = match slot {
+            Some(slot) => slot,
+            None => return Err(future),
+        };
+
+        // Safety: we just claimed this slot, and `run` ignores it until `woken` is set.
+        unsafe {
+            ptr::write((*slot.storage.get()).0.as_mut_ptr() as *mut F, future);
+            *slot.poll.get() = poll_future::<F>;
+        }
+        slot.woken.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Poll woken tasks until every spawned task has completed.
+    ///
+    /// There is nothing to park on without an operating system, so this spins while idle.
+    /// A real embedded executor would sleep until an interrupt (`WFE`) instead.
+    pub fn run(&'static self) {
+        assert!(
+            !self.running.swap(true, Ordering::AcqRel),
+            "StaticExecutor::run is already running"
+        );
+
+        loop {
+            let mut any_occupied = false;
+            for slot in &self.slots {
+                if !slot.occupied.load(Ordering::Acquire) {
+                    continue;
+                }
+                any_occupied = true;
+                if !slot.woken.swap(false, Ordering::AcqRel) {
+                    continue;
+                }
+
+                let raw_waker =
+                    RawWaker::new(&slot.woken as *const AtomicBool as *const (), &WAKER_VTABLE);
+                // Safety: the vtable functions only touch the `'static` flag.
+                let waker = unsafe { Waker::from_raw(raw_waker) };
+                let context = &mut Context::from_waker(&waker);
+
+                // Safety: `spawn` finished writing the slot before setting `woken`.
+                let completed = unsafe {
+                    let poll = *slot.poll.get();
+                    poll((*slot.storage.get()).0.as_mut_ptr() as *mut u8, context).is_ready()
+                };
+                if completed {
+                    slot.occupied.store(false, Ordering::Release);
+                }
+            }
+
+            if !any_occupied {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+
+        self.running.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::TimerFuture;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+    static_executor!(static EXECUTOR: tasks = 2, task_size = 256);
+
+    #[test]
+    fn runs_tasks_from_static_slots() {
+        for millis in [20, 10] {
+            let spawned = EXECUTOR.spawn(async move {
+                TimerFuture::new(Duration::from_millis(millis)).await;
+                FINISHED.fetch_add(1, Ordering::SeqCst);
+            });
+            assert!(spawned.is_ok());
+        }
+        // Both slots are taken.
+        assert!(EXECUTOR.spawn(async {}).is_err());
+
+        EXECUTOR.run();
+        assert_eq!(FINISHED.load(Ordering::SeqCst), 2);
+
+        // Completed tasks free their slots again.
+        assert!(EXECUTOR.spawn(async {}).is_ok());
+        EXECUTOR.run();
+    }
+}
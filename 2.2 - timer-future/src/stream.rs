@@ -0,0 +1,526 @@
+// A few stream combinators written by hand, to show how a `Stream` that drives other streams
+// has to handle wakers. The rule is the same as for futures: only return `Pending` if every
+// inner stream we polled also returned `Pending`, because those are the streams which have
+// registered our waker and will wake us up again.
+
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    pin::Pin,
+    task::{Context, Poll},
+    thread,
+    time::Duration,
+};
+
+use futures::stream::{FusedStream, Stream};
+
+use crate::{
+    channel::{self, Receiver},
+    timer::{ThreadTimer, Timer},
+};
+
+/// Interleave the items of two streams in the order they become ready.
+///
+/// The stream ends once both inputs have ended.
+pub fn merge<A, B>(a: A, b: B) -> Merge<A, B>
+where
+    A: Stream + Unpin,
+    B: Stream<Item = A::Item> + Unpin,
+{
+    Merge {
+        a: Some(a),
+        b: Some(b),
+        poll_b_first: false,
+    }
+}
+
+/// Stream returned by `merge`.
+pub struct Merge<A, B> {
+    // An input is set to `None` once it has ended, so it's never polled again.
+    a: Option<A>,
+    b: Option<B>,
+    // Alternate which input goes first, so a stream that is always ready can't starve the other.
+    poll_b_first: bool,
+}
+
+// Poll an optional input, clearing it once it has ended.
+fn poll_input<S: Stream + Unpin>(
+    input: &mut Option<S>,
+    cx: &mut Context<'_>,
+) -> Poll<Option<S::Item>> {
+    let item = match input {
+        Some(stream) => match Pin::new(stream).poll_next(cx) {
+            Poll::Ready(item) => item,
+            Poll::Pending => return Poll::Pending,
+        },
+        None => return Poll::Ready(None),
+    };
+    if item.is_none() {
+        *input = None;
+    }
+    Poll::Ready(item)
+}
+
+impl<A, B> Stream for Merge<A, B>
+where
+    A: Stream + Unpin,
+    B: Stream<Item = A::Item> + Unpin,
+{
+    type Item = A::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        this.poll_b_first = !this.poll_b_first;
+
+        // If the first input has an item, return it straight away. The second input hasn't
+        // been polled this time, but we'll be polled again for the next item anyway.
+        let first = if this.poll_b_first {
+            poll_input(&mut this.b, cx)
+        } else {
+            poll_input(&mut this.a, cx)
+        };
+        if let Poll::Ready(Some(item)) = first {
+            return Poll::Ready(Some(item));
+        }
+
+        let second = if this.poll_b_first {
+            poll_input(&mut this.a, cx)
+        } else {
+            poll_input(&mut this.b, cx)
+        };
+        match second {
+            Poll::Ready(Some(item)) => Poll::Ready(Some(item)),
+            Poll::Ready(None) if this.a.is_none() && this.b.is_none() => Poll::Ready(None),
+            // Either input which is still running has returned `Pending` and holds our waker.
+            _ => Poll::Pending,
+        }
+    }
+}
+
+/// Combine two streams, yielding the latest item of each every time either one produces
+/// a new item. Nothing is yielded until both streams have produced at least one item.
+///
+/// The stream ends once both inputs have ended, or as soon as one input ends without
+/// ever having produced an item.
+pub fn zip_latest<A, B>(a: A, b: B) -> ZipLatest<A, B>
+where
+    A: Stream + Unpin,
+    A::Item: Clone,
+    B: Stream + Unpin,
+    B::Item: Clone,
+{
+    ZipLatest {
+        a: Some(a),
+        b: Some(b),
+        latest_a: None,
+        latest_b: None,
+    }
+}
+
+/// Stream returned by `zip_latest`.
+pub struct ZipLatest<A: Stream, B: Stream> {
+    a: Option<A>,
+    b: Option<B>,
+    latest_a: Option<A::Item>,
+    latest_b: Option<B::Item>,
+}
+
+// The latest items are plain values which we never pin, and both inputs are `Unpin`.
+impl<A: Stream, B: Stream> Unpin for ZipLatest<A, B> {}
+
+impl<A, B> Stream for ZipLatest<A, B>
+where
+    A: Stream + Unpin,
+    A::Item: Clone,
+    B: Stream + Unpin,
+    B::Item: Clone,
+{
+    type Item = (A::Item, B::Item);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        // Poll both inputs every time, so each of them either yields an item or registers
+        // our waker. If both have a new item we fold them into a single combined item.
+        let mut updated = false;
+        if let Poll::Ready(Some(item)) = poll_input(&mut this.a, cx) {
+            this.latest_a = Some(item);
+            updated = true;
+        }
+        if let Poll::Ready(Some(item)) = poll_input(&mut this.b, cx) {
+            this.latest_b = Some(item);
+            updated = true;
+        }
+
+        match (&this.latest_a, &this.latest_b) {
+            (Some(a), Some(b)) if updated => return Poll::Ready(Some((a.clone(), b.clone()))),
+            _ => {}
+        }
+
+        let a_finished = this.a.is_none();
+        let b_finished = this.b.is_none();
+        let starved =
+            (a_finished && this.latest_a.is_none()) || (b_finished && this.latest_b.is_none());
+        if (a_finished && b_finished) || starved {
+            Poll::Ready(None)
+        } else if updated {
+            // One side produced its first item but the other hasn't yet. That side may have
+            // returned `Ready` without registering our waker, so schedule another poll.
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Group the items of `stream` into batches of up to `size` items. A batch is yielded as soon
+/// as it is full, or once `timeout` has elapsed since its first item arrived, whichever
+/// comes first. Any partial batch is yielded when the stream ends.
+///
+/// # Panics
+///
+/// Panics if `size` is 0.
+pub fn chunks_timeout<S>(stream: S, size: usize, timeout: Duration) -> ChunksTimeout<S, ThreadTimer>
+where
+    S: Stream + Unpin,
+{
+    ChunksTimeout::with_timer(stream, size, timeout, ThreadTimer)
+}
+
+/// Stream returned by `chunks_timeout`.
+pub struct ChunksTimeout<S: Stream, T: Timer> {
+    stream: Option<S>,
+    size: usize,
+    timeout: Duration,
+    timer: T,
+    batch: Vec<S::Item>,
+    // Started when the first item of a batch arrives, cleared when the batch is yielded.
+    deadline: Option<Pin<Box<T::Sleep>>>,
+}
+
+impl<S: Stream + Unpin, T: Timer> ChunksTimeout<S, T> {
+    /// Like `chunks_timeout`, but measures the timeout with the given `Timer`.
+    pub fn with_timer(stream: S, size: usize, timeout: Duration, timer: T) -> Self {
+        assert!(size > 0, "chunk size must be greater than 0");
+        ChunksTimeout {
+            stream: Some(stream),
+            size,
+            timeout,
+            timer,
+            batch: Vec::with_capacity(size),
+            deadline: None,
+        }
+    }
+
+    fn take_batch(&mut self) -> Vec<S::Item> {
+        self.deadline = None;
+        std::mem::replace(&mut self.batch, Vec::with_capacity(self.size))
+    }
+}
+
+// Buffered items and the timer are never pinned, and the sleep future is boxed.
+impl<S: Stream, T: Timer> Unpin for ChunksTimeout<S, T> {}
+
+impl<S: Stream + Unpin, T: Timer> Stream for ChunksTimeout<S, T> {
+    type Item = Vec<S::Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        // Pull as many items as are ready, until the batch is full.
+        loop {
+            match poll_input(&mut this.stream, cx) {
+                Poll::Ready(Some(item)) => {
+                    if this.batch.is_empty() {
+                        this.deadline = Some(Box::pin(this.timer.sleep(this.timeout)));
+                    }
+                    this.batch.push(item);
+                    if this.batch.len() == this.size {
+                        return Poll::Ready(Some(this.take_batch()));
+                    }
+                }
+                Poll::Ready(None) if this.batch.is_empty() => return Poll::Ready(None),
+                Poll::Ready(None) => return Poll::Ready(Some(this.take_batch())),
+                Poll::Pending => break,
+            }
+        }
+
+        // The stream has nothing for us right now; flush the partial batch if it's overdue.
+        let overdue = match &mut this.deadline {
+            Some(deadline) => deadline.as_mut().poll(cx).is_ready(),
+            None => false,
+        };
+        if overdue {
+            Poll::Ready(Some(this.take_batch()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Wait for the next item from any of `streams`, e.g. several channel receivers, and return
+/// it together with the index of the stream it came from. Returns `None` once every stream
+/// has ended.
+///
+/// Each call starts checking at a random stream, so a busy stream early in the slice can't
+/// starve the ones after it.
+pub fn select_recv<S>(streams: &mut [S]) -> SelectRecv<'_, S>
+where
+    S: FusedStream + Unpin,
+{
+    let start = match streams.len() {
+        0 => 0,
+        len => (random_seed() % len as u64) as usize,
+    };
+    SelectRecv { streams, start }
+}
+
+// `RandomState` is seeded randomly by the standard library, which is plenty for picking
+// a starting point without depending on a random number crate.
+fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Future returned by `select_recv`.
+pub struct SelectRecv<'a, S> {
+    streams: &'a mut [S],
+    start: usize,
+}
+
+impl<S: FusedStream + Unpin> Future for SelectRecv<'_, S> {
+    type Output = Option<(usize, S::Item)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let len = this.streams.len();
+        let mut any_pending = false;
+
+        for offset in 0..len {
+            let index = (this.start + offset) % len;
+            let stream = &mut this.streams[index];
+            if stream.is_terminated() {
+                continue;
+            }
+            match Pin::new(stream).poll_next(cx) {
+                Poll::Ready(Some(item)) => return Poll::Ready(Some((index, item))),
+                Poll::Ready(None) => {}
+                Poll::Pending => any_pending = true,
+            }
+        }
+
+        if any_pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(None)
+        }
+    }
+}
+
+/// Turn a blocking iterator (reading a directory, parsing lines of a file, ...) into a stream.
+///
+/// Calling `next` on such an iterator inside a task would block the executor thread, so the
+/// iterator runs on a thread of its own, like `TimerFuture`'s timers. Up to `prefetch` items
+/// are read ahead of the consumer. Dropping the stream stops the thread after its current item.
+///
+/// # Panics
+///
+/// Panics if `prefetch` is 0.
+pub fn from_blocking_iter<I>(iter: I, prefetch: usize) -> Receiver<I::Item>
+where
+    I: IntoIterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+    let (sender, receiver) = channel::channel(prefetch);
+    thread::spawn(move || {
+        for item in iter {
+            // This thread isn't an executor thread, so it's fine to block on the send.
+            if futures::executor::block_on(sender.send(item)).is_err() {
+                // The stream has been dropped; nobody wants the rest.
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, stream::StreamExt};
+
+    // A tiny xorshift generator, so the tests are randomized but reproducible without
+    // pulling in a dependency.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    /// A stream over `items` which randomly returns `Pending` (waking itself straight away)
+    /// before each item, to simulate inputs becoming ready at arbitrary times.
+    struct Jittery {
+        items: std::vec::IntoIter<u64>,
+        rng: Rng,
+    }
+
+    impl Jittery {
+        fn new(items: Vec<u64>, seed: u64) -> Self {
+            Jittery {
+                items: items.into_iter(),
+                rng: Rng(seed),
+            }
+        }
+    }
+
+    impl Stream for Jittery {
+        type Item = u64;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<u64>> {
+            if self.rng.next().is_multiple_of(3) {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(self.items.next())
+        }
+    }
+
+    #[test]
+    fn merge_keeps_every_item_and_per_input_order() {
+        for seed in 1..200u64 {
+            let mut rng = Rng(seed);
+            let a: Vec<u64> = (0..rng.next() % 20).collect();
+            let b: Vec<u64> = (100..100 + rng.next() % 20).collect();
+
+            let merged: Vec<u64> = block_on(
+                merge(
+                    Jittery::new(a.clone(), seed * 3 + 1),
+                    Jittery::new(b.clone(), seed * 7 + 1),
+                )
+                .collect(),
+            );
+
+            let from_a: Vec<u64> = merged.iter().copied().filter(|x| *x < 100).collect();
+            let from_b: Vec<u64> = merged.iter().copied().filter(|x| *x >= 100).collect();
+            assert_eq!(from_a, a, "seed {}", seed);
+            assert_eq!(from_b, b, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn zip_latest_matches_reference_behavior() {
+        for seed in 1..200u64 {
+            let mut rng = Rng(seed);
+            let a: Vec<u64> = (0..rng.next() % 10).collect();
+            let b: Vec<u64> = (0..rng.next() % 10).collect();
+
+            let zipped: Vec<(u64, u64)> = block_on(
+                zip_latest(
+                    Jittery::new(a.clone(), seed * 3 + 1),
+                    Jittery::new(b.clone(), seed * 7 + 1),
+                )
+                .collect(),
+            );
+
+            if a.is_empty() || b.is_empty() {
+                assert!(zipped.is_empty(), "seed {}", seed);
+                continue;
+            }
+            // Each output moves at least one side forward, and neither side goes back.
+            for pair in zipped.windows(2) {
+                let ((a0, b0), (a1, b1)) = (pair[0], pair[1]);
+                assert!(
+                    a1 >= a0 && b1 >= b0 && (a1, b1) != (a0, b0),
+                    "seed {}",
+                    seed
+                );
+            }
+            // The last output pairs the last item of each input.
+            assert_eq!(
+                zipped.last(),
+                Some(&(*a.last().unwrap(), *b.last().unwrap())),
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn chunks_timeout_yields_full_batches_and_the_remainder() {
+        let chunks: Vec<Vec<u64>> = block_on(
+            chunks_timeout(futures::stream::iter(0..7), 3, Duration::from_secs(10)).collect(),
+        );
+        assert_eq!(chunks, vec![vec![0, 1, 2], vec![3, 4, 5], vec![6]]);
+    }
+
+    #[test]
+    fn chunks_timeout_flushes_partial_batch_when_timer_fires() {
+        // Two items arrive straight away, the third only after a long pause.
+        let delays = vec![(0, 0), (1, 0), (2, 300)];
+        let stream = Box::pin(
+            futures::stream::iter(delays).then(|(item, delay)| async move {
+                crate::TimerFuture::new(Duration::from_millis(delay)).await;
+                item
+            }),
+        );
+
+        let chunks: Vec<Vec<u64>> =
+            block_on(chunks_timeout(stream, 3, Duration::from_millis(30)).collect());
+        assert_eq!(chunks, vec![vec![0, 1], vec![2]]);
+    }
+
+    #[test]
+    fn select_recv_is_fair_between_busy_streams() {
+        let mut streams = vec![
+            futures::stream::repeat(0).fuse(),
+            futures::stream::repeat(1).fuse(),
+        ];
+        let mut counts = [0; 2];
+        for _ in 0..1000 {
+            let (index, item) = block_on(select_recv(&mut streams)).unwrap();
+            assert_eq!(index, item);
+            counts[index] += 1;
+        }
+        assert!(counts[0] > 300 && counts[1] > 300, "{:?}", counts);
+    }
+
+    #[test]
+    fn select_recv_drains_every_stream_then_ends() {
+        let mut streams = vec![
+            futures::stream::iter(vec![1, 2]).fuse(),
+            futures::stream::iter(vec![]).fuse(),
+            futures::stream::iter(vec![3]).fuse(),
+        ];
+        let mut received = Vec::new();
+        while let Some((_, item)) = block_on(select_recv(&mut streams)) {
+            received.push(item);
+        }
+        received.sort();
+        assert_eq!(received, vec![1, 2, 3]);
+        // Every stream has ended, so there is nothing left to wait for.
+        assert_eq!(block_on(select_recv(&mut streams)), None);
+    }
+
+    #[test]
+    fn from_blocking_iter_yields_every_item() {
+        let items: Vec<u32> = block_on(from_blocking_iter(0..10, 2).collect());
+        assert_eq!(items, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn from_blocking_iter_reads_a_directory() {
+        let entries = std::fs::read_dir("src").unwrap();
+        let names: Vec<String> = block_on(
+            from_blocking_iter(entries, 4)
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect(),
+        );
+        assert!(names.contains(&String::from("lib.rs")));
+    }
+}
@@ -0,0 +1,303 @@
+// `TimerFuture` is tied to one way of measuring time: a thread that sleeps and then wakes the task.
+// Code that only needs "wait for a while" shouldn't care how that happens, so we describe a timer
+// as a trait. Each runtime (our thread-per-timer driver, async-std, tokio, ...) can then provide
+// its own implementation, and helpers built on top of `Timer` work on any of them.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::stream::Stream;
+
+use crate::TimerFuture;
+
+/// A source of sleep futures.
+pub trait Timer: Clone {
+    /// The future returned by `sleep`.
+    type Sleep: Future<Output = ()> + Send + 'static;
+
+    /// Create a future which completes after `duration` has elapsed.
+    fn sleep(&self, duration: Duration) -> Self::Sleep;
+
+    /// The current time according to this timer. Only a fake clock needs to override it.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Create a future which completes once `deadline` has been reached.
+    /// A deadline in the past completes (almost) immediately.
+    fn sleep_until(&self, deadline: Instant) -> Self::Sleep {
+        self.sleep(deadline.saturating_duration_since(self.now()))
+    }
+
+    /// Create a stream which yields every `period`.
+    fn interval(&self, period: Duration) -> Interval<Self> {
+        Interval::new(self.clone(), period)
+    }
+}
+
+/// The timer driver used in this crate: every sleep spawns a thread running `TimerFuture`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadTimer;
+
+impl Timer for ThreadTimer {
+    type Sleep = TimerFuture;
+
+    fn sleep(&self, duration: Duration) -> Self::Sleep {
+        TimerFuture::new(duration)
+    }
+}
+
+/// Stream returned by `Timer::interval`.
+///
+/// Each tick is scheduled relative to the previous deadline rather than to the time the
+/// previous tick was observed, so a slow consumer doesn't make the interval drift.
+pub struct Interval<T: Timer> {
+    timer: T,
+    period: Duration,
+    next_deadline: Instant,
+    sleep: Pin<Box<T::Sleep>>,
+}
+
+impl<T: Timer> Interval<T> {
+    fn new(timer: T, period: Duration) -> Self {
+        let next_deadline = timer.now() + period;
+        let sleep = Box::pin(timer.sleep_until(next_deadline));
+        Interval {
+            timer,
+            period,
+            next_deadline,
+            sleep,
+        }
+    }
+}
+
+// The sleep future is already pinned on the heap and the timer itself is never pinned,
+// so `Interval` can be moved freely.
+impl<T: Timer> Unpin for Interval<T> {}
+
+impl<T: Timer> Stream for Interval<T> {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let tick = self.next_deadline;
+        self.next_deadline = tick + self.period;
+        let sleep = self.timer.sleep_until(self.next_deadline);
+        self.sleep = Box::pin(sleep);
+        Poll::Ready(Some(tick))
+    }
+}
+
+// `TimerFuture` never fires early: `thread::sleep` sleeps at least as long as asked. It can
+// fire late though, by however long the OS takes to reschedule the timer thread. Every
+// `TimerFuture` records how late it was here, so an application can see what accuracy to
+// expect, e.g. through the executor's metrics.
+
+static TIMERS_FIRED: AtomicUsize = AtomicUsize::new(0);
+static TOTAL_LATENESS_NANOS: AtomicU64 = AtomicU64::new(0);
+static MAX_LATENESS_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// How late `TimerFuture`s in this process have fired, from `accuracy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimerAccuracy {
+    /// Timers which have fired.
+    pub fired: usize,
+    /// Average time between a timer's deadline and it firing.
+    pub mean_lateness: Duration,
+    /// Longest time between a timer's deadline and it firing.
+    pub max_lateness: Duration,
+}
+
+/// Take a snapshot of how late timers have fired so far.
+pub fn accuracy() -> TimerAccuracy {
+    let fired = TIMERS_FIRED.load(Ordering::Relaxed);
+    let total = TOTAL_LATENESS_NANOS.load(Ordering::Relaxed);
+    TimerAccuracy {
+        fired,
+        mean_lateness: Duration::from_nanos(total.checked_div(fired as u64).unwrap_or(0)),
+        max_lateness: Duration::from_nanos(MAX_LATENESS_NANOS.load(Ordering::Relaxed)),
+    }
+}
+
+/// Called by a timer when it fires `lateness` after its deadline.
+pub(crate) fn record_lateness(lateness: Duration) {
+    let nanos = u64::try_from(lateness.as_nanos()).unwrap_or(u64::MAX);
+    TOTAL_LATENESS_NANOS.fetch_add(nanos, Ordering::Relaxed);
+    MAX_LATENESS_NANOS.fetch_max(nanos, Ordering::Relaxed);
+    TIMERS_FIRED.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::waker::CountingWaker;
+    use futures::{executor::block_on, stream::StreamExt};
+    use std::sync::{Arc, Mutex};
+
+    // A timer whose clock only moves when the test says so, which makes timing exact and
+    // the tests instant.
+    #[derive(Clone)]
+    struct MockTimer {
+        clock: Arc<Mutex<MockClock>>,
+    }
+
+    struct MockClock {
+        now: Instant,
+        sleeps: Vec<Arc<Mutex<MockSleepState>>>,
+    }
+
+    struct MockSleepState {
+        deadline: Instant,
+        fired_at: Option<Instant>,
+        waker: Option<std::task::Waker>,
+    }
+
+    struct MockSleep(Arc<Mutex<MockSleepState>>);
+
+    impl MockTimer {
+        fn new() -> Self {
+            MockTimer {
+                clock: Arc::new(Mutex::new(MockClock {
+                    now: Instant::now(),
+                    sleeps: Vec::new(),
+                })),
+            }
+        }
+
+        /// Move the clock forward, firing every sleep whose deadline has been reached.
+        fn advance(&self, by: Duration) {
+            let mut clock = self.clock.lock().unwrap();
+            clock.now += by;
+            let now = clock.now;
+            clock.sleeps.retain(|sleep| {
+                let mut sleep = sleep.lock().unwrap();
+                if sleep.deadline > now {
+                    return true;
+                }
+                sleep.fired_at = Some(now);
+                if let Some(waker) = sleep.waker.take() {
+                    waker.wake();
+                }
+                false
+            });
+        }
+    }
+
+    impl Timer for MockTimer {
+        type Sleep = MockSleep;
+
+        fn now(&self) -> Instant {
+            self.clock.lock().unwrap().now
+        }
+
+        fn sleep(&self, duration: Duration) -> MockSleep {
+            let mut clock = self.clock.lock().unwrap();
+            let state = Arc::new(Mutex::new(MockSleepState {
+                deadline: clock.now + duration,
+                fired_at: duration.is_zero().then_some(clock.now),
+                waker: None,
+            }));
+            if !duration.is_zero() {
+                clock.sleeps.push(state.clone());
+            }
+            MockSleep(state)
+        }
+    }
+
+    impl Future for MockSleep {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            let mut state = self.0.lock().unwrap();
+            match state.fired_at {
+                Some(fired_at) => {
+                    assert!(fired_at >= state.deadline, "sleep fired early");
+                    Poll::Ready(())
+                }
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sleep_does_not_fire_before_its_deadline() {
+        let timer = MockTimer::new();
+        let counter = CountingWaker::new();
+        let waker = counter.waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut sleep = timer.sleep(Duration::from_millis(10));
+
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending());
+        timer.advance(Duration::from_millis(9));
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending());
+        assert_eq!(counter.wake_count(), 0);
+
+        timer.advance(Duration::from_millis(1));
+        assert_eq!(counter.wake_count(), 1);
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn interval_does_not_drift_over_many_ticks() {
+        let timer = MockTimer::new();
+        let period = Duration::from_millis(10);
+        let start = timer.now();
+        let mut interval = timer.interval(period);
+        let counter = CountingWaker::new();
+        let waker = counter.waker();
+        let mut cx = Context::from_waker(&waker);
+
+        for tick in 1..=1_000u32 {
+            // The consumer gets round to each tick a little late, by a varying amount.
+            // The schedule must stay anchored to `start` regardless.
+            let late = Duration::from_micros(u64::from(tick % 7) * 300);
+            timer.advance(start + period * tick + late - timer.now());
+
+            match Pin::new(&mut interval).poll_next(&mut cx) {
+                Poll::Ready(Some(deadline)) => assert_eq!(deadline, start + period * tick),
+                _ => panic!("tick {} wasn't ready", tick),
+            }
+            assert!(Pin::new(&mut interval).poll_next(&mut cx).is_pending());
+        }
+    }
+
+    // Real time, so these take a few seconds and their margins depend on the machine.
+    // Run them with `cargo test -- --ignored`.
+
+    #[test]
+    #[ignore]
+    fn thread_timer_never_fires_early() {
+        for _ in 0..50 {
+            let started = Instant::now();
+            block_on(ThreadTimer.sleep(Duration::from_millis(10)));
+            assert!(started.elapsed() >= Duration::from_millis(10));
+        }
+        assert!(accuracy().fired >= 50);
+    }
+
+    #[test]
+    #[ignore]
+    fn thread_timer_interval_does_not_drift() {
+        let period = Duration::from_millis(10);
+        let ticks: Vec<Instant> =
+            block_on(ThreadTimer.interval(period).take(200).collect::<Vec<_>>());
+
+        for (i, tick) in ticks.iter().enumerate() {
+            assert_eq!(*tick - ticks[0], period * i as u32);
+        }
+        let last = *ticks.last().unwrap();
+        // The last tick is observed about as late as one timer is, not 200 times that.
+        assert!(Instant::now() - last < Duration::from_millis(50));
+    }
+}
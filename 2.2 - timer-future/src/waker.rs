@@ -0,0 +1,139 @@
+// `ArcWake` hides how a `Waker` is actually put together. Underneath, a `Waker` is just a
+// data pointer plus a table of four functions (clone, wake, wake_by_ref, drop) that the
+// executor supplies. This module builds two wakers by hand to show what `ArcWake` does for us:
+//
+// - a no-op waker, which is handy for polling a future once by hand, and
+// - a counting waker, which records how many times it was woken so tests can assert on it.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{RawWaker, RawWakerVTable, Waker},
+};
+
+// The no-op waker has no data at all, so every function ignores the pointer.
+const NOOP_VTABLE: RawWakerVTable = RawWakerVTable::new(noop_clone, noop, noop, noop);
+
+unsafe fn noop_clone(_: *const ()) -> RawWaker {
+    RawWaker::new(std::ptr::null(), &NOOP_VTABLE)
+}
+
+unsafe fn noop(_: *const ()) {}
+
+/// Create a `Waker` that does nothing when woken.
+pub fn noop_waker() -> Waker {
+    // Safety: the vtable functions never touch the data pointer.
+    unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &NOOP_VTABLE)) }
+}
+
+/// A waker which counts how many times it has been woken.
+///
+/// Cloning the `Waker` returned by `waker` shares the same counter, just like cloning
+/// an `ArcWake` waker shares the same task.
+#[derive(Clone, Debug, Default)]
+pub struct CountingWaker {
+    count: Arc<AtomicUsize>,
+}
+
+// The data pointer of a counting waker is an `Arc<AtomicUsize>` turned into a raw pointer
+// with `Arc::into_raw`. Each `RawWaker` owns one strong reference.
+const COUNTING_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    counting_clone,
+    counting_wake,
+    counting_wake_by_ref,
+    counting_drop,
+);
+
+unsafe fn counting_clone(data: *const ()) -> RawWaker {
+    // Bump the reference count for the new `RawWaker` without taking ownership of ours.
+    Arc::increment_strong_count(data as *const AtomicUsize);
+    RawWaker::new(data, &COUNTING_VTABLE)
+}
+
+unsafe fn counting_wake(data: *const ()) {
+    // `wake` consumes the waker, so take back ownership of its reference and let it drop.
+    let count = Arc::from_raw(data as *const AtomicUsize);
+    count.fetch_add(1, Ordering::SeqCst);
+}
+
+unsafe fn counting_wake_by_ref(data: *const ()) {
+    // `wake_by_ref` borrows the waker, so the reference count must stay untouched.
+    let count = &*(data as *const AtomicUsize);
+    count.fetch_add(1, Ordering::SeqCst);
+}
+
+unsafe fn counting_drop(data: *const ()) {
+    drop(Arc::from_raw(data as *const AtomicUsize));
+}
+
+impl CountingWaker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a `Waker` which increments this counter when woken.
+    pub fn waker(&self) -> Waker {
+        let data = Arc::into_raw(self.count.clone()) as *const ();
+        // Safety: the vtable functions treat `data` as the `Arc<AtomicUsize>` created above
+        // and keep its reference count balanced.
+        unsafe { Waker::from_raw(RawWaker::new(data, &COUNTING_VTABLE)) }
+    }
+
+    /// Number of times any waker created from this counter has been woken.
+    pub fn wake_count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TimerFuture;
+    use std::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn noop_waker_can_poll_a_ready_future() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(async { 42 });
+        assert_eq!(future.as_mut().poll(&mut cx), Poll::Ready(42));
+    }
+
+    #[test]
+    fn counting_waker_counts_wake_and_wake_by_ref() {
+        let counter = CountingWaker::new();
+        let waker = counter.waker();
+
+        waker.wake_by_ref();
+        let cloned = waker.clone();
+        cloned.wake();
+        waker.wake();
+
+        assert_eq!(counter.wake_count(), 3);
+        // All wakers have been consumed, so only the counter's own reference is left.
+        assert_eq!(Arc::strong_count(&counter.count), 1);
+    }
+
+    #[test]
+    fn timer_future_wakes_the_task_once() {
+        let counter = CountingWaker::new();
+        let waker = counter.waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut timer = TimerFuture::new(Duration::from_millis(10));
+
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_pending());
+        while counter.wake_count() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_ready());
+        assert_eq!(counter.wake_count(), 1);
+    }
+}
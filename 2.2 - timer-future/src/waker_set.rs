@@ -0,0 +1,188 @@
+// A set of wakers for tasks waiting on the same thing, such as the receivers of a channel
+// waiting for a message.
+//
+// Keeping a `Vec<Waker>` means searching it on every poll to avoid adding the same task twice.
+// Here each waiting future is handed a key the first time it registers, and passes that key
+// back on later polls, which replaces its waker in place. Entries live in a slab (a `Vec` with
+// a list of free slots, so a key stays valid while others come and go), and a bitset marks the
+// entries currently holding a waker, so waking skips everything else a word at a time.
+//
+// A woken entry keeps its slot until its future removes it, so the key can't be handed to
+// another future while the old one might still use it.
+
+use std::task::Waker;
+
+enum Entry {
+    /// Free, holding the index of the next free entry.
+    Vacant(usize),
+    /// Owned by a waiting future; `None` once it has been woken.
+    Occupied(Option<Waker>),
+}
+
+/// Wakers of tasks waiting on the same event, see the module documentation.
+#[derive(Default)]
+pub struct WakerSet {
+    entries: Vec<Entry>,
+    /// Head of the free list threaded through `Entry::Vacant`; `entries.len()` when empty.
+    next_vacant: usize,
+    /// Bit `i` is set when `entries[i]` holds a waker.
+    waiting: Vec<u64>,
+}
+
+impl WakerSet {
+    pub fn new() -> Self {
+        WakerSet::default()
+    }
+
+    /// Store `waker` for the future owning `key`. A future passes `&mut None` the first time,
+    /// which is replaced by its key, and the same key every time after.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` has already been removed.
+    pub fn register(&mut self, key: &mut Option<usize>, waker: &Waker) {
+        let index = match *key {
+            Some(index) => index,
+            None => {
+                let index = self.insert();
+                *key = Some(index);
+                index
+            }
+        };
+        match &mut self.entries[index] {
+            Entry::Occupied(Some(existing)) if existing.will_wake(waker) => {}
+            Entry::Occupied(slot) => *slot = Some(waker.clone()),
+            Entry::Vacant(_) => panic!("WakerSet key used after it was removed"),
+        }
+        self.waiting[index / 64] |= 1 << (index % 64);
+    }
+
+    /// Free the entry for `key`, once its future has finished waiting or is dropped.
+    /// Returns whether it had been woken and not registered again since, in which case the
+    /// caller may want to pass the wake-up on to someone else.
+    pub fn remove(&mut self, key: usize) -> bool {
+        let entry = std::mem::replace(&mut self.entries[key], Entry::Vacant(self.next_vacant));
+        self.next_vacant = key;
+        self.waiting[key / 64] &= !(1 << (key % 64));
+        match entry {
+            Entry::Occupied(waker) => waker.is_none(),
+            Entry::Vacant(_) => panic!("WakerSet key removed twice"),
+        }
+    }
+
+    /// Wake every waiting task. Returns whether there were any.
+    pub fn notify_all(&mut self) -> bool {
+        let mut woke = false;
+        for (word_index, word) in self.waiting.iter_mut().enumerate() {
+            while *word != 0 {
+                let index = word_index * 64 + word.trailing_zeros() as usize;
+                // Clear the lowest set bit.
+                *word &= *word - 1;
+                if let Entry::Occupied(waker) = &mut self.entries[index] {
+                    waker.take().expect("waiting bit set without a waker").wake();
+                    woke = true;
+                }
+            }
+        }
+        woke
+    }
+
+    /// Wake one waiting task, the one with the lowest key. Returns whether there was one.
+    pub fn notify_one(&mut self) -> bool {
+        let word_index = match self.waiting.iter().position(|&word| word != 0) {
+            Some(word_index) => word_index,
+            None => return false,
+        };
+        let word = &mut self.waiting[word_index];
+        let index = word_index * 64 + word.trailing_zeros() as usize;
+        *word &= *word - 1;
+        if let Entry::Occupied(waker) = &mut self.entries[index] {
+            waker.take().expect("waiting bit set without a waker").wake();
+        }
+        true
+    }
+
+    /// Whether any task is waiting to be woken.
+    pub fn is_empty(&self) -> bool {
+        self.waiting.iter().all(|&word| word == 0)
+    }
+
+    fn insert(&mut self) -> usize {
+        let index = self.next_vacant;
+        if index == self.entries.len() {
+            self.entries.push(Entry::Occupied(None));
+            self.next_vacant = self.entries.len();
+            if self.waiting.len() * 64 < self.entries.len() {
+                self.waiting.push(0);
+            }
+        } else {
+            match std::mem::replace(&mut self.entries[index], Entry::Occupied(None)) {
+                Entry::Vacant(next) => self.next_vacant = next,
+                Entry::Occupied(_) => unreachable!("free list points at an occupied entry"),
+            }
+        }
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::waker::CountingWaker;
+
+    #[test]
+    fn registering_again_replaces_the_waker() {
+        let mut set = WakerSet::new();
+        let first = CountingWaker::new();
+        let second = CountingWaker::new();
+        let mut key = None;
+
+        set.register(&mut key, &first.waker());
+        set.register(&mut key, &first.waker());
+        set.register(&mut key, &second.waker());
+        assert!(set.notify_all());
+
+        assert_eq!(first.wake_count(), 0);
+        assert_eq!(second.wake_count(), 1);
+        assert!(set.is_empty());
+        assert!(!set.notify_all());
+    }
+
+    #[test]
+    fn notify_one_wakes_a_single_task() {
+        let mut set = WakerSet::new();
+        let wakers: Vec<_> = (0..100).map(|_| CountingWaker::new()).collect();
+        let mut keys = vec![None; wakers.len()];
+        for (key, waker) in keys.iter_mut().zip(&wakers) {
+            set.register(key, &waker.waker());
+        }
+
+        // Entries past the first word of the bitset are found too.
+        for key in keys.iter().take(70) {
+            assert!(!set.remove(key.unwrap()));
+        }
+        assert!(set.notify_one());
+        let woken: Vec<usize> = wakers.iter().map(|waker| waker.wake_count()).collect();
+        assert_eq!(woken.iter().sum::<usize>(), 1);
+        assert_eq!(woken[70], 1);
+
+        // The woken task is told so when it stops waiting.
+        assert!(set.remove(keys[70].unwrap()));
+        assert!(!set.remove(keys[71].unwrap()));
+    }
+
+    #[test]
+    fn removed_keys_are_reused() {
+        let mut set = WakerSet::new();
+        let waker = CountingWaker::new();
+        let (mut a, mut b, mut c) = (None, None, None);
+        set.register(&mut a, &waker.waker());
+        set.register(&mut b, &waker.waker());
+        set.remove(a.unwrap());
+        set.register(&mut c, &waker.waker());
+
+        assert_eq!(c, a);
+        assert!(set.notify_all());
+        assert_eq!(waker.wake_count(), 2);
+    }
+}
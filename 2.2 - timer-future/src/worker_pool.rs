@@ -0,0 +1,391 @@
+// A pool of worker threads polling the tasks of one `Executor`, for futures that keep a CPU
+// busy between their `.await`s. `ExecutorBuilder::build_worker_pool` creates one.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    rc::Rc,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::mpsc::RecvTimeoutError,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use crate::context;
+use crate::executor::{Task, TaskReceiver};
+
+/// Task executor that runs tasks on a pool of worker threads, so CPU-bound futures
+/// are polled in parallel instead of serializing behind each other.
+///
+/// Each worker has its own run queue, which tasks woken or spawned on that worker go to.
+/// Tasks spawned or woken anywhere else go to the shared task channel, the global queue.
+/// A worker that runs out of work takes tasks from the global queue, and failing that
+/// steals half of a sibling's queue, so one busy worker doesn't leave the rest idle.
+///
+/// A worker whose own queue never empties, say because its tasks keep yielding, would never
+/// get round to the global queue, so every `GLOBAL_QUEUE_INTERVAL` polls it looks there
+/// first.
+pub struct WorkerPool {
+    /// Shared by the workers; whichever worker gets the lock takes the next task.
+    pub(crate) ready_queue: Arc<Mutex<TaskReceiver>>,
+    pub(crate) workers: usize,
+    /// Worker threads are named `{thread_name}-{index}`.
+    pub(crate) thread_name: String,
+    pub(crate) executor_id: usize,
+    /// Whether the workers have a LIFO slot.
+    pub(crate) lifo_slot: bool,
+    /// Tells the workers to exit even though the task channel is still open.
+    pub(crate) stop: Arc<AtomicBool>,
+    /// Each worker's run queue, by index.
+    pub(crate) local_queues: Arc<Vec<LocalQueue>>,
+    /// Each worker's counters, by index.
+    pub(crate) counters: Arc<Vec<WorkerCounters>>,
+}
+
+/// Counters kept by each `WorkerPool` worker, behind `WorkerPool::worker_metrics`.
+#[derive(Default)]
+pub(crate) struct WorkerCounters {
+    pub(crate) polls: AtomicUsize,
+    pub(crate) lifo_polls: AtomicUsize,
+    pub(crate) steal_attempts: AtomicUsize,
+    pub(crate) steals: AtomicUsize,
+    pub(crate) parks: AtomicUsize,
+}
+
+/// A snapshot of one `WorkerPool` worker's counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WorkerMetrics {
+    /// Tasks waiting in the worker's local queue.
+    pub local_queue_depth: usize,
+    /// Times the worker polled a task.
+    pub polls: usize,
+    /// Polls of a task taken from the worker's LIFO slot, counted in `polls` too.
+    pub lifo_polls: usize,
+    /// Times the worker ran out of tasks and looked in its siblings' queues.
+    pub steal_attempts: usize,
+    /// Steal attempts which found tasks to take.
+    pub steals: usize,
+    /// Times the worker found nothing to do anywhere and waited on the task channel.
+    pub parks: usize,
+}
+
+/// A worker's run queue. Its owner pops from the front, thieves split off the back.
+pub(crate) type LocalQueue = Arc<Mutex<VecDeque<Arc<Task>>>>;
+
+/// Where a `WorkerPool` worker queues the tasks woken on its thread.
+pub(crate) struct WorkerQueues {
+    /// The worker's pool, so only that pool's tasks are queued here.
+    pub(crate) executor_id: usize,
+    pub(crate) local: LocalQueue,
+    /// The task most recently woken or spawned by the task being polled, which the worker
+    /// polls next, ahead of its queue. A task handing a message to another is usually
+    /// followed by the other one reading it, and polling that straight away saves it a trip
+    /// through the queue, while the message (and the rest of its state) is still in the
+    /// cache. A second wake pushes the first task into the queue. `None` without a slot.
+    ///
+    /// Only the worker itself uses the slot, so it isn't stolen from: a task in it waits if
+    /// the task being polled blocks. Two tasks waking each other could also keep it to
+    /// themselves, so the worker polls at most `MAX_LIFO_POLLS` in a row from it.
+    pub(crate) lifo_slot: Option<Cell<Option<Arc<Task>>>>,
+}
+
+/// Polls in a row a worker takes from its LIFO slot before turning to its queue.
+const MAX_LIFO_POLLS: usize = 3;
+
+/// Polls of local tasks after which a worker takes a task from the global queue first. Prime,
+/// so it doesn't fall into step with tasks doing something every so many polls.
+const GLOBAL_QUEUE_INTERVAL: usize = 61;
+
+thread_local! {
+    /// The queues of the `WorkerPool` worker on this thread.
+    pub(crate) static LOCAL_QUEUE: RefCell<Option<Rc<WorkerQueues>>> = const { RefCell::new(None) };
+}
+
+impl WorkerPool {
+    /// Runs tasks on `workers` threads until every `Spawner` and task has been dropped.
+    pub fn run(self) {
+        for worker in self.start() {
+            worker.join().expect("worker thread panicked");
+        }
+    }
+
+    /// Starts the worker threads, which run until every `Spawner` and task has been
+    /// dropped, or until `stop` is set.
+    pub fn start(&self) -> Vec<thread::JoinHandle<()>> {
+        (0..self.workers)
+            .map(|id| {
+                let ready_queue = self.ready_queue.clone();
+                let local_queues = self.local_queues.clone();
+                let all_counters = self.counters.clone();
+                let executor_id = self.executor_id;
+                let lifo_slot = self.lifo_slot;
+                let stop = self.stop.clone();
+                thread::Builder::new()
+                    .name(format!("{}-{}", self.thread_name, id))
+                    .spawn(move || {
+                        let _enter = context::enter();
+                        let counters = &all_counters[id];
+                        let local = local_queues[id].clone();
+                        let queues = Rc::new(WorkerQueues {
+                            executor_id,
+                            local: local.clone(),
+                            lifo_slot: lifo_slot.then(|| Cell::new(None)),
+                        });
+                        LOCAL_QUEUE.with(|queue| *queue.borrow_mut() = Some(queues.clone()));
+
+                        let mut lifo_polls = 0;
+                        let mut local_polls = 0;
+                        while !stop.load(Ordering::Relaxed) {
+                            if local_polls >= GLOBAL_QUEUE_INTERVAL {
+                                local_polls = 0;
+                                let task = ready_queue.lock().unwrap().try_recv();
+                                if let Ok(task) = task {
+                                    counters.polls.fetch_add(1, Ordering::Relaxed);
+                                    task.poll();
+                                    continue;
+                                }
+                            }
+
+                            if let Some(task) = queues.lifo_slot.as_ref().and_then(Cell::take) {
+                                if lifo_polls < MAX_LIFO_POLLS {
+                                    lifo_polls += 1;
+                                    local_polls += 1;
+                                    counters.polls.fetch_add(1, Ordering::Relaxed);
+                                    counters.lifo_polls.fetch_add(1, Ordering::Relaxed);
+                                    task.poll();
+                                    continue;
+                                }
+                                // Let the queue have a turn; the task keeps its place at the
+                                // back of it.
+                                local.lock().unwrap().push_back(task);
+                            }
+                            lifo_polls = 0;
+
+                            // Bind the popped task first, so the queue's lock is released
+                            // before polling and thieves aren't kept waiting.
+                            let task = local.lock().unwrap().pop_front();
+                            if let Some(task) = task {
+                                local_polls += 1;
+                                counters.polls.fetch_add(1, Ordering::Relaxed);
+                                task.poll();
+                                continue;
+                            }
+                            // The lock is released at the end of this statement, so other
+                            // workers can take tasks while this one polls.
+                            local_polls = 0;
+                            let task = ready_queue.lock().unwrap().try_recv();
+                            if let Ok(task) = task {
+                                counters.polls.fetch_add(1, Ordering::Relaxed);
+                                task.poll();
+                                continue;
+                            }
+                            counters.steal_attempts.fetch_add(1, Ordering::Relaxed);
+                            if steal(&local_queues, id) {
+                                counters.steals.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                            // Nothing to do anywhere. Wait on the task channel, but only
+                            // briefly, as work may turn up in a sibling's queue instead.
+                            counters.parks.fetch_add(1, Ordering::Relaxed);
+                            let task = ready_queue
+                                .lock()
+                                .unwrap()
+                                .recv_timeout(Duration::from_millis(1));
+                            match task {
+                                Ok(task) => {
+                                    counters.polls.fetch_add(1, Ordering::Relaxed);
+                                    task.poll();
+                                }
+                                Err(RecvTimeoutError::Timeout) => {}
+                                // Every task holds a sender, so no tasks are left anywhere.
+                                Err(RecvTimeoutError::Disconnected) => break,
+                            }
+                        }
+                    })
+                    .expect("failed to spawn worker thread")
+            })
+            .collect()
+    }
+
+    /// Takes a snapshot of each worker's counters, by worker index.
+    pub fn worker_metrics(&self) -> Vec<WorkerMetrics> {
+        self.counters
+            .iter()
+            .zip(self.local_queues.iter())
+            .map(|(counters, local_queue)| WorkerMetrics {
+                local_queue_depth: local_queue.lock().unwrap().len(),
+                polls: counters.polls.load(Ordering::Relaxed),
+                lifo_polls: counters.lifo_polls.load(Ordering::Relaxed),
+                steal_attempts: counters.steal_attempts.load(Ordering::Relaxed),
+                steals: counters.steals.load(Ordering::Relaxed),
+                parks: counters.parks.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// Moves half of the first non-empty sibling queue into worker `thief`'s queue.
+/// Returns whether anything was stolen.
+fn steal(local_queues: &[LocalQueue], thief: usize) -> bool {
+    let workers = local_queues.len();
+    for offset in 1..workers {
+        let victim = (thief + offset) % workers;
+        // Only hold one queue's lock at a time, so two workers stealing from each other
+        // can't deadlock.
+        let stolen = {
+            let mut queue = local_queues[victim].lock().unwrap();
+            let keep = queue.len() / 2;
+            queue.split_off(keep)
+        };
+        if !stolen.is_empty() {
+            local_queues[thief].lock().unwrap().extend(stolen);
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{coop, executor::Executor};
+    use std::sync::mpsc;
+
+    #[test]
+    fn worker_pool_polls_tasks_in_parallel() {
+        // Every task waits for all the others, which only finishes if they run at once.
+        const WORKERS: usize = 4;
+        let (pool, spawner) = Executor::builder().worker_threads(WORKERS).build_worker_pool();
+        let barrier = Arc::new(std::sync::Barrier::new(WORKERS));
+        let (done_sender, done_receiver) = mpsc::channel();
+        for _ in 0..WORKERS {
+            let barrier = barrier.clone();
+            let done_sender = done_sender.clone();
+            spawner.spawn(async move {
+                barrier.wait();
+                done_sender.send(()).unwrap();
+            });
+        }
+        drop(spawner);
+        drop(done_sender);
+
+        pool.run();
+        assert_eq!(done_receiver.iter().count(), WORKERS);
+    }
+
+    #[test]
+    fn worker_pool_steals_tasks_spawned_on_a_busy_worker() {
+        // Tasks spawned from inside a task start out on that worker's own queue, so
+        // they can only wait on each other at the barrier if the other workers steal them.
+        const WORKERS: usize = 4;
+        let (pool, spawner) = Executor::builder().worker_threads(WORKERS).build_worker_pool();
+        let (done_sender, done_receiver) = mpsc::channel();
+        let inner_spawner = spawner.clone();
+        spawner.spawn(async move {
+            let barrier = Arc::new(std::sync::Barrier::new(WORKERS));
+            for _ in 0..WORKERS {
+                let barrier = barrier.clone();
+                let done_sender = done_sender.clone();
+                inner_spawner.spawn(async move {
+                    barrier.wait();
+                    done_sender.send(thread::current().name().unwrap().to_owned()).unwrap();
+                });
+            }
+        });
+        drop(spawner);
+
+        for worker in pool.start() {
+            worker.join().unwrap();
+        }
+        let mut workers: Vec<String> = done_receiver.iter().collect();
+        workers.sort();
+        workers.dedup();
+        assert_eq!(workers.len(), WORKERS);
+
+        let metrics = pool.worker_metrics();
+        assert!(metrics.iter().any(|worker| worker.steals > 0), "{:?}", metrics);
+        assert!(metrics.iter().all(|worker| worker.steals <= worker.steal_attempts));
+        assert_eq!(metrics.iter().map(|worker| worker.polls).sum::<usize>(), WORKERS + 1);
+        assert!(metrics.iter().all(|worker| worker.local_queue_depth == 0));
+    }
+
+    #[test]
+    fn a_busy_worker_still_takes_tasks_from_the_global_queue() {
+        let (pool, spawner) = Executor::builder().worker_threads(1).build_worker_pool();
+        let stop_spinning = Arc::new(AtomicBool::new(false));
+        let (spinning_sender, spinning) = mpsc::channel();
+        let inner_spawner = spawner.clone();
+        let spin = stop_spinning.clone();
+        spawner.spawn(async move {
+            // Spawned on the worker, so it lives in the worker's own queue and never lets it
+            // run dry.
+            inner_spawner.spawn(async move {
+                spinning_sender.send(()).unwrap();
+                while !spin.load(Ordering::SeqCst) {
+                    coop::yield_now().await;
+                }
+            });
+        });
+        let workers = pool.start();
+        spinning.recv().unwrap();
+
+        // Spawned from this thread, so it goes to the global queue.
+        let (done_sender, done) = mpsc::channel();
+        spawner.spawn(async move {
+            stop_spinning.store(true, Ordering::SeqCst);
+            done_sender.send(()).unwrap();
+        });
+        drop(spawner);
+
+        let reached = done.recv_timeout(Duration::from_secs(5));
+        pool.stop.store(true, Ordering::SeqCst);
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert!(reached.is_ok(), "the global queue was starved");
+    }
+
+    #[test]
+    fn lifo_slot_polls_the_task_woken_last_first() {
+        for (lifo_slot, expected) in [(true, ["second", "first"]), (false, ["first", "second"])] {
+            let (pool, spawner) = Executor::builder()
+                .worker_threads(1)
+                .lifo_slot(lifo_slot)
+                .build_worker_pool();
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let inner_spawner = spawner.clone();
+            let inner_log = log.clone();
+            spawner.spawn(async move {
+                for name in ["first", "second"] {
+                    let log = inner_log.clone();
+                    inner_spawner.spawn(async move { log.lock().unwrap().push(name) });
+                }
+            });
+            drop(spawner);
+
+            let counters = pool.counters.clone();
+            pool.run();
+            assert_eq!(*log.lock().unwrap(), expected, "lifo_slot: {}", lifo_slot);
+            assert_eq!(counters[0].lifo_polls.load(Ordering::Relaxed) > 0, lifo_slot);
+        }
+    }
+
+    #[test]
+    fn a_yielding_task_does_not_take_the_lifo_slot() {
+        let (pool, spawner) = Executor::builder().worker_threads(1).build_worker_pool();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let inner_spawner = spawner.clone();
+        let inner_log = log.clone();
+        spawner.spawn(async move {
+            let log = inner_log.clone();
+            inner_spawner.spawn(async move { log.lock().unwrap().push("other") });
+            // The other task is in the slot; yielding must not jump ahead of it.
+            coop::yield_now().await;
+            inner_log.lock().unwrap().push("yielded");
+        });
+        drop(spawner);
+
+        pool.run();
+        assert_eq!(*log.lock().unwrap(), ["other", "yielded"]);
+    }
+}
@@ -0,0 +1,191 @@
+// To compare scheduler changes fairly, every run has to see the same work arriving at the same
+// times. A `Workload` describes a mix of task kinds and an average arrival rate; from a seed it
+// derives a fixed plan of (delay, kind) pairs, then spawns the tasks according to that plan.
+//
+// Arrivals follow a Poisson process, i.e. the gaps between arrivals are exponentially
+// distributed, which is the usual model for independent requests hitting a server.
+
+use std::time::{Duration, Instant};
+
+use futures::task::{Spawn, SpawnError, SpawnExt};
+
+use crate::TimerFuture;
+
+/// The kinds of task a workload can spawn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskKind {
+    /// Spins on the CPU for `Workload::cpu_time` without yielding.
+    Cpu,
+    /// Sleeps once for `Workload::timer_delay`.
+    Timer,
+    /// Waits `Workload::io_waits` times for `Workload::io_delay` each, like a task making
+    /// a series of small reads.
+    Io,
+}
+
+/// Description of a workload. Weights are relative: `cpu: 1, timer: 3, io: 0` spawns
+/// roughly one CPU task for every three timer tasks.
+#[derive(Clone, Debug)]
+pub struct Workload {
+    pub seed: u64,
+    pub tasks: usize,
+    /// Average arrivals per second.
+    pub arrival_rate: f64,
+    pub cpu_weight: u32,
+    pub timer_weight: u32,
+    pub io_weight: u32,
+    pub cpu_time: Duration,
+    pub timer_delay: Duration,
+    pub io_delay: Duration,
+    pub io_waits: u32,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload {
+            seed: 1,
+            tasks: 100,
+            arrival_rate: 100.0,
+            cpu_weight: 1,
+            timer_weight: 1,
+            io_weight: 1,
+            cpu_time: Duration::from_millis(1),
+            timer_delay: Duration::from_millis(10),
+            io_delay: Duration::from_millis(1),
+            io_waits: 5,
+        }
+    }
+}
+
+/// How many tasks of each kind were spawned.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub cpu: usize,
+    pub timer: usize,
+    pub io: usize,
+}
+
+impl Workload {
+    /// The arrival plan: for each task, the delay since the previous arrival and its kind.
+    /// The same seed always produces the same plan.
+    pub fn plan(&self) -> Vec<(Duration, TaskKind)> {
+        let total_weight = self.cpu_weight + self.timer_weight + self.io_weight;
+        assert!(total_weight > 0, "at least one task kind needs a weight");
+        assert!(self.arrival_rate > 0.0, "arrival rate must be positive");
+
+        let mut rng = Rng::new(self.seed);
+        (0..self.tasks)
+            .map(|_| {
+                // Inverse transform sampling of the exponential distribution.
+                let gap = -(1.0 - rng.next_f64()).ln() / self.arrival_rate;
+                let pick = (rng.next_u64() % total_weight as u64) as u32;
+                let kind = if pick < self.cpu_weight {
+                    TaskKind::Cpu
+                } else if pick < self.cpu_weight + self.timer_weight {
+                    TaskKind::Timer
+                } else {
+                    TaskKind::Io
+                };
+                (Duration::from_secs_f64(gap), kind)
+            })
+            .collect()
+    }
+
+    /// Spawn the planned tasks onto `spawner`, waiting between arrivals.
+    pub async fn run<S: Spawn>(&self, spawner: &S) -> Result<Summary, SpawnError> {
+        let mut summary = Summary::default();
+        for (gap, kind) in self.plan() {
+            TimerFuture::new(gap).await;
+            match kind {
+                TaskKind::Cpu => {
+                    summary.cpu += 1;
+                    let cpu_time = self.cpu_time;
+                    spawner.spawn(async move {
+                        let start = Instant::now();
+                        while start.elapsed() < cpu_time {
+                            std::hint::spin_loop();
+                        }
+                    })?;
+                }
+                TaskKind::Timer => {
+                    summary.timer += 1;
+                    spawner.spawn(TimerFuture::new(self.timer_delay))?;
+                }
+                TaskKind::Io => {
+                    summary.io += 1;
+                    let (io_delay, io_waits) = (self.io_delay, self.io_waits);
+                    spawner.spawn(async move {
+                        for _ in 0..io_waits {
+                            TimerFuture::new(io_delay).await;
+                        }
+                    })?;
+                }
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// xorshift64*: tiny, seedable, and good enough for generating test load.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero.
+        Rng(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::LocalPool;
+
+    #[test]
+    fn plans_are_reproducible_and_follow_the_weights() {
+        let workload = Workload {
+            tasks: 1000,
+            cpu_weight: 1,
+            timer_weight: 3,
+            io_weight: 0,
+            arrival_rate: 1000.0,
+            ..Workload::default()
+        };
+        let plan = workload.plan();
+        assert_eq!(plan, workload.plan());
+
+        let cpu = plan.iter().filter(|(_, kind)| *kind == TaskKind::Cpu).count();
+        assert!((200..300).contains(&cpu), "{} cpu tasks", cpu);
+        assert!(plan.iter().all(|(_, kind)| *kind != TaskKind::Io));
+
+        // The mean gap should be close to 1 / rate = 1ms.
+        let mean = plan.iter().map(|(gap, _)| gap.as_secs_f64()).sum::<f64>() / 1000.0;
+        assert!((0.0008..0.0012).contains(&mean), "mean gap {}", mean);
+    }
+
+    #[test]
+    fn run_spawns_every_planned_task() {
+        let workload = Workload {
+            tasks: 20,
+            arrival_rate: 2000.0,
+            ..Workload::default()
+        };
+        let mut pool = LocalPool::new();
+        let summary = pool.run_until(workload.run(&pool.spawner())).unwrap();
+        pool.run();
+
+        assert_eq!(summary.cpu + summary.timer + summary.io, 20);
+    }
+}
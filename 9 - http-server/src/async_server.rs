@@ -1,76 +1,440 @@
 
 
 use std::fs;
+use std::net::IpAddr;
 use std::str::from_utf8;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use async_std::io::{Read, Write};
 
 use async_std::prelude::*;
 use async_std::net::{TcpListener, TcpStream};
 use async_std::task;
 use async_std::task::spawn;
+use futures::future::{self, Either};
 use futures::stream::StreamExt;
 
+use crate::date;
+use crate::headers::HeaderMap;
+use crate::parser::{Parser, Status};
+use crate::ip_list::IpList;
+use crate::sniff;
+use crate::tarpit;
+use crate::throttle::{self, Throttled};
+use crate::uri::{percent_encode, Uri};
+
 // Adding async to the function declaration changes its return type
 // from the unit type () to a type that implements Future<Output=()>.
 // handle_Connection does not actually require an async_std::net::TcpStream.
 // It requires any struct that implements async_std::io::REad, async_std::io::WRite, and market::Unpin
 async fn handle_connection(mut stream: impl Read + Write + Unpin) {
-    // Read the first 1024 bytes of data from the stream
     let mut buffer = [0; 1024];
-    stream.read(&mut buffer).await.unwrap();
+    let mut parser = Parser::new();
+    // Bytes read past the end of a head. Only a request the client sent without waiting for
+    // the response to the one before (pipelining) gets this far, as a body closes the connection.
+    let mut leftover = Vec::new();
+    loop {
+        // Read until the whole head has arrived, however many reads that takes
+        let head_complete = loop {
+            let chunk = if leftover.is_empty() {
+                match stream.read(&mut buffer).await {
+                    Ok(len) if len > 0 => buffer[..len].to_vec(),
+                    // The client hung up or reset the connection, between requests or in the
+                    // middle of one
+                    Ok(_) | Err(_) => return,
+                }
+            } else {
+                std::mem::take(&mut leftover)
+            };
+            match parser.advance(&chunk) {
+                Status::Partial => continue,
+                Status::Complete(used) => {
+                    leftover = chunk[used..].to_vec();
+                    break true;
+                }
+                Status::TooLarge => break false,
+            }
+        };
+
+        // Respond with greetings, a 404, or a 400 for requests we refuse to interpret,
+        // depending on the data in the request
+        let request = if head_complete { parse_request(parser.head()) } else { None };
+        // After a 400 we can't tell where the next request would start, and we don't read
+        // bodies, so another request only follows one without a body
+        let mut keep_alive = request.as_ref().is_some_and(|request| request.keep_alive() && !request.has_body());
+        let mut location = None;
+        let mut last_modified = None;
+        let route = request.as_ref().map(|request| (request.method, request.path.as_str()));
+        let (status_line, filename) = match route {
+            None => ("HTTP/1.1 400 BAD REQUEST", Some("400.html")),
+            Some(("GET", "/")) => {
+                // Say when the page last changed, so a client with a copy can ask whether it
+                // still is current instead of fetching it again
+                last_modified = modified_time("hello.html");
+                let current = last_modified.zip(request.as_ref()).is_some_and(|(modified, request)| request.has_copy_from(modified));
+                if current {
+                    ("HTTP/1.1 304 NOT MODIFIED", None)
+                } else {
+                    ("HTTP/1.1 200 OK", Some("hello.html"))
+                }
+            }
+            Some(("GET", "/index.html")) => {
+                // The usual name for the root page, which lives at "/" itself; the query goes along
+                location = request.as_ref().map(|request| request.with_query("/"));
+                ("HTTP/1.1 301 MOVED PERMANENTLY", None)
+            }
+            Some(("GET", "/sleep")) => {
+                // "/sleep?seconds=1" asks for less, but never for more than the default
+                let seconds = request
+                    .as_ref()
+                    .and_then(|request| request.query("seconds"))
+                    .and_then(|seconds| seconds.parse().ok())
+                    .map_or(MAX_SLEEP_SECS, |seconds: u64| seconds.min(MAX_SLEEP_SECS));
+                // Race the slow work against the client hanging up,
+                // so an abandoned request does not hold on to the connection for the full 5 seconds
+                let work = task::sleep(Duration::from_secs(seconds));
+                let disconnected = wait_for_disconnect(&mut stream);
+                futures::pin_mut!(work, disconnected);
+                if let Either::Right(_) = future::select(work, disconnected).await {
+                    // Nobody is left to read the response
+                    return;
+                }
+                // Watching for the hang-up read, and threw away, anything sent after the request
+                keep_alive = false;
+                ("HTTP/1.1 200 OK", Some("hello.html"))
+            }
+            Some(_) => ("HTTP/1.1 404 NOT FOUND", Some("404.html")),
+        };
+        let contents = filename.map_or_else(String::new, |filename| fs::read_to_string(filename).unwrap());
+
+        let mut headers = HeaderMap::new();
+        // A response has a few short headers, well inside the map's limits
+        let mut add = |name: &str, value: &str| headers.append(name, value).expect("response headers over the limit");
+        // Every response carries the time it was sent, cached so it isn't formatted per request
+        add("Date", &date::current_date());
+        if let Some(location) = &location {
+            add("Location", location);
+        }
+        if let Some(modified) = last_modified {
+            add("Last-Modified", &date::fmt_http_date(modified));
+        }
+        // The length tells the client where this response ends and the next one starts. A 304
+        // never has a body, and its length would have to be that of the page it stands for.
+        if filename.is_some() || location.is_some() {
+            add("Content-Length", &contents.len().to_string());
+        }
+        if !keep_alive {
+            add("Connection", "close");
+        }
+        let response = format_response(status_line, &headers, &contents);
+        // An error means the client went away (a reset or a broken pipe), and there's nobody left to tell
+        if write_response(&mut stream, response.as_bytes()).await.is_err() || !keep_alive {
+            return;
+        }
+        parser.reset();
+    }
+}
+
+// When filename last changed, to the second as HTTP-dates have it
+fn modified_time(filename: &str) -> Option<SystemTime> {
+    let modified = fs::metadata(filename).ok()?.modified().ok()?;
+    let secs = modified.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// The status line, then the headers in order, then the body
+fn format_response(status_line: &str, headers: &HeaderMap, body: &str) -> String {
+    let mut response = format!("{}\r\n", status_line);
+    for (name, value) in headers.iter() {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    response
+}
+
+// Longest request target we accept, anything longer gets a 400
+const MAX_TARGET_LEN: usize = 256;
+
+// How long /sleep takes, unless the query asks for less
+const MAX_SLEEP_SECS: u64 = 5;
+
+struct Request<'a> {
+    method: &'a str,
+    version: &'a str,
+    // Percent-decoded, then with any dot segments removed
+    path: String,
+    // Decoded name/value pairs, in the order they were sent
+    query: Vec<(String, String)>,
+    headers: HeaderMap,
+}
+
+impl Request<'_> {
+    // The first value given for name in the query
+    fn query(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    // Whether the client wants to send another request on this connection (RFC 7230 section
+    // 6.3): unless it says otherwise from HTTP/1.1, and only when it asks from HTTP/1.0
+    fn keep_alive(&self) -> bool {
+        let mut options = self
+            .headers
+            .get_all("connection")
+            .flat_map(|value| value.split(','))
+            .map(str::trim);
+        if self.version == "HTTP/1.1" {
+            !options.any(|option| option.eq_ignore_ascii_case("close"))
+        } else {
+            options.any(|option| option.eq_ignore_ascii_case("keep-alive"))
+        }
+    }
+
+    // Whether the copy the client has, from If-Modified-Since, is no older than modified. A
+    // date that doesn't parse is ignored (RFC 7232 section 3.3).
+    fn has_copy_from(&self, modified: SystemTime) -> bool {
+        self.headers
+            .get("if-modified-since")
+            .and_then(date::parse_http_date)
+            .is_some_and(|since| modified <= since)
+    }
+
+    // Whether a body follows the head
+    fn has_body(&self) -> bool {
+        self.headers.contains("transfer-encoding")
+            || self.headers.get("content-length").is_some_and(|length| length != "0")
+    }
+
+    // path followed by this request's query, encoded again
+    fn with_query(&self, path: &str) -> String {
+        let pairs: Vec<String> = self
+            .query
+            .iter()
+            .map(|(name, value)| format!("{}={}", percent_encode(name), percent_encode(value)))
+            .collect();
+        if pairs.is_empty() {
+            path.to_owned()
+        } else {
+            format!("{}?{}", path, pairs.join("&"))
+        }
+    }
+}
+
+// Check the request line and headers, and parse them into a Request.
+// Returns None for requests that should get a 400:
+// - malformed request lines, or versions other than HTTP/1.0 and HTTP/1.1
+// - targets that aren't a plain path (absolute-form is only for proxies, which we aren't)
+// - targets longer than MAX_TARGET_LEN
+// - paths with an encoded '/', which would only become a separator once decoded
+// - malformed headers, or more than a HeaderMap holds
+// - HTTP/1.1 requests without a Host header, requests with more than one, and Host headers
+//   that aren't a host and port
+fn parse_request(head: &[u8]) -> Option<Request<'_>> {
+    let head = from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?;
+    let target = request_line.next()?;
+    let version = request_line.next()?;
+    if request_line.next().is_some() || method.is_empty() {
+        return None;
+    }
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return None;
+    }
+    if target.len() > MAX_TARGET_LEN {
+        return None;
+    }
+    let uri = Uri::parse(target).ok()?;
+    if uri.scheme().is_some() {
+        return None;
+    }
+    if uri.path_segments().any(|segment| segment.contains('/')) {
+        return None;
+    }
+
+    let mut headers = HeaderMap::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':')?;
+        headers.append(name, value.trim()).ok()?;
+    }
+    // Exactly one Host header from HTTP/1.1, and at most one from HTTP/1.0 (RFC 7230 section 5.4)
+    let hosts = headers.get_all("host").count();
+    if hosts > 1 || (version == "HTTP/1.1" && hosts == 0) {
+        return None;
+    }
+    if let Some(host) = headers.get("host") {
+        // Anything past the authority ("localhost/a", "localhost?a") would be dropped by the
+        // parse, so it has to come back out unchanged
+        let uri = Uri::parse(&format!("http://{}", host)).ok()?;
+        if uri.authority() != Some(host) {
+            return None;
+        }
+    }
+
+    Some(Request {
+        method,
+        version,
+        // Decoded first, so an encoded dot segment ("/%2e%2e/") is removed like any other
+        path: remove_dot_segments(uri.path_segments()),
+        query: uri.query_pairs(),
+        headers,
+    })
+}
+
+// Resolve "." and ".." segments the way RFC 3986 does, so "/sleep/../" is served as "/"
+// and ".." can never climb above the root
+fn remove_dot_segments(segments: impl IntoIterator<Item = String>) -> String {
+    let mut kept = Vec::new();
+    // A trailing "." or ".." still refers to a directory
+    let mut directory = false;
+    for segment in segments {
+        directory = segment == "." || segment == "..";
+        match segment.as_str() {
+            "." => {}
+            ".." => {
+                kept.pop();
+            }
+            _ => kept.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", kept.join("/"));
+    if directory && !normalized.ends_with('/') {
+        normalized.push('/');
+    }
+    normalized
+}
+
+// Write response back to the stream,
+// and flush the stream to ensure the response is sent back to the client.
+// A single `write` may only accept part of the buffer (a short write), e.g. when the socket's
+// send buffer is full, so `write_all` keeps writing until every byte has been accepted.
+async fn write_response(stream: &mut (impl Write + Unpin), response: &[u8]) -> std::io::Result<()> {
+    stream.write_all(response).await?;
+    stream.flush().await
+}
+
+// Resolves once the client closes its end of the connection (read returns EOF) or resets it.
+async fn wait_for_disconnect(stream: &mut (impl Read + Unpin)) {
+    let mut buffer = [0; 1024];
+    loop {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            // The client is still there and sent more bytes, keep waiting
+            Ok(_) => continue,
+        }
+    }
+}
 
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
+// How the server treats its clients, read from the environment when it starts
+struct Policy {
+    // Clients to tarpit, from TARPIT_IPS. Empty (the default) turns the tarpit off.
+    denylist: IpList,
+    // Bytes per second each connection may use, from BANDWIDTH_LIMIT
+    bandwidth_limit: Option<u64>,
+    // Load balancers allowed to name the client in a PROXY header, from TRUSTED_PROXIES.
+    // Empty (the default) believes nobody's.
+    trusted_proxies: IpList,
+}
 
-    // Respond with greetings or a 404,
-    // depending on the data in the request
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        task::sleep(Duration::from_secs(5)).await;
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
+impl Policy {
+    fn from_env() -> Self {
+        Policy {
+            denylist: IpList::from_env("TARPIT_IPS"),
+            bandwidth_limit: throttle::limit_from_env(),
+            trusted_proxies: IpList::from_env("TRUSTED_PROXIES"),
+        }
     }
-    else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
-    };
-    let contents = fs::read_to_string(filename).unwrap();
+}
 
-    // Write response back to the stream,
-    // and flush the stream to ensure the response is sent back to the client
-    let response = format!("{}{}", status_line, contents);
-    stream.write(response.as_bytes()).await.unwrap();
-    stream.flush().await.unwrap();
+// Serve one connection from client (None if its address is unknown) as policy says
+async fn serve(stream: impl Read + Write + Unpin, client: Option<IpAddr>, policy: &Policy) {
+    if client.is_some_and(|address| policy.denylist.contains(address)) {
+        // Tarpitted clients only cost a sleeping task, so they can't crowd out anyone else
+        tarpit::serve(stream).await;
+        return;
+    }
+    match policy.bandwidth_limit {
+        Some(limit) => handle_connection(Throttled::new(stream, limit)).await,
+        None => handle_connection(stream).await,
+    }
+}
+
+fn peer_ip(stream: &TcpStream) -> Option<IpAddr> {
+    stream.peer_addr().ok().map(|address| address.ip())
 }
 
 async fn async_concurrent() {
     let listener = TcpListener::bind("127.0.0.1:7878").await.unwrap();
+    spawn(date::refresh_date());
+    let policy = Policy::from_env();
 
     // The asynchronous version of TcpListener implements the Stream trait for listener.incoming()
     listener.incoming()
         // for_each_concurrent is implemented by the StreamExt trait in the futures crate
-        .for_each_concurrent(None, |stream| async move {
-            let stream = stream.unwrap();
-            // As long as handle_connection does not block, a slow request will no longer prevent other requests from completing
-            handle_connection(stream).await;
+        .for_each_concurrent(None, |stream| {
+            let policy = &policy;
+            async move {
+                let stream = stream.unwrap();
+                let client = peer_ip(&stream);
+                // As long as handle_connection does not block, a slow request will no longer prevent other requests from completing
+                serve(stream, client, policy).await;
+            }
         }).await;
 }
 
 async fn async_parallel() {
     let listener = TcpListener::bind("127.0.0.1:7878").await.unwrap();
+    spawn(date::refresh_date());
+    let policy = Arc::new(Policy::from_env());
 
     listener.incoming()
-        .for_each_concurrent(None, |stream| async move {
-            let stream = stream.unwrap();
-            // Because handle_connection is both Send and non-blocking,
-            // it's safe to use with async_std::task::spawn.
-            spawn(handle_connection(stream));
+        .for_each_concurrent(None, |stream| {
+            let policy = policy.clone();
+            async move {
+                let stream = stream.unwrap();
+                let client = peer_ip(&stream);
+                // Because handle_connection is both Send and non-blocking,
+                // it's safe to use with async_std::task::spawn.
+                spawn(async move { serve(stream, client, &policy).await });
+            }
+        }).await;
+}
+
+// Like async_concurrent, but first works out what each connection speaks, so the one port
+// takes both plain HTTP and HTTP behind a load balancer's PROXY protocol header
+async fn async_sniffing() {
+    let listener = TcpListener::bind("127.0.0.1:7878").await.unwrap();
+    spawn(date::refresh_date());
+    let policy = Policy::from_env();
+
+    listener.incoming()
+        .for_each_concurrent(None, |stream| {
+            let policy = &policy;
+            async move {
+                let stream = stream.unwrap();
+                let peer = peer_ip(&stream);
+                let trusted_proxy = peer.is_some_and(|address| policy.trusted_proxies.contains(address));
+                let Some(accepted) = sniff::accept(stream, trusted_proxy).await else {
+                    return;
+                };
+                // Behind a load balancer the peer is the balancer; the header names the client.
+                // Nobody else gets this far with a header, so anyone else is the peer.
+                serve(accepted.stream, accepted.source.or(peer), policy).await;
+            }
         }).await;
 }
 
 #[async_std::main]
 pub async fn main() {
-    async_concurrent().await;
+    // Only look for PROXY headers when asked to, and even then only believe them from the load
+    // balancers in TRUSTED_PROXIES: any client could send one and claim to be somebody else
+    if std::env::var_os("SNIFF_PROTOCOLS").is_some() {
+        async_sniffing().await;
+    } else {
+        async_concurrent().await;
+    }
 }
 
 #[cfg(test)]
@@ -84,23 +448,28 @@ mod tests {
 
     struct MockTcpStream {
         read_data: Vec<u8>,
-        write_data: Vec<u8>
+        write_data: Vec<u8>,
+        // Most bytes a single write accepts, to simulate short writes
+        max_write_size: usize,
     }
 
     impl Read for MockTcpStream {
         fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
-            let size: usize = min(self.read_data.len(), buf.len());
-            buf[..size].copy_from_slice(&self.read_data[..size]);
+            // Consume what was read, so the stream reports EOF once the client has sent everything
+            let stream = self.get_mut();
+            let size: usize = min(stream.read_data.len(), buf.len());
+            buf[..size].copy_from_slice(&stream.read_data[..size]);
+            stream.read_data.drain(..size);
             Poll::Ready(Ok(size))
         }
     }
 
     impl Write for MockTcpStream {
         fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
-            unsafe {
-                self.get_unchecked_mut().write_data = Vec::from(buf);
-            }
-            Poll::Ready(Ok(buf.len()))
+            let stream = self.get_mut();
+            let size = min(stream.max_write_size, buf.len());
+            stream.write_data.extend_from_slice(&buf[..size]);
+            Poll::Ready(Ok(size))
         }
 
         fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
@@ -115,20 +484,348 @@ mod tests {
     // To indicate that its location in memory can safely be moved.
     impl Unpin for MockTcpStream {}
 
+    // Check the response has a valid Date header, and remove it so the rest can be compared exactly
+    fn without_date(response: &[u8]) -> String {
+        let response = from_utf8(response).unwrap();
+        let start = response.find("\r\nDate: ").unwrap() + 2;
+        let end = start + response[start..].find("\r\n").unwrap() + 2;
+        assert!(date::parse_http_date(&response[start + "Date: ".len()..end - 2]).is_some());
+        format!("{}{}", &response[..start], &response[end..])
+    }
+
+    // The response to GET / from a client without a copy of the page, less the Date header
+    fn hello_response() -> String {
+        let contents = fs::read_to_string("hello.html").unwrap();
+        let modified = date::fmt_http_date(fs::metadata("hello.html").unwrap().modified().unwrap());
+        format!(
+            "HTTP/1.1 200 OK\r\nLast-Modified: {}\r\nContent-Length: {}\r\n\r\n{}",
+            modified,
+            contents.len(),
+            contents
+        )
+    }
+
     #[async_std::test]
     async fn test_handle_connection() {
-        let input_bytes = b"GET / HTTP/1.1\r\n";
+        let input_bytes = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let mut contents = vec![0u8; 1024];
+        contents[..input_bytes.len()].clone_from_slice(input_bytes);
+        let mut stream = MockTcpStream {
+            read_data: contents,
+            write_data: Vec::new(),
+            max_write_size: usize::MAX,
+        };
+
+        handle_connection(&mut stream).await;
+
+        let expected_response = hello_response();
+        assert!(without_date(&stream.write_data).starts_with(&expected_response));
+    }
+
+    #[async_std::test]
+    async fn test_serve_tarpits_denylisted_clients() {
+        let policy = Policy { denylist: IpList::parse("10.0.0.1"), bandwidth_limit: None, trusted_proxies: IpList::default() };
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let mut denied = MockTcpStream {
+            read_data: request.to_vec(),
+            write_data: Vec::new(),
+            max_write_size: usize::MAX,
+        };
+        let mut allowed = MockTcpStream {
+            read_data: request.to_vec(),
+            write_data: Vec::new(),
+            max_write_size: usize::MAX,
+        };
+
+        {
+            let tarpitted = serve(&mut denied, Some("10.0.0.1".parse().unwrap()), &policy);
+            let served = serve(&mut allowed, Some("10.0.0.2".parse().unwrap()), &policy);
+            futures::pin_mut!(tarpitted, served);
+            assert!(matches!(future::select(tarpitted, served).await, Either::Right(_)));
+        }
+
+        // The tarpit sleeps before its first byte, long after the other client was answered
+        assert!(denied.write_data.is_empty());
+        assert_eq!(without_date(&allowed.write_data), hello_response());
+    }
+
+    #[async_std::test]
+    async fn test_serve_throttles_under_a_bandwidth_limit() {
+        let policy = Policy { denylist: IpList::default(), bandwidth_limit: Some(1_000_000), trusted_proxies: IpList::default() };
+        let mut stream = MockTcpStream {
+            read_data: b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
+            write_data: Vec::new(),
+            max_write_size: usize::MAX,
+        };
+
+        serve(&mut stream, None, &policy).await;
+
+        let expected_response = hello_response();
+        assert_eq!(without_date(&stream.write_data), expected_response);
+    }
+
+    #[async_std::test]
+    async fn test_handle_connection_with_short_writes() {
+        let input_bytes = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
         let mut contents = vec![0u8; 1024];
         contents[..input_bytes.len()].clone_from_slice(input_bytes);
+        // Only accept a few bytes per write, like a busy socket
         let mut stream = MockTcpStream {
             read_data: contents,
             write_data: Vec::new(),
+            max_write_size: 7,
         };
 
         handle_connection(&mut stream).await;
 
-        let expected_contents = fs::read_to_string("hello.html").unwrap();
-        let expected_response = format!("HTTP/1.1 200 OK\r\n\r\n{}", expected_contents);
-        assert!(stream.write_data.starts_with(expected_response.as_bytes()));
+        let expected_response = hello_response();
+        assert_eq!(without_date(&stream.write_data), expected_response);
+    }
+
+    #[async_std::test]
+    async fn test_handle_connection_reads_a_head_split_across_reads() {
+        // A client writing its request a few bytes at a time
+        struct TrickleStream(MockTcpStream);
+
+        impl Read for TrickleStream {
+            fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+                let len = min(buf.len(), 5);
+                Pin::new(&mut self.get_mut().0).poll_read(cx, &mut buf[..len])
+            }
+        }
+
+        impl Write for TrickleStream {
+            fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+                Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Pin::new(&mut self.get_mut().0).poll_flush(cx)
+            }
+
+            fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Pin::new(&mut self.get_mut().0).poll_close(cx)
+            }
+        }
+
+        let mut stream = TrickleStream(MockTcpStream {
+            read_data: b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
+            write_data: Vec::new(),
+            max_write_size: usize::MAX,
+        });
+
+        handle_connection(&mut stream).await;
+
+        let response = from_utf8(&stream.0.write_data).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[async_std::test]
+    async fn test_handle_connection_stops_when_client_disconnects() {
+        let input_bytes = b"GET /sleep HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let mut contents = vec![0u8; 1024];
+        contents[..input_bytes.len()].clone_from_slice(input_bytes);
+        // Nothing is left to read after the request, so the client looks like it hung up
+        let mut stream = MockTcpStream {
+            read_data: contents,
+            write_data: Vec::new(),
+            max_write_size: usize::MAX,
+        };
+
+        let start = std::time::Instant::now();
+        handle_connection(&mut stream).await;
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(stream.write_data.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_handle_connection_survives_connection_errors() {
+        // A client that resets the connection, either before sending anything or after its request
+        struct ResetStream {
+            request: &'static [u8],
+        }
+
+        impl Read for ResetStream {
+            fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+                let stream = self.get_mut();
+                if stream.request.is_empty() {
+                    return Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()));
+                }
+                let size = min(stream.request.len(), buf.len());
+                buf[..size].copy_from_slice(&stream.request[..size]);
+                stream.request = &stream.request[size..];
+                Poll::Ready(Ok(size))
+            }
+        }
+
+        impl Write for ResetStream {
+            fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, _: &[u8]) -> Poll<std::io::Result<usize>> {
+                Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        // Neither panics
+        handle_connection(ResetStream { request: b"" }).await;
+        handle_connection(ResetStream { request: b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n" }).await;
+    }
+
+    async fn respond_to(request: &[u8]) -> String {
+        let mut stream = MockTcpStream {
+            read_data: request.to_vec(),
+            write_data: Vec::new(),
+            max_write_size: usize::MAX,
+        };
+        handle_connection(&mut stream).await;
+        String::from_utf8(stream.write_data).unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_handle_connection_rejects_invalid_requests() {
+        let bad_requests: [&[u8]; 8] = [
+            // HTTP/1.1 requires a Host header
+            b"GET / HTTP/1.1\r\n\r\n",
+            // which names just a host and port
+            b"GET / HTTP/1.1\r\nHost: localhost/a\r\n\r\n",
+            b"GET / HTTP/1.0\r\nHost: \r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nhost: example.com\r\n\r\n",
+            // Absolute-form is only for proxies
+            b"GET http://localhost/ HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"GET / HTTP/2.0\r\nHost: localhost\r\n\r\n",
+            b"GET /  HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            b"GET\r\n\r\n",
+        ];
+        for request in bad_requests {
+            let response = respond_to(request).await;
+            assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST\r\n"), "{:?}", request);
+        }
+
+        let long_request = format!(
+            "GET /{} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "a".repeat(MAX_TARGET_LEN)
+        );
+        let response = respond_to(long_request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST\r\n"));
+    }
+
+    #[async_std::test]
+    async fn test_handle_connection_normalizes_the_path() {
+        let response = respond_to(b"GET /sleep/../ HTTP/1.1\r\nhost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // HTTP/1.0 doesn't need a Host header
+        let response = respond_to(b"GET /./missing/.. HTTP/1.0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let response = respond_to(b"GET /%73leep/..?x=1 HTTP/1.0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[async_std::test]
+    async fn test_handle_connection_answers_pipelined_requests() {
+        let second = b"GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let response = respond_to(&[b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n", &second[..]].concat()).await;
+        let (first_response, second_response) = response.split_at(response.find("HTTP/1.1 404").unwrap());
+        assert!(first_response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!first_response.contains("\r\nConnection: close\r\n"));
+        assert!(second_response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+
+        // HTTP/1.0 only keeps the connection open when asked to
+        let response = respond_to(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n").await;
+        assert_eq!(response.matches("\r\nDate: ").count(), 2);
+
+        // Otherwise asking to close, a body, or a bad request ends the connection after one response
+        let closing: [&[u8]; 4] = [
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive, close\r\n\r\n",
+            b"GET / HTTP/1.0\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\nhi",
+            b"GET / HTTP/2.0\r\n\r\n",
+        ];
+        for request in closing {
+            let response = respond_to(&[request, &second[..]].concat()).await;
+            assert_eq!(response.matches("\r\nDate: ").count(), 1, "{:?}", request);
+            assert!(response.contains("\r\nConnection: close\r\n"), "{:?}", request);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_handle_connection_answers_if_modified_since() {
+        let modified = modified_time("hello.html").unwrap();
+        let ask = |since: SystemTime| {
+            format!(
+                "GET / HTTP/1.1\r\nHost: localhost\r\nIf-Modified-Since: {}\r\n\r\n",
+                date::fmt_http_date(since)
+            )
+        };
+
+        let response = respond_to(ask(modified).as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 304 NOT MODIFIED\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n"));
+        assert!(!response.contains("Content-Length"));
+
+        // An older copy, or a date we can't read, gets the page
+        for request in [
+            ask(modified - Duration::from_secs(1)),
+            "GET / HTTP/1.1\r\nHost: localhost\r\nIf-Modified-Since: yesterday\r\n\r\n".to_owned(),
+        ] {
+            let response = respond_to(request.as_bytes()).await;
+            assert_eq!(without_date(response.as_bytes()), hello_response());
+        }
+    }
+
+    #[async_std::test]
+    async fn test_handle_connection_redirects_index_html() {
+        let response = respond_to(b"GET /index.html?a=b+c&d=%26 HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 301 MOVED PERMANENTLY\r\n"));
+        assert!(response.contains("\r\nLocation: /?a=b%20c&d=%26\r\n"));
+
+        let response = respond_to(b"GET /index.html HTTP/1.1\r\nHost: localhost:7878\r\n\r\n").await;
+        assert!(response.contains("\r\nLocation: /\r\n"));
+    }
+
+    #[test]
+    fn test_parse_request_resolves_encoded_dot_segments() {
+        let request = parse_request(b"GET /%2e%2e/%2E%2E/etc/passwd HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/etc/passwd");
+        let request = parse_request(b"GET /a/.%2e/%2e/b HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/b");
+
+        // An encoded '/' is refused rather than decoded into a separator
+        assert!(parse_request(b"GET /..%2f..%2Fetc/passwd HTTP/1.0\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn test_parse_request_collects_headers() {
+        let buffer = b"GET /a?b&c=d+e&c=f HTTP/1.1\r\nHost: localhost\r\nAccept:  */*\r\n\r\n";
+        let request = parse_request(buffer).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/a");
+        assert_eq!(request.query("b"), Some(""));
+        assert_eq!(request.query("c"), Some("d e"));
+        assert_eq!(request.query("d"), None);
+        assert_eq!(request.headers.get("host"), Some("localhost"));
+        assert_eq!(request.headers.get("accept"), Some("*/*"));
+
+        // Header lines need a colon
+        assert!(parse_request(b"GET / HTTP/1.1\r\nHost: localhost\r\nbogus\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn test_remove_dot_segments() {
+        let remove = |path: &str| remove_dot_segments(path.split('/').skip(1).map(str::to_owned));
+        assert_eq!(remove("/"), "/");
+        assert_eq!(remove("/a/./b"), "/a/b");
+        assert_eq!(remove("/a/b/../c"), "/a/c");
+        assert_eq!(remove("/a/.."), "/");
+        assert_eq!(remove("/a/b/."), "/a/b/");
+        assert_eq!(remove("/../../etc/passwd"), "/etc/passwd");
     }
 }
\ No newline at end of file
@@ -0,0 +1,164 @@
+// HTTP-dates (RFC 7231 section 7.1.1.1), as used by the Date header.
+// Always in GMT, e.g. "Sun, 06 Nov 1994 08:49:37 GMT".
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_std::task;
+
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// The current Date header value, and the second since the epoch it was formatted for
+static CURRENT_DATE: Mutex<Option<(u64, String)>> = Mutex::new(None);
+
+// Format time as an IMF-fixdate, the only form HTTP/1.1 senders may generate.
+// Times before 1970 are clamped to the epoch.
+pub fn fmt_http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    // 1970-01-01 was a Thursday
+    let weekday = WEEKDAYS[((days + 3) % 7) as usize];
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        weekday,
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// Parse any of the three formats recipients must accept:
+// IMF-fixdate "Sun, 06 Nov 1994 08:49:37 GMT", RFC 850 "Sunday, 06-Nov-94 08:49:37 GMT",
+// and asctime "Sun Nov  6 08:49:37 1994".
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let fields: Vec<&str> = date.split_whitespace().collect();
+    let (day, month, year, time) = match fields.as_slice() {
+        [_, day, month, year, time, "GMT"] => (*day, *month, year.parse().ok()?, *time),
+        [_, date, time, "GMT"] => {
+            let mut date = date.split('-');
+            let (day, month, year) = (date.next()?, date.next()?, date.next()?);
+            // RFC 850 only has two digits for the year, take them to be 1970 to 2069
+            let year: i64 = year.parse().ok()?;
+            (day, month, if year < 70 { 2000 + year } else { 1900 + year }, *time)
+        }
+        [_, month, day, time, year] => (*day, *month, year.parse().ok()?, *time),
+        _ => return None,
+    };
+
+    let month = MONTHS.iter().position(|name| *name == month)? as u32 + 1;
+    let day: u32 = day.parse().ok()?;
+    let mut time = time.split(':').map(|field| field.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    // A leap second (60) is allowed, and rolls over into the next minute
+    if time.next().is_some() || !(1..=31).contains(&day) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+    let secs = days as u64 * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// The Date header value for now. Formatted at most once a second,
+// usually ahead of time by refresh_date.
+pub fn current_date() -> String {
+    let now = SystemTime::now();
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut cached = CURRENT_DATE.lock().unwrap();
+    match &*cached {
+        Some((cached_secs, date)) if *cached_secs == secs => date.clone(),
+        _ => {
+            let date = fmt_http_date(now);
+            *cached = Some((secs, date.clone()));
+            date
+        }
+    }
+}
+
+// Keep the cached Date header value fresh, so requests never pay for formatting it.
+// Spawn this once alongside the server.
+pub async fn refresh_date() {
+    loop {
+        current_date();
+        // Wake up just as the next second starts
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let into_this_second = Duration::from_nanos(since_epoch.subsec_nanos().into());
+        task::sleep(Duration::from_secs(1) - into_this_second).await;
+    }
+}
+
+// Days since 1970-01-01 to (year, month, day), using Howard Hinnant's algorithm
+// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months counted from March, so the leap day comes last
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = (month_from_march + 2) % 12 + 1;
+    let month = month as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// The inverse of civil_from_days
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fmt_http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(fmt_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(fmt_http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        // The leap day in a leap year divisible by 400
+        let time = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(fmt_http_date(time), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn test_parse_http_date_accepts_all_three_formats() {
+        let expected = Some(UNIX_EPOCH + Duration::from_secs(784111777));
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), expected);
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 PST"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+    }
+
+    #[test]
+    fn test_current_date_round_trips() {
+        let date = current_date();
+        let parsed = parse_http_date(&date).unwrap();
+        assert_eq!(fmt_http_date(parsed), date);
+    }
+}
@@ -0,0 +1,120 @@
+// Request and response headers, kept in the order they arrived.
+// Header names are case-insensitive (RFC 7230 section 3.2), and a name may appear more than once,
+// so this is a list of name/value pairs rather than a HashMap.
+
+use std::fmt;
+
+// Defaults for HeaderMap::new, generous for the small requests this server expects
+pub const MAX_HEADERS: usize = 64;
+pub const MAX_HEADER_BYTES: usize = 8 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+    // Bytes taken up by names and values so far
+    size: usize,
+    max_headers: usize,
+    max_bytes: usize,
+}
+
+// Returned by HeaderMap::append when a header would go over the map's limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyHeaders;
+
+impl fmt::Display for TooManyHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "too many headers, or headers too large")
+    }
+}
+
+impl std::error::Error for TooManyHeaders {}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::with_limits(MAX_HEADERS, MAX_HEADER_BYTES)
+    }
+
+    // A map that refuses to hold more than max_headers headers,
+    // or more than max_bytes of names and values together
+    pub fn with_limits(max_headers: usize, max_bytes: usize) -> Self {
+        HeaderMap {
+            entries: Vec::new(),
+            size: 0,
+            max_headers,
+            max_bytes,
+        }
+    }
+
+    // Add a header, keeping any earlier values with the same name
+    pub fn append(&mut self, name: &str, value: &str) -> Result<(), TooManyHeaders> {
+        let size = self.size + name.len() + value.len();
+        if self.entries.len() == self.max_headers || size > self.max_bytes {
+            return Err(TooManyHeaders);
+        }
+        self.entries.push((name.to_owned(), value.to_owned()));
+        self.size = size;
+        Ok(())
+    }
+
+    // The first value for name, ignoring case
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(entry, _)| entry.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Every value for name in the order they were added, ignoring case
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(entry, _)| entry.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    // Every header in the order they were added, with names as they were given
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+impl Default for HeaderMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_ignores_case_and_keeps_every_value() {
+        let mut headers = HeaderMap::new();
+        headers.append("Accept", "text/html").unwrap();
+        headers.append("Host", "localhost").unwrap();
+        headers.append("accept", "*/*").unwrap();
+
+        assert_eq!(headers.get("HOST"), Some("localhost"));
+        assert_eq!(headers.get_all("ACCEPT").collect::<Vec<_>>(), ["text/html", "*/*"]);
+        assert_eq!(headers.get("cookie"), None);
+        let names: Vec<_> = headers.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["Accept", "Host", "accept"]);
+    }
+
+    #[test]
+    fn test_append_enforces_limits() {
+        let mut headers = HeaderMap::with_limits(2, 20);
+        headers.append("Host", "localhost").unwrap();
+        assert_eq!(headers.append("Accept", "text/html"), Err(TooManyHeaders));
+        headers.append("A", "b").unwrap();
+        assert_eq!(headers.append("C", "d"), Err(TooManyHeaders));
+        assert_eq!(headers.iter().count(), 2);
+    }
+}
@@ -0,0 +1,42 @@
+// Sets of IP addresses the server is configured with, such as the clients to tarpit and the
+// load balancers whose PROXY headers we believe.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Default)]
+pub struct IpList {
+    addresses: HashSet<IpAddr>,
+}
+
+impl IpList {
+    // Parse a comma-separated list of IP addresses, skipping any that don't parse
+    pub fn parse(list: &str) -> Self {
+        IpList {
+            addresses: list.split(',').filter_map(|address| address.trim().parse().ok()).collect(),
+        }
+    }
+
+    // The list in the environment variable var, or an empty list if it isn't set
+    pub fn from_env(var: &str) -> Self {
+        std::env::var(var).map(|list| Self::parse(&list)).unwrap_or_default()
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        self.addresses.contains(&address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_skips_bad_addresses() {
+        let list = IpList::parse("10.0.0.1, ::1,not-an-address");
+        assert!(list.contains("10.0.0.1".parse().unwrap()));
+        assert!(list.contains("::1".parse().unwrap()));
+        assert!(!list.contains("10.0.0.2".parse().unwrap()));
+        assert!(!IpList::default().contains("127.0.0.1".parse().unwrap()));
+    }
+}
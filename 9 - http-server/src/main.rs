@@ -2,6 +2,14 @@
 // to serve requests concurrently: https://doc.rust-lang.org/book/ch20-01-single-threaded.html
 
 mod async_server;
+mod date;
+mod headers;
+mod ip_list;
+mod parser;
+mod sniff;
+mod tarpit;
+mod throttle;
+mod uri;
 
 use std::fs;
 use std::io::prelude::*;
@@ -46,6 +54,9 @@ fn basic_example() {
 }
 
 fn main() {
-    // async_server::main();
-    basic_example();
+    // `cargo run -- async` runs the asynchronous server instead
+    match std::env::args().nth(1).as_deref() {
+        Some("async") => async_server::main(),
+        _ => basic_example(),
+    }
 }
\ No newline at end of file
@@ -0,0 +1,139 @@
+// Finds the end of a request head in bytes that arrive a piece at a time.
+//
+// A single read can return any part of a request: half the request line, the head and the
+// start of a body, or one request followed by the next on a pipelined connection. The Parser
+// is fed each chunk as it arrives and keeps what it has seen so far, along with how much of
+// the blank line that ends the head ("\r\n\r\n") it has matched, so no byte is looked at twice
+// and a chunk boundary can fall anywhere.
+
+// Longest head we buffer before giving up, the same as the limit on header bytes
+pub const MAX_HEAD_LEN: usize = crate::headers::MAX_HEADER_BYTES;
+
+const HEAD_END: &[u8] = b"\r\n\r\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    // The head isn't complete yet, read some more
+    Partial,
+    // The head is complete, and took this many bytes of the last chunk. Anything after that
+    // belongs to the body or to the next request.
+    Complete(usize),
+    // The head went over MAX_HEAD_LEN without ending
+    TooLarge,
+}
+
+#[derive(Debug, Clone)]
+pub struct Parser {
+    head: Vec<u8>,
+    // How many bytes of HEAD_END the head currently ends with
+    matched: usize,
+    max_len: usize,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::with_limit(MAX_HEAD_LEN)
+    }
+
+    pub fn with_limit(max_len: usize) -> Self {
+        Parser {
+            head: Vec::new(),
+            matched: 0,
+            max_len,
+        }
+    }
+
+    // Feed the next chunk read from the connection. Once the head is complete, the rest is
+    // ignored until reset.
+    pub fn advance(&mut self, input: &[u8]) -> Status {
+        if self.matched == HEAD_END.len() {
+            return Status::Complete(0);
+        }
+        for (index, &byte) in input.iter().enumerate() {
+            if self.head.len() == self.max_len {
+                return Status::TooLarge;
+            }
+            self.head.push(byte);
+            self.matched = if byte == HEAD_END[self.matched] {
+                self.matched + 1
+            } else if byte == b'\r' {
+                // Could be the start of a new "\r\n" after a lone "\r"
+                1
+            } else {
+                0
+            };
+            if self.matched == HEAD_END.len() {
+                return Status::Complete(index + 1);
+            }
+        }
+        Status::Partial
+    }
+
+    // The bytes of the head so far, including the blank line once it is complete
+    pub fn head(&self) -> &[u8] {
+        &self.head
+    }
+
+    // Start over for the next request on the same connection
+    pub fn reset(&mut self) {
+        self.head.clear();
+        self.matched = 0;
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    #[test]
+    fn test_advance_handles_every_split() {
+        // However the request is split across two reads, the head comes out the same
+        for split in 0..=REQUEST.len() {
+            let mut parser = Parser::new();
+            let (first, second) = REQUEST.split_at(split);
+            let status = match parser.advance(first) {
+                Status::Partial => parser.advance(second),
+                status => status,
+            };
+            let last_chunk = if split == REQUEST.len() { first } else { second };
+            assert_eq!(status, Status::Complete(last_chunk.len()), "{}", split);
+            assert_eq!(parser.head(), REQUEST);
+        }
+
+        // One byte at a time too
+        let mut parser = Parser::new();
+        let statuses: Vec<Status> = REQUEST.iter().map(|byte| parser.advance(&[*byte])).collect();
+        assert!(statuses[..REQUEST.len() - 1].iter().all(|status| *status == Status::Partial));
+        assert_eq!(statuses[REQUEST.len() - 1], Status::Complete(1));
+    }
+
+    #[test]
+    fn test_advance_leaves_pipelined_requests() {
+        let pipelined = [REQUEST, b"GET /sleep HTTP/1.1\r\nHost: localhost\r\n\r\n"].concat();
+        let mut parser = Parser::new();
+        let Status::Complete(used) = parser.advance(&pipelined) else {
+            panic!("first request should be complete");
+        };
+        assert_eq!(parser.head(), REQUEST);
+
+        parser.reset();
+        assert_eq!(parser.advance(&pipelined[used..]), Status::Complete(pipelined.len() - used));
+        assert!(parser.head().starts_with(b"GET /sleep "));
+    }
+
+    #[test]
+    fn test_advance_limits_the_head() {
+        let mut parser = Parser::with_limit(8);
+        assert_eq!(parser.advance(b"GET / HTTP/1.1\r\n"), Status::TooLarge);
+        // Line endings split up by other bytes don't end the head
+        assert_eq!(Parser::new().advance(b"GET / HTTP/1.1\r\n\r\r\n"), Status::Partial);
+    }
+}
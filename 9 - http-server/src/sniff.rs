@@ -0,0 +1,299 @@
+// Serving more than one protocol on a single port, by looking at the first bytes a client
+// sends before deciding what to do with the connection.
+//
+// Plain HTTP starts with a method ("GET ", "POST "...), a TLS connection with a handshake
+// record (0x16 0x03), and a connection from a load balancer speaking the PROXY protocol with
+// a header naming the real client: the text line "PROXY TCP4 ..." in version 1, or a fixed
+// 12-byte signature in version 2. The bytes read to tell them apart are part of the request,
+// so they are handed on to the handler in front of the rest of the stream by Prefixed.
+//
+// Anyone can send a PROXY header and claim to be somebody else, so one is only believed from
+// the load balancers listed in TRUSTED_PROXIES. From any other peer it is a protocol we don't
+// serve, and the connection is closed.
+// https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::io::Result;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use async_std::io::{Read, Write};
+
+use async_std::prelude::*;
+
+// First bytes of a PROXY protocol header, versions 1 and 2
+const PROXY_V1_PREFIX: &[u8] = b"PROXY ";
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// Longest version 1 header allowed, including the "\r\n"
+const PROXY_V1_MAX_LEN: usize = 107;
+
+// Longest HTTP method we wait for before deciding the client speaks something else
+const MAX_METHOD_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Http,
+    Tls,
+    ProxyV1,
+    ProxyV2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sniffed {
+    // Not enough bytes yet to tell
+    NeedMore,
+    Found(Protocol),
+    // None of the protocols we serve
+    Unknown,
+}
+
+// Tell which protocol a connection speaks from the bytes read from it so far
+pub fn sniff(prefix: &[u8]) -> Sniffed {
+    match prefix.first() {
+        None => Sniffed::NeedMore,
+        // A TLS handshake record, for any TLS version
+        Some(0x16) => match prefix.get(1) {
+            None => Sniffed::NeedMore,
+            Some(0x03) => Sniffed::Found(Protocol::Tls),
+            Some(_) => Sniffed::Unknown,
+        },
+        Some(b'\r') if prefix.starts_with(PROXY_V2_SIGNATURE) => Sniffed::Found(Protocol::ProxyV2),
+        Some(b'\r') if PROXY_V2_SIGNATURE.starts_with(prefix) => Sniffed::NeedMore,
+        _ if prefix.starts_with(PROXY_V1_PREFIX) => Sniffed::Found(Protocol::ProxyV1),
+        _ => {
+            // An HTTP method is a token of capital letters followed by a space
+            let method_len = prefix.iter().take_while(|byte| byte.is_ascii_uppercase()).count();
+            match prefix.get(method_len) {
+                Some(b' ') if method_len > 0 => Sniffed::Found(Protocol::Http),
+                None if method_len <= MAX_METHOD_LEN => Sniffed::NeedMore,
+                _ => Sniffed::Unknown,
+            }
+        }
+    }
+}
+
+// A connection we know how to serve, ready for the HTTP handler
+pub struct Accepted<S> {
+    pub stream: Prefixed<S>,
+    // The client named in a PROXY protocol header, if there was one that named it
+    pub source: Option<IpAddr>,
+}
+
+// Read enough of the connection to tell what it speaks, and strip any PROXY protocol header.
+// None means the connection should be closed: it went away, speaks something we don't serve,
+// is TLS, which this server has no support for, or sent a PROXY header without coming from a
+// trusted load balancer.
+pub async fn accept<S: Read + Unpin>(mut stream: S, trusted_proxy: bool) -> Option<Accepted<S>> {
+    let mut buffer = Vec::new();
+    let protocol = loop {
+        match sniff(&buffer) {
+            Sniffed::NeedMore => read_more(&mut stream, &mut buffer).await?,
+            Sniffed::Found(protocol) => break protocol,
+            Sniffed::Unknown => return None,
+        }
+    };
+    if matches!(protocol, Protocol::ProxyV1 | Protocol::ProxyV2) && !trusted_proxy {
+        return None;
+    }
+
+    let (header_len, source) = match protocol {
+        Protocol::Http => (0, None),
+        Protocol::Tls => return None,
+        Protocol::ProxyV1 => loop {
+            if let Some(end) = buffer.windows(2).position(|window| window == b"\r\n") {
+                break (end + 2, parse_proxy_v1(&buffer[..end])?);
+            }
+            if buffer.len() >= PROXY_V1_MAX_LEN {
+                return None;
+            }
+            read_more(&mut stream, &mut buffer).await?;
+        },
+        Protocol::ProxyV2 => {
+            // The fixed part is the signature, version and command, family, and the length
+            // of the addresses which follow
+            while buffer.len() < 16 {
+                read_more(&mut stream, &mut buffer).await?;
+            }
+            let header_len = 16 + u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+            while buffer.len() < header_len {
+                read_more(&mut stream, &mut buffer).await?;
+            }
+            (header_len, parse_proxy_v2(&buffer[..header_len])?)
+        }
+    };
+
+    buffer.drain(..header_len);
+    Some(Accepted {
+        stream: Prefixed::new(buffer, stream),
+        source,
+    })
+}
+
+// Append the next read to buffer, or None at EOF or on an error
+async fn read_more(stream: &mut (impl Read + Unpin), buffer: &mut Vec<u8>) -> Option<()> {
+    let mut chunk = [0; 256];
+    match stream.read(&mut chunk).await {
+        Ok(0) | Err(_) => None,
+        Ok(len) => {
+            buffer.extend_from_slice(&chunk[..len]);
+            Some(())
+        }
+    }
+}
+
+// Parse "PROXY TCP4 <source> <destination> <source port> <destination port>", without the
+// "\r\n". The outer None means the header is malformed, the inner one that it doesn't name a
+// client ("PROXY UNKNOWN", e.g. for the load balancer's own health checks).
+fn parse_proxy_v1(line: &[u8]) -> Option<Option<IpAddr>> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return None;
+    }
+    let source = match fields.next()? {
+        "UNKNOWN" => return Some(None),
+        "TCP4" => IpAddr::V4(fields.next()?.parse().ok()?),
+        "TCP6" => IpAddr::V6(fields.next()?.parse().ok()?),
+        _ => return None,
+    };
+    Some(Some(source))
+}
+
+// Parse a whole version 2 header, as for parse_proxy_v1
+fn parse_proxy_v2(header: &[u8]) -> Option<Option<IpAddr>> {
+    let version_command = header[12];
+    if version_command >> 4 != 2 {
+        return None;
+    }
+    // LOCAL connections come from the load balancer itself, and name no client
+    if version_command & 0x0f == 0 {
+        return Some(None);
+    }
+    let addresses = &header[16..];
+    let source = match header[13] {
+        // TCP or UDP over IPv4: source and destination addresses, then ports
+        0x11 | 0x12 if addresses.len() >= 12 => {
+            let octets: [u8; 4] = addresses[..4].try_into().ok()?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        // TCP or UDP over IPv6
+        0x21 | 0x22 if addresses.len() >= 36 => {
+            let octets: [u8; 16] = addresses[..16].try_into().ok()?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        // Unix sockets and unspecified families don't have an IP address to report
+        _ => return Some(None),
+    };
+    Some(Some(source))
+}
+
+// A stream which reads prefix before anything more from inner, to put back the bytes
+// accept read while sniffing
+pub struct Prefixed<S> {
+    prefix: Vec<u8>,
+    // How much of prefix has been read already
+    position: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Prefixed {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: Read + Unpin> Read for Prefixed<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let rest = &this.prefix[this.position..];
+        if rest.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        this.position += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl<S: Write + Unpin> Write for Prefixed<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    #[test]
+    fn test_sniff_tells_the_protocols_apart() {
+        assert_eq!(sniff(b""), Sniffed::NeedMore);
+        assert_eq!(sniff(b"GE"), Sniffed::NeedMore);
+        assert_eq!(sniff(REQUEST), Sniffed::Found(Protocol::Http));
+        assert_eq!(sniff(b"PRO"), Sniffed::NeedMore);
+        assert_eq!(sniff(b"PROXY TCP4 "), Sniffed::Found(Protocol::ProxyV1));
+        assert_eq!(sniff(b"PROPFIND / HTTP/1.1"), Sniffed::Found(Protocol::Http));
+        assert_eq!(sniff(b"\r\n\r\n\0"), Sniffed::NeedMore);
+        assert_eq!(sniff(b"\r\n\r\n\0\r\nQUIT\n\x21"), Sniffed::Found(Protocol::ProxyV2));
+        assert_eq!(sniff(&[0x16]), Sniffed::NeedMore);
+        assert_eq!(sniff(&[0x16, 0x03, 0x01]), Sniffed::Found(Protocol::Tls));
+        assert_eq!(sniff(b"get / HTTP/1.1"), Sniffed::Unknown);
+        assert_eq!(sniff(b"\r\nGET"), Sniffed::Unknown);
+    }
+
+    #[async_std::test]
+    async fn test_accept_hands_on_the_sniffed_bytes() {
+        let accepted = accept(REQUEST, false).await.unwrap();
+        assert_eq!(accepted.source, None);
+
+        let mut stream = accepted.stream;
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, REQUEST);
+    }
+
+    #[async_std::test]
+    async fn test_accept_strips_proxy_headers() {
+        let v1 = [b"PROXY TCP4 192.0.2.7 198.51.100.1 56324 443\r\n", REQUEST].concat();
+        let mut v2 = PROXY_V2_SIGNATURE.to_vec();
+        // PROXY over TCP4, then 12 bytes of addresses and ports
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 0, 2, 7, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        v2.extend_from_slice(REQUEST);
+
+        for connection in [v1, v2] {
+            let accepted = accept(&connection[..], true).await.unwrap();
+            assert_eq!(accepted.source, Some("192.0.2.7".parse().unwrap()));
+            let mut stream = accepted.stream;
+            let mut read = Vec::new();
+            stream.read_to_end(&mut read).await.unwrap();
+            assert_eq!(read, REQUEST);
+        }
+
+        assert!(accept(&b"PROXY TCP4 not-an-address\r\n"[..], true).await.is_none());
+        assert!(accept(&[0x16, 0x03, 0x01, 0x00][..], true).await.is_none());
+    }
+
+    #[async_std::test]
+    async fn test_accept_closes_proxy_headers_from_untrusted_peers() {
+        let v1 = [b"PROXY TCP4 192.0.2.7 198.51.100.1 56324 443\r\n", REQUEST].concat();
+        let v2 = [PROXY_V2_SIGNATURE, &[0x21, 0x11, 0, 12, 192, 0, 2, 7, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb], REQUEST].concat();
+        for connection in [v1, v2] {
+            assert!(accept(&connection[..], false).await.is_none());
+        }
+    }
+}
@@ -0,0 +1,53 @@
+// An optional tarpit for clients we'd rather not serve, such as scanners probing for
+// vulnerable paths. Instead of closing the connection, which just lets them move on to the
+// next target straight away, we answer one byte every few seconds and keep them waiting.
+//
+// Each tarpitted connection is a task asleep on a timer between writes, which costs us
+// almost nothing, while the client holds a socket and usually a thread for the whole time.
+
+use std::time::Duration;
+use async_std::io::Write;
+
+use async_std::prelude::*;
+use async_std::task;
+
+// How long to wait before each byte
+pub const DRIP_INTERVAL: Duration = Duration::from_secs(2);
+
+// What a tarpitted client eventually gets: a complete, valid response, so the connection ends
+// cleanly after about three minutes if the client is still waiting by then
+const TARPIT_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 SERVICE UNAVAILABLE\r\nRetry-After: 86400\r\nContent-Length: 0\r\n\r\n";
+
+// Answer a flagged client, as slowly as we can get away with
+pub async fn serve(mut stream: impl Write + Unpin) {
+    // An error means the client gave up, which is what we wanted anyway
+    let _ = drip_feed(&mut stream, TARPIT_RESPONSE, DRIP_INTERVAL).await;
+}
+
+// Write response one byte at a time, sleeping for interval before each one.
+// Flushing every byte makes sure it really goes out on its own.
+async fn drip_feed(stream: &mut (impl Write + Unpin), response: &[u8], interval: Duration) -> std::io::Result<()> {
+    for byte in response.chunks(1) {
+        task::sleep(interval).await;
+        stream.write_all(byte).await?;
+        stream.flush().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[async_std::test]
+    async fn test_drip_feed_writes_every_byte_slowly() {
+        let mut written = Vec::new();
+        let started = Instant::now();
+        drip_feed(&mut written, b"HTTP/1.1", Duration::from_millis(5)).await.unwrap();
+
+        assert_eq!(written, b"HTTP/1.1");
+        assert!(started.elapsed() >= Duration::from_millis(40));
+    }
+}
@@ -0,0 +1,192 @@
+// Caps how fast bytes go through a stream, to limit the bandwidth one connection can take, or
+// to play a slow client in tests.
+//
+// The budget is a token bucket: it fills at the allowed rate up to a burst size, and each byte
+// read or written takes one token. While the bucket is empty the read or write sleeps until
+// enough tokens have come in for at least one byte.
+
+use std::future::Future;
+use std::io::Result;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use async_std::io::{Read, Write};
+
+use async_std::task;
+
+// Bytes per second allowed in each direction on a connection, from the BANDWIDTH_LIMIT
+// environment variable. None (unset or not a number) means no limit.
+pub fn limit_from_env() -> Option<u64> {
+    std::env::var("BANDWIDTH_LIMIT").ok().and_then(|limit| limit.trim().parse().ok()).filter(|&limit| limit > 0)
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    // Tokens added per second
+    rate: f64,
+    // Most tokens the bucket holds, and so the largest burst it allows
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    // A full bucket, refilling at rate tokens per second up to capacity
+    pub fn new(rate: u64, capacity: u64) -> Self {
+        assert!(rate > 0 && capacity > 0, "a token bucket needs a rate and a capacity");
+        TokenBucket {
+            rate: rate as f64,
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    // Take up to wanted tokens. Returns how many were taken or, if the bucket is empty,
+    // how long until the next one comes in.
+    pub fn take(&mut self, wanted: usize, now: Instant) -> std::result::Result<usize, Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled_at = now;
+
+        let taken = self.tokens.floor().min(wanted as f64);
+        if taken < 1.0 && wanted > 0 {
+            return Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate));
+        }
+        self.tokens -= taken;
+        Ok(taken as usize)
+    }
+
+    // Give back tokens taken but not used, e.g. when a read returned fewer bytes than asked
+    pub fn put_back(&mut self, tokens: usize) {
+        self.tokens = (self.tokens + tokens as f64).min(self.capacity);
+    }
+}
+
+type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// A stream whose reads and writes each go no faster than bytes_per_sec, with bursts of up to
+// one second's worth
+pub struct Throttled<S> {
+    inner: S,
+    read_budget: TokenBucket,
+    write_budget: TokenBucket,
+    // Set while waiting for the bucket to refill
+    read_sleep: Option<Sleep>,
+    write_sleep: Option<Sleep>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, bytes_per_sec: u64) -> Self {
+        Throttled {
+            inner,
+            read_budget: TokenBucket::new(bytes_per_sec, bytes_per_sec),
+            write_budget: TokenBucket::new(bytes_per_sec, bytes_per_sec),
+            read_sleep: None,
+            write_sleep: None,
+        }
+    }
+}
+
+// Take tokens for up to wanted bytes, or sleep until some are available
+fn poll_budget(budget: &mut TokenBucket, sleep: &mut Option<Sleep>, wanted: usize, cx: &mut Context<'_>) -> Poll<usize> {
+    loop {
+        if let Some(pending) = sleep {
+            if pending.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            *sleep = None;
+        }
+        match budget.take(wanted, Instant::now()) {
+            Ok(granted) => return Poll::Ready(granted),
+            Err(wait) => *sleep = Some(Box::pin(task::sleep(wait))),
+        }
+    }
+}
+
+impl<S: Read + Unpin> Read for Throttled<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let granted = match poll_budget(&mut this.read_budget, &mut this.read_sleep, buf.len(), cx) {
+            Poll::Ready(granted) => granted,
+            Poll::Pending => return Poll::Pending,
+        };
+        let result = Pin::new(&mut this.inner).poll_read(cx, &mut buf[..granted]);
+        let used = match &result {
+            Poll::Ready(Ok(read)) => *read,
+            _ => 0,
+        };
+        this.read_budget.put_back(granted - used);
+        result
+    }
+}
+
+impl<S: Write + Unpin> Write for Throttled<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let granted = match poll_budget(&mut this.write_budget, &mut this.write_sleep, buf.len(), cx) {
+            Poll::Ready(granted) => granted,
+            Poll::Pending => return Poll::Pending,
+        };
+        let result = Pin::new(&mut this.inner).poll_write(cx, &buf[..granted]);
+        let used = match &result {
+            Poll::Ready(Ok(written)) => *written,
+            _ => 0,
+        };
+        this.write_budget.put_back(granted - used);
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::prelude::*;
+
+    #[test]
+    fn test_token_bucket_refills_at_its_rate() {
+        let mut bucket = TokenBucket::new(100, 50);
+        let start = bucket.refilled_at;
+        // Starts full, so a burst up to the capacity goes straight through
+        assert_eq!(bucket.take(80, start), Ok(50));
+        let wait = bucket.take(1, start).unwrap_err();
+        assert!(wait > Duration::from_millis(9) && wait <= Duration::from_millis(10), "{:?}", wait);
+
+        // 100 tokens a second is 5 in 50ms
+        assert_eq!(bucket.take(80, start + Duration::from_millis(50)), Ok(5));
+        bucket.put_back(3);
+        assert_eq!(bucket.take(80, start + Duration::from_millis(50)), Ok(3));
+        // Never more than the capacity, however long it has been
+        assert_eq!(bucket.take(80, start + Duration::from_secs(10)), Ok(50));
+    }
+
+    #[async_std::test]
+    async fn test_throttled_write_keeps_to_the_rate() {
+        let mut written = Vec::new();
+        let mut stream = Throttled::new(&mut written, 1000);
+        let started = Instant::now();
+        // The first 1000 bytes are the burst, the other 200 take about 200ms
+        stream.write_all(&[b'x'; 1200]).await.unwrap();
+
+        assert!(started.elapsed() >= Duration::from_millis(150), "{:?}", started.elapsed());
+        assert_eq!(written.len(), 1200);
+    }
+
+    #[async_std::test]
+    async fn test_throttled_read_takes_only_what_it_reads() {
+        let data = [b'x'; 10];
+        let mut stream = Throttled::new(&data[..], 100);
+        let mut buffer = [0; 64];
+        assert_eq!(stream.read(&mut buffer).await.unwrap(), 10);
+        // Only the 10 bytes read were charged, not the 64 asked for
+        assert_eq!(stream.read_budget.take(usize::MAX, stream.read_budget.refilled_at), Ok(90));
+    }
+}
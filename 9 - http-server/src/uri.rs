@@ -0,0 +1,186 @@
+// Request targets and other URIs (RFC 3986), split into their parts,
+// with helpers for percent-encoding.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uri {
+    scheme: Option<String>,
+    authority: Option<String>,
+    // Still percent-encoded, as are the query and the parts above
+    path: String,
+    query: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidUri;
+
+impl fmt::Display for InvalidUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid URI")
+    }
+}
+
+impl std::error::Error for InvalidUri {}
+
+impl Uri {
+    // Parse either a path with an optional query ("/a/b?c=d", the origin-form most requests use)
+    // or an absolute URI ("http://example.com/a?b"). Any fragment is dropped.
+    pub fn parse(uri: &str) -> Result<Uri, InvalidUri> {
+        if uri.bytes().any(|byte| byte.is_ascii_control() || byte == b' ') {
+            return Err(InvalidUri);
+        }
+        let uri = uri.split('#').next().unwrap_or(uri);
+
+        let (scheme, authority, rest) = if uri.starts_with('/') {
+            (None, None, uri)
+        } else {
+            let (scheme, rest) = uri.split_once("://").ok_or(InvalidUri)?;
+            let valid_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+            if !valid_scheme {
+                return Err(InvalidUri);
+            }
+            let end = rest.find(['/', '?']).unwrap_or(rest.len());
+            let (authority, rest) = rest.split_at(end);
+            if authority.is_empty() {
+                return Err(InvalidUri);
+            }
+            (Some(scheme.to_ascii_lowercase()), Some(authority.to_owned()), rest)
+        };
+
+        let (path, query) = match rest.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_owned())),
+            None => (rest, None),
+        };
+        // Check the escapes now, so path_segments doesn't have to
+        percent_decode(path).ok_or(InvalidUri)?;
+        // An absolute URI may leave the path out entirely
+        let path = if path.is_empty() { "/" } else { path };
+
+        Ok(Uri {
+            scheme,
+            authority,
+            path: path.to_owned(),
+            query,
+        })
+    }
+
+    pub fn scheme(&self) -> Option<&str> {
+        self.scheme.as_deref()
+    }
+
+    pub fn authority(&self) -> Option<&str> {
+        self.authority.as_deref()
+    }
+
+    // The decoded segments of the path, e.g. ["a b", "c"] for "/a%20b/c"
+    pub fn path_segments(&self) -> impl Iterator<Item = String> + '_ {
+        self.path
+            .split('/')
+            .skip(1)
+            .map(|segment| percent_decode(segment).unwrap_or_default())
+    }
+
+    // The decoded name/value pairs of the query, with '+' meaning a space as in HTML forms.
+    // Pairs that don't decode are skipped.
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        let query = match &self.query {
+            Some(query) => query,
+            None => return Vec::new(),
+        };
+        query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let name = percent_decode(&name.replace('+', " "))?;
+                let value = percent_decode(&value.replace('+', " "))?;
+                Some((name, value))
+            })
+            .collect()
+    }
+}
+
+// Escape every byte except the unreserved characters (letters, digits, '-', '.', '_' and '~'),
+// so the result can go anywhere in a URI, e.g. in a path segment or query value
+pub fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+// Undo percent-encoding. Returns None if an escape is malformed or the result isn't UTF-8.
+pub fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            // from_str_radix alone would also take a sign, as in "%+1"
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_origin_and_absolute_form() {
+        let uri = Uri::parse("/a%20b/c?x=1&y=two+words#top").unwrap();
+        assert_eq!(uri.scheme(), None);
+        assert_eq!(uri.authority(), None);
+        assert_eq!(uri.path_segments().collect::<Vec<_>>(), ["a b", "c"]);
+        assert_eq!(
+            uri.query_pairs(),
+            [("x".to_owned(), "1".to_owned()), ("y".to_owned(), "two words".to_owned())]
+        );
+
+        let uri = Uri::parse("HTTP://localhost:7878?q").unwrap();
+        assert_eq!(uri.scheme(), Some("http"));
+        assert_eq!(uri.authority(), Some("localhost:7878"));
+        assert_eq!(uri.path_segments().collect::<Vec<_>>(), [""]);
+        assert_eq!(uri.query_pairs(), [("q".to_owned(), String::new())]);
+
+        for invalid in ["", "*", "a b", "/%zz", "/%e2%82", "1http://x/", "http:///"] {
+            assert_eq!(Uri::parse(invalid), Err(InvalidUri), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_percent_encoding_round_trips() {
+        for input in ["a b/c?d=é&~", "", "100%", "+-._~", "/%2e%2e/"] {
+            let encoded = percent_encode(input);
+            assert!(encoded.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"%-._~".contains(&byte)));
+            assert_eq!(percent_decode(&encoded).as_deref(), Some(input));
+        }
+        assert_eq!(percent_encode("a b/c?d=é&~"), "a%20b%2Fc%3Fd%3D%C3%A9%26~");
+
+        // Query values encoded this way come back out of query_pairs unchanged
+        let value = "two words & 50% off";
+        let uri = Uri::parse(&format!("/?q={}", percent_encode(value))).unwrap();
+        assert_eq!(uri.query_pairs(), [("q".to_owned(), value.to_owned())]);
+
+        assert_eq!(percent_decode("a%20b%2Fc%3Fd%3D%C3%A9%26~").as_deref(), Some("a b/c?d=é&~"));
+        assert_eq!(percent_decode("%4"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%-0"), None);
+    }
+}